@@ -0,0 +1,334 @@
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::errors::BookServiceError;
+use crate::events::EventStream;
+use crate::model::{
+    Book, BookPage, CacheStats, IdempotentResponse, Revision, TrashedBook, VersionedBook,
+};
+use crate::store::BookStore;
+
+/// A read-through cache in front of another store, keeping up to capacity recently read
+/// books for at most the ttl. Every write made through the cache evicts the books it
+/// touched, writes made by other processes are only seen once the cached copy expires.
+#[derive(Debug)]
+pub struct CachedStore {
+    inner: Arc<dyn BookStore>,
+    capacity: usize,
+    ttl: Duration,
+    lru: Mutex<Lru>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    book: VersionedBook,
+    expires_at: Instant,
+    last_used: u64,
+}
+
+/// Books by uuid along with the order they were last used in
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<Uuid, Entry>,
+    /// uuids by the tick they were last used at, the least recently used first
+    order: BTreeMap<u64, Uuid>,
+    tick: u64,
+    /// Bumped by every eviction for a write, so a read that raced a write does not
+    /// cache what it read
+    generation: u64,
+}
+
+impl Lru {
+    fn get(&mut self, uuid: &Uuid, now: Instant) -> Option<VersionedBook> {
+        let expired = self.entries.get(uuid)?.expires_at <= now;
+        if expired {
+            self.remove(uuid);
+            return None;
+        }
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(uuid)?;
+        self.order.remove(&entry.last_used);
+        self.order.insert(tick, uuid.to_owned());
+        entry.last_used = tick;
+        Some(entry.book.clone())
+    }
+
+    fn insert(&mut self, uuid: Uuid, book: VersionedBook, expires_at: Instant, capacity: usize) {
+        self.remove(&uuid);
+        while self.entries.len() >= capacity {
+            let oldest = match self.order.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            if let Some(evicted) = self.order.remove(&oldest) {
+                self.entries.remove(&evicted);
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, uuid);
+        self.entries.insert(
+            uuid,
+            Entry {
+                book,
+                expires_at,
+                last_used: self.tick,
+            },
+        );
+    }
+
+    fn remove(&mut self, uuid: &Uuid) {
+        if let Some(entry) = self.entries.remove(uuid) {
+            self.order.remove(&entry.last_used);
+        }
+    }
+}
+
+impl CachedStore {
+    pub fn new(inner: Arc<dyn BookStore>, capacity: usize, ttl: Duration) -> Self {
+        CachedStore {
+            inner,
+            capacity,
+            ttl,
+            lru: Mutex::new(Lru::default()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    // The cache is consistent between calls, so a poisoned lock is still safe to use
+    fn lru(&self) -> MutexGuard<'_, Lru> {
+        self.lru.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn evict(&self, uuid: &Uuid) {
+        let mut lru = self.lru();
+        lru.generation += 1;
+        lru.remove(uuid);
+    }
+}
+
+impl BookStore for CachedStore {
+    fn put(&self, entry: &Book, source: &str) -> Result<u64, BookServiceError> {
+        let result = self.inner.put(entry, source);
+        self.evict(&entry.book_id);
+        result
+    }
+
+    fn put_many(&self, entries: &[Book], source: &str) -> Result<Vec<u64>, BookServiceError> {
+        let result = self.inner.put_many(entries, source);
+        for entry in entries {
+            self.evict(&entry.book_id);
+        }
+        result
+    }
+
+    fn get_versioned(&self, uuid: &Uuid) -> Result<VersionedBook, BookServiceError> {
+        let generation = {
+            let mut lru = self.lru();
+            if let Some(cached) = lru.get(uuid, Instant::now()) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(cached);
+            }
+            lru.generation
+        };
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let versioned = self.inner.get_versioned(uuid)?;
+        let mut lru = self.lru();
+        if lru.generation == generation && self.capacity > 0 {
+            lru.insert(
+                uuid.to_owned(),
+                versioned.clone(),
+                Instant::now() + self.ttl,
+                self.capacity,
+            );
+        }
+        Ok(versioned)
+    }
+
+    fn update(
+        &self,
+        entry: &Book,
+        expected_version: Option<u64>,
+        source: &str,
+    ) -> Result<Option<u64>, BookServiceError> {
+        let result = self.inner.update(entry, expected_version, source);
+        self.evict(&entry.book_id);
+        result
+    }
+
+    fn patch(
+        &self,
+        uuid: &Uuid,
+        patch: &Value,
+        expected_version: Option<u64>,
+        source: &str,
+    ) -> Result<VersionedBook, BookServiceError> {
+        let result = self.inner.patch(uuid, patch, expected_version, source);
+        self.evict(uuid);
+        result
+    }
+
+    fn delete(
+        &self,
+        uuid: &Uuid,
+        expected_version: Option<u64>,
+        source: &str,
+    ) -> Result<bool, BookServiceError> {
+        let result = self.inner.delete(uuid, expected_version, source);
+        self.evict(uuid);
+        result
+    }
+
+    fn list_trash(&self) -> Result<Vec<TrashedBook>, BookServiceError> {
+        self.inner.list_trash()
+    }
+
+    fn restore(
+        &self,
+        uuid: &Uuid,
+        source: &str,
+    ) -> Result<Option<VersionedBook>, BookServiceError> {
+        let result = self.inner.restore(uuid, source);
+        self.evict(uuid);
+        result
+    }
+
+    /// Only trashed books are purged and those are never cached
+    fn purge(&self, deleted_before: u64) -> Result<usize, BookServiceError> {
+        self.inner.purge(deleted_before)
+    }
+
+    fn history(&self, uuid: &Uuid) -> Result<Vec<Revision>, BookServiceError> {
+        self.inner.history(uuid)
+    }
+
+    fn list(&self, cursor: Option<&str>, limit: usize) -> Result<BookPage, BookServiceError> {
+        self.inner.list(cursor, limit)
+    }
+
+    fn find_by_author(&self, author: &str) -> Result<Vec<Book>, BookServiceError> {
+        self.inner.find_by_author(author)
+    }
+
+    fn search(&self, query: &str, limit: usize) -> Result<Vec<Book>, BookServiceError> {
+        self.inner.search(query, limit)
+    }
+
+    fn idempotent_response(
+        &self,
+        key: &str,
+    ) -> Result<Option<IdempotentResponse>, BookServiceError> {
+        self.inner.idempotent_response(key)
+    }
+
+    fn save_idempotent_response(
+        &self,
+        key: &str,
+        response: &IdempotentResponse,
+        ttl_secs: u64,
+    ) -> Result<bool, BookServiceError> {
+        self.inner.save_idempotent_response(key, response, ttl_secs)
+    }
+
+    fn replace_idempotent_response(
+        &self,
+        key: &str,
+        response: &IdempotentResponse,
+        ttl_secs: u64,
+    ) -> Result<(), BookServiceError> {
+        self.inner
+            .replace_idempotent_response(key, response, ttl_secs)
+    }
+
+    fn remove_idempotent_response(&self, key: &str) -> Result<(), BookServiceError> {
+        self.inner.remove_idempotent_response(key)
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: self.lru().entries.len(),
+            capacity: self.capacity,
+        })
+    }
+
+    fn events(&self) -> Option<Result<EventStream, BookServiceError>> {
+        self.inner.events()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+
+    fn cached(capacity: usize, ttl: Duration) -> (Arc<dyn BookStore>, CachedStore) {
+        let inner: Arc<dyn BookStore> = Arc::new(MemoryStore::new());
+        let store = CachedStore::new(inner.clone(), capacity, ttl);
+        (inner, store)
+    }
+
+    #[test]
+    fn test_read_through() {
+        let (inner, store) = cached(10, Duration::from_secs(60));
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the Wrold");
+        let _ = store.put(&book, "test").unwrap();
+
+        assert_eq!(book, store.get(&book_id).unwrap());
+        assert_eq!(book, store.get(&book_id).unwrap());
+        let stats = store.cache_stats().unwrap();
+        assert_eq!((1, 1, 1), (stats.hits, stats.misses, stats.size));
+
+        // writes made elsewhere are not seen until the entry expires
+        let fixed = Book::new(book_id, "Robert Jordan", "Eye of the World");
+        let _ = inner.put(&fixed, "test").unwrap();
+        assert_eq!(book, store.get(&book_id).unwrap());
+
+        // writes through the cache are seen straight away
+        let _ = store.put(&fixed, "test").unwrap();
+        assert_eq!(3, store.get_versioned(&book_id).unwrap().version);
+        assert!(store.delete(&book_id, None, "test").unwrap());
+        assert!(store.get(&book_id).is_err());
+    }
+
+    #[test]
+    fn test_expiry_and_eviction() {
+        let (_, store) = cached(10, Duration::from_secs(0));
+        let book_id = Uuid::new_v4();
+        let _ = store
+            .put(
+                &Book::new(book_id, "Robert Jordan", "Eye of the World"),
+                "test",
+            )
+            .unwrap();
+        let _ = store.get(&book_id).unwrap();
+        let _ = store.get(&book_id).unwrap();
+        assert_eq!(0, store.cache_stats().unwrap().hits);
+
+        let (_, store) = cached(2, Duration::from_secs(60));
+        let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for id in &ids {
+            let _ = store
+                .put(&Book::new(*id, "Robert Jordan", "The Great Hunt"), "test")
+                .unwrap();
+        }
+        let _ = store.get(&ids[0]).unwrap();
+        let _ = store.get(&ids[1]).unwrap();
+        // using the first book makes the second the least recently used
+        let _ = store.get(&ids[0]).unwrap();
+        let _ = store.get(&ids[2]).unwrap();
+        let _ = store.get(&ids[0]).unwrap();
+        let _ = store.get(&ids[1]).unwrap();
+        let stats = store.cache_stats().unwrap();
+        assert_eq!((2, 4, 2), (stats.hits, stats.misses, stats.size));
+    }
+}
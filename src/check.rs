@@ -0,0 +1,104 @@
+use std::fmt;
+
+/// Something a catalog check found in Redis that the service can not read or that a
+/// half-done write left behind
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// A key holding another Redis type than the service writes under it
+    WrongType {
+        key: String,
+        expected: &'static str,
+        found: String,
+    },
+    /// A book record `book_from_map` rejects
+    Malformed { key: String, reason: String },
+    /// An index entry naming a book that is missing, unreadable or not indexed there
+    DanglingEntry { key: String, id: String },
+    /// A book left out of an index it belongs in, as books written before the index
+    /// existed are
+    MissingEntry { key: String, id: String },
+    /// A key belonging to a book that no longer exists
+    Orphaned { key: String },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::WrongType {
+                key,
+                expected,
+                found,
+            } => write!(f, "{} is a {}, expected a {}", key, found, expected),
+            Problem::Malformed { key, reason } => {
+                write!(f, "{} can not be read as a book: {}", key, reason)
+            }
+            Problem::DanglingEntry { key, id } => {
+                write!(f, "{} lists {}, which is not a book indexed there", key, id)
+            }
+            Problem::MissingEntry { key, id } => {
+                write!(
+                    f,
+                    "{} does not list {}, which is a book indexed there",
+                    key, id
+                )
+            }
+            Problem::Orphaned { key } => write!(f, "{} belongs to a book that does not exist", key),
+        }
+    }
+}
+
+/// The outcome of checking one catalog
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// The book records looked at
+    pub books: usize,
+    pub problems: Vec<Problem>,
+    /// How many of the problems were repaired, none unless repairing
+    pub repaired: usize,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_problems() {
+        let wrong_type = Problem::WrongType {
+            key: "BOOK-0bcd291d-b7c5-4390-965f-8a70707d22a5".to_string(),
+            expected: "hash",
+            found: "string".to_string(),
+        };
+        assert_eq!(
+            "BOOK-0bcd291d-b7c5-4390-965f-8a70707d22a5 is a string, expected a hash",
+            wrong_type.to_string()
+        );
+        let dangling = Problem::DanglingEntry {
+            key: "AUTHOR-robert jordan".to_string(),
+            id: "0bcd291d-b7c5-4390-965f-8a70707d22a5".to_string(),
+        };
+        assert_eq!(
+            "AUTHOR-robert jordan lists 0bcd291d-b7c5-4390-965f-8a70707d22a5, which is not a book indexed there",
+            dangling.to_string()
+        );
+        let missing = Problem::MissingEntry {
+            key: "AUTHOR-robert jordan".to_string(),
+            id: "0bcd291d-b7c5-4390-965f-8a70707d22a5".to_string(),
+        };
+        assert_eq!(
+            "AUTHOR-robert jordan does not list 0bcd291d-b7c5-4390-965f-8a70707d22a5, which is a book indexed there",
+            missing.to_string()
+        );
+        assert!(CheckReport::default().is_clean());
+        assert!(!CheckReport {
+            problems: vec![dangling],
+            ..CheckReport::default()
+        }
+        .is_clean());
+    }
+}
@@ -0,0 +1,286 @@
+use r2d2_redis::{r2d2, RedisConnectionManager};
+use redis::{self, ErrorKind, RedisError, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::dao::{self, RedisConnection};
+use crate::errors::{BookServiceError, DaoCause};
+use crate::settings::Settings;
+
+/// Redis Cluster splits the keyspace into this many hash slots
+const SLOT_COUNT: u16 = 16384;
+/// How often the slot layout is read again, so slots moved by resharding or a failover
+/// are followed
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The masters of a Redis Cluster along with the slots each of them serves, keys are
+/// routed to the master serving their hash slot. The layout is read from the cluster at
+/// startup and refreshed every REFRESH_INTERVAL, as well as whenever a master can not be
+/// reached.
+#[derive(Debug)]
+pub struct Cluster {
+    seeds: Vec<String>,
+    settings: Settings,
+    /// The master serving each range of slots, by the first slot of the range
+    slots: RwLock<BTreeMap<u16, (u16, String)>>,
+    pools: RwLock<HashMap<String, r2d2::Pool<RedisConnectionManager>>>,
+}
+
+impl Cluster {
+    /// Discovers the cluster through the nodes in BOOKSTORE_REDISCLUSTERNODES
+    pub fn connect(settings: &Settings) -> Result<Arc<Cluster>, BookServiceError> {
+        let cluster = Arc::new(Cluster {
+            seeds: settings.redis_cluster_nodes(),
+            settings: settings.clone(),
+            slots: RwLock::new(BTreeMap::new()),
+            pools: RwLock::new(HashMap::new()),
+        });
+        cluster
+            .refresh()
+            .map_err(BookServiceError::DaoInitializationError)?;
+        println!("Connected to cluster masters {:?}", cluster.masters());
+        refresh_periodically(&cluster);
+        Ok(cluster)
+    }
+
+    /// Reads the slot layout from the first known master or seed node that answers
+    pub fn refresh(&self) -> Result<(), DaoCause> {
+        let mut nodes = self.masters();
+        nodes.extend(self.seeds.iter().cloned());
+        let mut failure = None;
+        for node in nodes {
+            match self.read_slots(&node) {
+                Ok(slots) => {
+                    *self.slots.write().unwrap_or_else(|e| e.into_inner()) = slots;
+                    return Ok(());
+                }
+                Err(e) => {
+                    eprintln!("Could not read cluster slots from {} {}", &node, &e);
+                    failure = Some(e);
+                }
+            }
+        }
+        Err(failure.unwrap_or_else(|| cluster_error("no cluster nodes configured")))
+    }
+
+    fn read_slots(&self, node: &str) -> Result<BTreeMap<u16, (u16, String)>, DaoCause> {
+        let conn = redis::Client::open(self.node_url(node)?.as_ref())?.get_connection()?;
+        let reply: Value = redis::cmd("CLUSTER").arg("SLOTS").query(&conn)?;
+        let host = node.rsplitn(2, ':').last().unwrap_or(node);
+        parse_slots(&reply, host).ok_or_else(|| cluster_error("unexpected CLUSTER SLOTS reply"))
+    }
+
+    /// Every master, sorted so the order holds for as long as the layout does
+    pub fn masters(&self) -> Vec<String> {
+        let slots = self.slots.read().unwrap_or_else(|e| e.into_inner());
+        let masters: BTreeSet<&String> = slots.values().map(|(_, node)| node).collect();
+        masters.into_iter().cloned().collect()
+    }
+
+    /// The master serving the slot of the key
+    pub fn master_for(&self, key: &str) -> Result<String, DaoCause> {
+        let slot = key_slot(key);
+        let slots = self.slots.read().unwrap_or_else(|e| e.into_inner());
+        slots
+            .range(..=slot)
+            .next_back()
+            .filter(|(_, (last, _))| slot <= *last)
+            .map(|(_, (_, node))| node.to_owned())
+            .ok_or_else(|| cluster_error("no master serves the slot"))
+    }
+
+    /// A pooled connection to the master, refreshing the layout when it can not be had
+    /// as the master may have failed over
+    pub fn connection(&self, node: &str) -> Result<RedisConnection, DaoCause> {
+        let pool = self.pool(node)?;
+        pool.get().map_err(|e| {
+            eprintln!("Could not reach cluster master {} {}", node, &e);
+            let _ = self.refresh();
+            DaoCause::from(e)
+        })
+    }
+
+    fn pool(&self, node: &str) -> Result<r2d2::Pool<RedisConnectionManager>, DaoCause> {
+        if let Some(pool) = self
+            .pools
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(node)
+        {
+            return Ok(pool.clone());
+        }
+        let pool = dao::connect(&self.node_url(node)?)?;
+        Ok(self
+            .pools
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(node.to_string())
+            .or_insert(pool)
+            .clone())
+    }
+
+    pub fn node_url(&self, node: &str) -> Result<String, DaoCause> {
+        self.settings
+            .redis_address_url(node)
+            .ok_or_else(|| cluster_error("invalid cluster node address"))
+    }
+}
+
+/// Refreshes the layout on a thread of its own for as long as the cluster is in use
+fn refresh_periodically(cluster: &Arc<Cluster>) {
+    let cluster = Arc::downgrade(cluster);
+    let spawned = thread::Builder::new()
+        .name("cluster-refresh".to_string())
+        .spawn(move || loop {
+            thread::sleep(REFRESH_INTERVAL);
+            match cluster.upgrade() {
+                Some(cluster) => {
+                    let _ = cluster.refresh();
+                }
+                None => break,
+            }
+        });
+    if let Err(e) = spawned {
+        eprintln!("Could not start refreshing the cluster slots {}", e);
+    }
+}
+
+/// The hash slot of the key. Only the part between the first { and the following } is
+/// hashed when there is one, so keys sharing such a hash tag share a slot.
+pub fn key_slot(key: &str) -> u16 {
+    let bytes = key.as_bytes();
+    let hashed = match key.find('{') {
+        Some(open) => match key[open + 1..].find('}') {
+            Some(0) | None => bytes,
+            Some(len) => &bytes[open + 1..open + 1 + len],
+        },
+        None => bytes,
+    };
+    crc16(hashed) % SLOT_COUNT
+}
+
+/// CRC16-CCITT (XMODEM), the checksum Redis Cluster hashes keys with
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ (u16::from(*byte) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// The master of every slot range in a CLUSTER SLOTS reply, by the first slot of the
+/// range. A master reported without a host is on the host that was asked.
+fn parse_slots(reply: &Value, asked_host: &str) -> Option<BTreeMap<u16, (u16, String)>> {
+    match reply {
+        Value::Bulk(ranges) => ranges
+            .iter()
+            .map(|range| match range {
+                Value::Bulk(fields) if fields.len() >= 3 => {
+                    let first = slot_number(&fields[0])?;
+                    let last = slot_number(&fields[1])?;
+                    let master = match &fields[2] {
+                        Value::Bulk(node) if node.len() >= 2 => {
+                            let host = match &node[0] {
+                                Value::Data(host) if host.is_empty() => asked_host.to_string(),
+                                Value::Data(host) => String::from_utf8(host.to_owned()).ok()?,
+                                _ => return None,
+                            };
+                            match node[1] {
+                                Value::Int(port) => format!("{}:{}", host, port),
+                                _ => return None,
+                            }
+                        }
+                        _ => return None,
+                    };
+                    Some((first, (last, master)))
+                }
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+fn slot_number(value: &Value) -> Option<u16> {
+    match *value {
+        Value::Int(slot) if slot >= 0 && slot < i64::from(SLOT_COUNT) => Some(slot as u16),
+        _ => None,
+    }
+}
+
+pub fn cluster_error(description: &'static str) -> DaoCause {
+    DaoCause::from(RedisError::from((ErrorKind::ResponseError, description)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_slot() {
+        assert_eq!(0x31C3, crc16(b"123456789"));
+        assert_eq!(12182, key_slot("foo"));
+        assert_eq!(
+            key_slot("{user1000}.following"),
+            key_slot("{user1000}.followers")
+        );
+        assert_eq!(key_slot("user1000"), key_slot("{user1000}.following"));
+        // an empty tag hashes the whole key
+        assert_eq!(crc16(b"{}foo") % SLOT_COUNT, key_slot("{}foo"));
+        assert_eq!(
+            key_slot("BOOK-{0bcd291d-b7c5-4390-965f-8a70707d22a5}"),
+            key_slot("staging:HISTORY-{0bcd291d-b7c5-4390-965f-8a70707d22a5}")
+        );
+    }
+
+    #[test]
+    fn test_parse_slots() {
+        let node = |host: &str, port: i64| {
+            Value::Bulk(vec![
+                Value::Data(host.as_bytes().to_vec()),
+                Value::Int(port),
+                Value::Data(b"09dbe9720cda62f7865eabc5fd8857c5d2678366".to_vec()),
+            ])
+        };
+        let reply = Value::Bulk(vec![
+            Value::Bulk(vec![
+                Value::Int(0),
+                Value::Int(8191),
+                node("10.0.0.1", 7000),
+                node("10.0.0.2", 7001),
+            ]),
+            Value::Bulk(vec![Value::Int(8192), Value::Int(16383), node("", 7002)]),
+        ]);
+        let slots = parse_slots(&reply, "10.0.0.3").unwrap();
+        assert_eq!(Some(&(8191, "10.0.0.1:7000".to_string())), slots.get(&0));
+        assert_eq!(
+            Some(&(16383, "10.0.0.3:7002".to_string())),
+            slots.get(&8192)
+        );
+        assert!(parse_slots(&Value::Int(1), "10.0.0.3").is_none());
+    }
+
+    #[test]
+    fn test_master_for() {
+        let cluster = Cluster {
+            seeds: Vec::new(),
+            settings: Settings::default(),
+            slots: RwLock::new(BTreeMap::new()),
+            pools: RwLock::new(HashMap::new()),
+        };
+        assert!(cluster.master_for("foo").is_err());
+        {
+            let mut slots = cluster.slots.write().unwrap();
+            slots.insert(0, (8191, "10.0.0.1:7000".to_string()));
+            slots.insert(8192, (16383, "10.0.0.2:7000".to_string()));
+        }
+        assert_eq!("10.0.0.2:7000", cluster.master_for("foo").unwrap());
+        assert_eq!(vec!["10.0.0.1:7000", "10.0.0.2:7000"], cluster.masters());
+    }
+}
@@ -1,74 +1,1124 @@
 use uuid::Uuid;
 
+use crate::check::{CheckReport, Problem};
+use crate::cluster::{cluster_error, key_slot, Cluster};
 use crate::errors::BookServiceError;
 use crate::errors::DaoCause;
-use crate::model::Book;
+use crate::events::{EventStream, Relays};
+use crate::model::{
+    Book, BookEvent, BookPage, IdempotentResponse, Operation, Revision, TrashedBook, VersionedBook,
+};
+use crate::namespace::{Namespace, BOOK_PREFIX};
+use crate::schema::{self, SCHEMA, SCHEMA_VERSION};
+use crate::search;
+use crate::sentinel::Sentinel;
 use crate::settings::Settings;
+use crate::store::{check_version, unix_time, BookStore};
 use r2d2_redis::redis::Commands;
 use r2d2_redis::{r2d2, RedisConnectionManager};
-use redis::{self, PipelineCommands};
-use std::collections::HashMap;
+use redis::{self, ErrorKind, PipelineCommands, RedisError};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
 use std::convert::AsRef;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
 
-const AUTHOR: &'static str = "author";
-const TITLE: &'static str = "title";
+pub const AUTHOR: &'static str = "author";
+pub const TITLE: &'static str = "title";
+pub const VERSION: &'static str = "version";
+/// Present on a trashed book, holding the unix time it was deleted at
+pub const DELETED_AT: &'static str = "deleted_at";
+/// How many books a bulk write puts in one transaction
+const BULK_BATCH_SIZE: usize = 100;
+/// How many keys each SCAN over a whole namespace asks for
+const NAMESPACE_SCAN_COUNT: usize = 1000;
+/// The field of an outbox entry holding the change record, a JSON book event
+const OUTBOX_FIELD: &'static str = "event";
+/// How long a read waits for a replica connection before falling back to the master
+const REPLICA_CONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub type RedisConnection = r2d2::PooledConnection<RedisConnectionManager>;
+
+/// The connections to Redis, shared by the daos of every tenant
+#[derive(Debug, Clone)]
+pub enum RedisPool {
+    /// A single master. When it is discovered through Sentinel the pool is swapped for
+    /// one connected to the new master after each failover.
+    Standalone(Arc<RwLock<Master>>),
+    /// The masters of a Redis Cluster, each key is routed to the one serving its slot
+    Cluster(Arc<Cluster>),
+}
+
+/// The pool of a standalone master, along with its url for the connections that can not
+/// be pooled such as Pub/Sub subscriptions
+#[derive(Debug, Clone)]
+pub struct Master {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    url: String,
+}
+
+impl Master {
+    fn new(pool: r2d2::Pool<RedisConnectionManager>, url: &str) -> Arc<RwLock<Master>> {
+        Arc::new(RwLock::new(Master {
+            pool,
+            url: url.to_string(),
+        }))
+    }
+}
+
+impl RedisPool {
+    fn standalone(url: &str) -> Result<RedisPool, BookServiceError> {
+        connect(url)
+            .map(|pool| RedisPool::Standalone(Master::new(pool, url)))
+            .map_err(BookServiceError::DaoInitializationError)
+    }
+
+    /// A connection to the master holding the key
+    pub fn get(&self, key: &str) -> Result<RedisConnection, DaoCause> {
+        match self {
+            RedisPool::Standalone(current) => {
+                // The pool is cloned out of the lock so a slow checkout does not hold up
+                // a failover
+                let pool = current
+                    .read()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .pool
+                    .clone();
+                pool.get().map_err(DaoCause::from)
+            }
+            RedisPool::Cluster(cluster) => cluster
+                .master_for(key)
+                .and_then(|master| cluster.connection(&master)),
+        }
+    }
+
+    /// A connection to every master, in the same order for as long as the cluster layout
+    /// holds
+    fn masters(&self) -> Result<Vec<RedisConnection>, DaoCause> {
+        match self {
+            RedisPool::Standalone(_) => self.get("").map(|conn| vec![conn]),
+            RedisPool::Cluster(cluster) => cluster
+                .masters()
+                .iter()
+                .map(|master| cluster.connection(master))
+                .collect(),
+        }
+    }
+
+    /// Groups the keys by the master holding them, each group is a connection to the
+    /// master along with the positions of its keys
+    fn by_master(&self, keys: &[String]) -> Result<Vec<(RedisConnection, Vec<usize>)>, DaoCause> {
+        match self {
+            RedisPool::Standalone(_) => self
+                .get("")
+                .map(|conn| vec![(conn, (0..keys.len()).collect())]),
+            RedisPool::Cluster(cluster) => {
+                let mut positions: HashMap<String, Vec<usize>> = HashMap::new();
+                for (position, key) in keys.iter().enumerate() {
+                    positions
+                        .entry(cluster.master_for(key)?)
+                        .or_insert_with(Vec::new)
+                        .push(position);
+                }
+                positions
+                    .into_iter()
+                    .map(|(master, positions)| {
+                        cluster.connection(&master).map(|conn| (conn, positions))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    fn is_cluster(&self) -> bool {
+        match self {
+            RedisPool::Standalone(_) => false,
+            RedisPool::Cluster(_) => true,
+        }
+    }
+
+    /// The url of a master, the first one of a cluster as every node of a cluster
+    /// relays what is published on any other
+    fn url(&self) -> Result<String, DaoCause> {
+        match self {
+            RedisPool::Standalone(current) => Ok(current
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .url
+                .to_owned()),
+            RedisPool::Cluster(cluster) => cluster
+                .masters()
+                .first()
+                .ok_or_else(|| cluster_error("no cluster masters known"))
+                .and_then(|master| cluster.node_url(master)),
+        }
+    }
+
+    /// Connects to the master at the url, keeping the current pool when it can not
+    fn replace(&self, url: &str) -> Result<(), DaoCause> {
+        if let RedisPool::Standalone(current) = self {
+            let pool = connect(url)?;
+            *current.write().unwrap_or_else(|e| e.into_inner()) = Master {
+                pool,
+                url: url.to_string(),
+            };
+        }
+        Ok(())
+    }
+}
+
+pub fn connect(url: &str) -> Result<r2d2::Pool<RedisConnectionManager>, DaoCause> {
+    RedisConnectionManager::new(url)
+        .map_err(|e| {
+            eprintln!("Could not create connection manager! {} ", &e);
+            DaoCause::from(e)
+        })
+        .and_then(|mgr| {
+            r2d2::Pool::builder().build(mgr).map_err(|e| {
+                eprintln!("Could not create connection pool! {} ", &e);
+                DaoCause::from(e)
+            })
+        })
+}
+
+/// A change to an index set, the trash or the outbox made along with a write to a book.
+/// Outside of a cluster the changes are queued into the transaction of the write; in a
+/// cluster those keys are on other slots than the book, so they are applied once it
+/// committed.
+#[derive(Debug)]
+enum IndexChange {
+    /// SADD of the book id to the set
+    Add(String, String),
+    /// SREM of the book id from the set
+    Remove(String, String),
+    /// ZADD of the book id to the trash, scored by its deletion time
+    Trash(String, String, u64),
+    /// ZREM of the book id from the trash
+    Untrash(String, String),
+    /// XADD of the change record of the write to the outbox stream
+    Outbox(String, Vec<u8>, OutboxTrim),
+}
+
+impl IndexChange {
+    fn key(&self) -> &str {
+        match self {
+            IndexChange::Add(key, _)
+            | IndexChange::Remove(key, _)
+            | IndexChange::Trash(key, _, _)
+            | IndexChange::Untrash(key, _)
+            | IndexChange::Outbox(key, _, _) => key.as_str(),
+        }
+    }
+
+    fn queue<'a>(&self, pipe: &'a mut redis::Pipeline) -> &'a mut redis::Pipeline {
+        let pipe = match self {
+            IndexChange::Add(key, id) => pipe.sadd(key.to_owned(), id.to_owned()),
+            IndexChange::Remove(key, id) => pipe.srem(key.to_owned(), id.to_owned()),
+            IndexChange::Trash(key, id, deleted_at) => {
+                pipe.zadd(key.to_owned(), id.to_owned(), *deleted_at)
+            }
+            IndexChange::Untrash(key, id) => pipe.zrem(key.to_owned(), id.to_owned()),
+            IndexChange::Outbox(key, record, trim) => trim.queue_append(pipe, key, record),
+        };
+        pipe.ignore()
+    }
+}
+
+/// How much of the outbox stream is kept. Entries beyond the max length are trimmed as
+/// each one is appended, along with those older than the max age when there is one.
+/// Trimming is approximate, Redis only drops whole macro nodes of the stream.
+#[derive(Debug, Clone, Copy)]
+struct OutboxTrim {
+    max_len: usize,
+    max_age: Option<Duration>,
+}
+
+impl OutboxTrim {
+    fn from_settings(settings: &Settings) -> Self {
+        OutboxTrim {
+            max_len: settings.outbox_max_len(),
+            max_age: settings.outbox_max_age(),
+        }
+    }
+
+    /// Queues XADD of the record, and XTRIM of the entries past the max age
+    fn queue_append<'a>(
+        &self,
+        pipe: &'a mut redis::Pipeline,
+        key: &str,
+        record: &[u8],
+    ) -> &'a mut redis::Pipeline {
+        pipe.cmd("XADD")
+            .arg(key)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(self.max_len)
+            .arg("*")
+            .arg(OUTBOX_FIELD)
+            .arg(record);
+        if let Some(max_age) = self.max_age {
+            // stream ids start with the milliseconds they were added at
+            let min_id = unix_time().saturating_sub(max_age.as_secs()) * 1000;
+            pipe.ignore()
+                .cmd("XTRIM")
+                .arg(key)
+                .arg("MINID")
+                .arg("~")
+                .arg(min_id);
+        }
+        pipe
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct BookDao {
-    redis_pool: r2d2::Pool<RedisConnectionManager>,
+    redis_pool: RedisPool,
+    namespace: Namespace,
+    replicas: Option<Arc<Replicas>>,
+    /// Set on the daos reading from a replica, which leave outdated records alone
+    read_only: bool,
+    /// The channel committed writes are published on, before the namespace is applied
+    events_channel: String,
+    /// The subscriptions relaying the events of every catalog to clients
+    relays: Arc<Relays>,
+    outbox_trim: OutboxTrim,
+}
+
+/// The read replicas of a standalone master, each read goes to the next one in turn
+#[derive(Debug)]
+struct Replicas {
+    pools: Vec<RedisPool>,
+    next: AtomicUsize,
+}
+
+impl Replicas {
+    /// Pools for the replicas in BOOKSTORE_REDISREPLICAS, None when there are none. The
+    /// pools connect lazily and give up quickly so a replica that is down only delays
+    /// the reads falling back to the master a little.
+    fn connect(settings: &Settings) -> Result<Option<Arc<Replicas>>, BookServiceError> {
+        let addresses = settings.redis_replicas();
+        if addresses.is_empty() {
+            return Ok(None);
+        }
+        println!("Reading from replicas {:?}", &addresses);
+        addresses
+            .iter()
+            .map(|address| {
+                let url = settings
+                    .redis_address_url(address)
+                    .ok_or_else(|| BookServiceError::InvalidReplicaError(address.to_owned()))?;
+                let mgr = RedisConnectionManager::new(url.as_ref()).map_err(|e| {
+                    eprintln!("Could not create replica connection manager! {} ", &e);
+                    BookServiceError::from(e)
+                })?;
+                let pool = r2d2::Pool::builder()
+                    .connection_timeout(REPLICA_CONNECTION_TIMEOUT)
+                    .build_unchecked(mgr);
+                Ok(RedisPool::Standalone(Master::new(pool, &url)))
+            })
+            .collect::<Result<Vec<RedisPool>, BookServiceError>>()
+            .map(|pools| {
+                Some(Arc::new(Replicas {
+                    pools,
+                    next: AtomicUsize::new(0),
+                }))
+            })
+    }
+
+    fn next(&self) -> &RedisPool {
+        &self.pools[self.next.fetch_add(1, Ordering::Relaxed) % self.pools.len()]
+    }
 }
 
 impl BookDao {
+    /// Connects to the Redis master, discovering it through the Sentinels when those are
+    /// configured and following it through failovers from then on. When cluster nodes
+    /// are configured the keys are spread over the masters of the cluster instead, and
+    /// per-book keys are hash tagged so a book and its history share a slot.
     pub fn new(settings: &Settings) -> Result<BookDao, BookServiceError> {
-        settings
-            .redis_url()
-            .and_then(|url| {
-                RedisConnectionManager::new(url.as_ref()).map_err(|e| {
-                    eprintln!("Could not create connection manager! {} ", &e);
-                    BookServiceError::from(e)
-                })
+        if !settings.redis_cluster_nodes().is_empty() {
+            if !settings.redis_replicas().is_empty() {
+                eprintln!("Ignoring the replicas, reads are made on the cluster masters");
+            }
+            eprintln!(
+                "The outbox is appended after each write commits in a cluster, a write may be missing from it"
+            );
+            return Cluster::connect(settings).map(|cluster| BookDao {
+                redis_pool: RedisPool::Cluster(cluster),
+                namespace: settings.redis_namespace().with_hash_tags(),
+                replicas: None,
+                read_only: false,
+                events_channel: settings.events_channel().to_string(),
+                relays: Arc::new(Relays::default()),
+                outbox_trim: OutboxTrim::from_settings(settings),
+            });
+        }
+        let redis_pool = match Sentinel::from_settings(settings) {
+            Some(sentinel) => {
+                let (host, port) = sentinel.master_address()?;
+                println!("Sentinel reports master at {}:{}", &host, port);
+                let pool = RedisPool::standalone(&settings.redis_master_url(&host, port))?;
+                follow_failovers(sentinel, (host, port), pool.clone(), settings.clone());
+                pool
+            }
+            None => settings
+                .redis_url()
+                .and_then(|url| RedisPool::standalone(&url))?,
+        };
+        let dao = BookDao {
+            redis_pool,
+            namespace: settings.redis_namespace(),
+            replicas: Replicas::connect(settings)?,
+            read_only: false,
+            events_channel: settings.events_channel().to_string(),
+            relays: Arc::new(Relays::default()),
+            outbox_trim: OutboxTrim::from_settings(settings),
+        };
+        dao.report_schema();
+        Ok(dao)
+    }
+
+    /// A dao over the same connection pool keeping the catalog of the tenant in a
+    /// namespace of its own
+    pub fn for_tenant(&self, tenant: &str) -> BookDao {
+        BookDao {
+            redis_pool: self.redis_pool.clone(),
+            namespace: self.namespace.child(tenant),
+            replicas: self.replicas.clone(),
+            read_only: false,
+            events_channel: self.events_channel.to_owned(),
+            relays: self.relays.clone(),
+            outbox_trim: self.outbox_trim,
+        }
+    }
+
+    /// Makes a read on the next replica. None when there are no replicas or the read
+    /// failed on the replica, it is then up to the master.
+    fn on_replica<T, F>(&self, read: F) -> Option<Result<T, BookServiceError>>
+    where
+        F: FnOnce(&BookDao) -> Result<T, BookServiceError>,
+    {
+        let replica = BookDao {
+            redis_pool: self.replicas.as_ref()?.next().clone(),
+            namespace: self.namespace.clone(),
+            replicas: None,
+            read_only: true,
+            events_channel: self.events_channel.to_owned(),
+            relays: self.relays.clone(),
+            outbox_trim: self.outbox_trim,
+        };
+        match read(&replica) {
+            Err(e) => {
+                eprintln!("Read from replica failed, reading from the master {}", e);
+                None
+            }
+            result => Some(result),
+        }
+    }
+
+    /// Makes a read of a list on the next replica like `on_replica`, except that an empty
+    /// list is read again on the master as the replica may not have caught up yet.
+    fn on_replica_unless_empty<T, F>(&self, read: F) -> Option<Result<Vec<T>, BookServiceError>>
+    where
+        F: FnOnce(&BookDao) -> Result<Vec<T>, BookServiceError>,
+    {
+        match self.on_replica(read) {
+            Some(Ok(ref found)) if found.is_empty() => None,
+            read => read,
+        }
+    }
+
+    /// Renames every key of the namespace the dao was created with into another one,
+    /// returning how many keys were moved. Keys whose new name is already taken are left
+    /// alone and reported. Meant to be run with the service stopped, books written while
+    /// it runs may be left behind.
+    pub fn rename_namespace(&self, to: &Namespace) -> Result<usize, BookServiceError> {
+        println!(
+            "Renaming namespace {:?} to {:?}",
+            self.namespace.name(),
+            to.name()
+        );
+        if &self.namespace == to {
+            return Ok(0);
+        }
+        // SCAN may return a key more than once, so every key is collected before renaming
+        let keys = self.scan_keys(&self.namespace.patterns())?;
+        let mut renamed = 0;
+        for key in keys {
+            if let Some(target) = self.namespace.move_key(&key, to) {
+                let moved = self.move_key(&key, &target).map_err(|e| {
+                    eprintln!("Failed to rename {} {:?}", &key, &e);
+                    BookServiceError::BookUpdateError(e)
+                })?;
+                if moved {
+                    renamed += 1;
+                } else {
+                    eprintln!("Not renaming {}, {} already exists", &key, &target);
+                }
+            }
+        }
+        Ok(renamed)
+    }
+
+    /// Every key on every master matching one of the patterns
+    fn scan_keys(&self, patterns: &[String]) -> Result<BTreeSet<String>, BookServiceError> {
+        let masters = self.redis_pool.masters().map_err(|e| {
+            eprintln!("Failed to scan namespace {:?}", &e);
+            BookServiceError::BookListError(e)
+        })?;
+        let mut keys = BTreeSet::new();
+        for conn in &masters {
+            for pattern in patterns {
+                let mut scan_cursor = 0;
+                loop {
+                    let (next_cursor, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                        .arg(scan_cursor)
+                        .arg("MATCH")
+                        .arg(pattern.to_owned())
+                        .arg("COUNT")
+                        .arg(NAMESPACE_SCAN_COUNT)
+                        .query(conn.deref())
+                        .map_err(|e| {
+                            eprintln!("Failed to scan namespace {:?}", &e);
+                            BookServiceError::BookListError(DaoCause::from(e))
+                        })?;
+                    keys.extend(batch);
+                    scan_cursor = next_cursor;
+                    if scan_cursor == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Brings every book of the namespace up to the current schema and records that it
+    /// is at it, returning how many books were migrated. Safe to run alongside the
+    /// service, each book is upgraded under WATCH.
+    pub fn migrate_schema(&self) -> Result<usize, BookServiceError> {
+        println!(
+            "Migrating namespace {:?} to schema {}",
+            self.namespace.name(),
+            SCHEMA_VERSION
+        );
+        let mut migrated = 0;
+        for key in self.scan_keys(&[self.namespace.book_pattern()])? {
+            let upgraded = self.upgrade_record(&key).map_err(|e| {
+                eprintln!("Failed to migrate {} {:?}", &key, &e);
+                BookServiceError::BookUpdateError(e)
+            })?;
+            if upgraded {
+                migrated += 1;
+            }
+        }
+        let schema_key = self.namespace.schema_key();
+        self.redis_pool
+            .get(&schema_key)
+            .and_then(|conn| {
+                conn.set::<_, _, ()>(schema_key.to_owned(), SCHEMA_VERSION)
+                    .map_err(DaoCause::from)
             })
-            .and_then(|mgr| {
-                r2d2::Pool::builder().build(mgr).map_err(|e| {
-                    eprintln!("Could not create connection pool! {} ", &e);
-                    BookServiceError::from(e)
-                })
+            .map_err(|e| {
+                eprintln!("Failed to record the schema {:?}", &e);
+                BookServiceError::BookUpdateError(e)
+            })?;
+        Ok(migrated)
+    }
+
+    /// The change record of a write, appended to the outbox in the transaction of the
+    /// write so it is there exactly when the write is. That only holds outside a cluster,
+    /// in one the record is appended after the write commits and is lost should that fail.
+    fn outbox_change(
+        &self,
+        event_type: Operation,
+        book: &Book,
+        version: u64,
+    ) -> redis::RedisResult<IndexChange> {
+        let record = BookEvent::new(event_type, book, version, unix_time())
+            .to_vec()
+            .map_err(|e| {
+                eprintln!("Could not serialize the change record {}", e);
+                RedisError::from((ErrorKind::TypeError, "unserializable change record"))
+            })?;
+        Ok(IndexChange::Outbox(
+            self.namespace.outbox_key(),
+            record,
+            self.outbox_trim,
+        ))
+    }
+
+    /// Tells downstream services about a committed write. The write stands whether or
+    /// not the event goes out, so a failure to publish is only logged.
+    fn publish(&self, event_type: Operation, book: &Book, version: u64) {
+        let channel = self.namespace.channel(&self.events_channel);
+        let published = BookEvent::new(event_type, book, version, unix_time())
+            .to_vec()
+            .and_then(|payload| {
+                self.redis_pool
+                    .get(&channel)
+                    .and_then(|conn| {
+                        conn.publish::<_, _, ()>(channel.to_owned(), payload)
+                            .map_err(DaoCause::from)
+                    })
+                    .map_err(BookServiceError::BookUpdateError)
+            });
+        if let Err(e) = published {
+            eprintln!(
+                "Could not publish the {} of {} {}",
+                event_type.as_str(),
+                &book.book_id,
+                e
+            );
+        }
+    }
+
+    /// Scans the namespace for book records the service can not read, index entries
+    /// naming books that are not indexed there and history lists of books that no longer
+    /// exist. With `repair` unreadable records are moved aside under QUARANTINE-, and the
+    /// dangling entries and orphaned lists removed. Repairing is meant to be done with
+    /// the service stopped, as a book written during the check may look half-written.
+    pub fn check(&self, repair: bool) -> Result<CheckReport, BookServiceError> {
+        println!("Checking namespace {:?}", self.namespace.name());
+        let mut report = CheckReport::default();
+        // the readable books by key, along with whether they are in the trash
+        let mut books: HashMap<String, (Book, bool)> = HashMap::new();
+        for key in self.scan_keys(&[self.namespace.book_pattern()])? {
+            match self.key_type(&key)?.as_str() {
+                // deleted since the scan
+                "none" => continue,
+                "hash" => (),
+                found => {
+                    report.problems.push(Problem::WrongType {
+                        key,
+                        expected: "hash",
+                        found: found.to_string(),
+                    });
+                    continue;
+                }
+            }
+            report.books += 1;
+            let record: HashMap<String, String> = self
+                .redis_pool
+                .get(&key)
+                .and_then(|conn| conn.hgetall(key.to_owned()).map_err(DaoCause::from))
+                .map_err(check_failed)?;
+            match book_from_map(&key, &record) {
+                Ok(book) => {
+                    books.insert(key, (book, is_trashed(&record)));
+                }
+                Err(e) => report.problems.push(Problem::Malformed {
+                    key,
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        let indexed = |id: &str| books.get(&self.namespace.book_key_for_id(id));
+
+        for key in self.scan_keys(&[self.namespace.author_pattern()])? {
+            let problems = self.check_index(&key, "set", |id| match indexed(id) {
+                Some((book, false)) => self.namespace.author_key(&book.author) == key,
+                _ => false,
+            })?;
+            report.problems.extend(problems);
+        }
+        for key in self.scan_keys(&[self.namespace.term_pattern()])? {
+            let problems = self.check_index(&key, "set", |id| match indexed(id) {
+                Some((book, false)) => search::tokenise(&book.title)
+                    .iter()
+                    .any(|term| self.namespace.term_key(term) == key),
+                _ => false,
+            })?;
+            report.problems.extend(problems);
+        }
+        let problems = self.check_missing_entries(&books, &report.problems)?;
+        report.problems.extend(problems);
+        let problems =
+            self.check_index(&self.namespace.trash_key(), "zset", |id| {
+                match indexed(id) {
+                    Some((_, trashed)) => *trashed,
+                    None => false,
+                }
+            })?;
+        report.problems.extend(problems);
+
+        for key in self.scan_keys(&[self.namespace.history_pattern()])? {
+            match self.key_type(&key)?.as_str() {
+                "none" | "list" => (),
+                found => {
+                    report.problems.push(Problem::WrongType {
+                        key,
+                        expected: "list",
+                        found: found.to_string(),
+                    });
+                    continue;
+                }
+            }
+            // the history of a book that is there but unreadable is not left behind
+            if let Some(book_key) = self.namespace.history_book_key(&key) {
+                if self.key_type(&book_key)? == "none" {
+                    report.problems.push(Problem::Orphaned { key });
+                }
+            }
+        }
+
+        if repair {
+            for problem in &report.problems {
+                match self.repair(problem) {
+                    Ok(true) => report.repaired += 1,
+                    Ok(false) => eprintln!("Could not repair {}, the key changed", problem),
+                    Err(e) => eprintln!("Could not repair {} {}", problem, e),
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// The Redis type of the key, "none" when it does not exist
+    fn key_type(&self, key: &str) -> Result<String, BookServiceError> {
+        self.redis_pool
+            .get(key)
+            .and_then(|conn| {
+                redis::cmd("TYPE")
+                    .arg(key)
+                    .query(conn.deref())
+                    .map_err(DaoCause::from)
+            })
+            .map_err(check_failed)
+    }
+
+    /// The problems of an index set, or the trash sorted set, whose entries should all
+    /// be ids of books it indexes
+    fn check_index<F>(
+        &self,
+        key: &str,
+        expected: &'static str,
+        indexes: F,
+    ) -> Result<Vec<Problem>, BookServiceError>
+    where
+        F: Fn(&str) -> bool,
+    {
+        match self.key_type(key)?.as_str() {
+            "none" => return Ok(Vec::new()),
+            found if found != expected => {
+                return Ok(vec![Problem::WrongType {
+                    key: key.to_string(),
+                    expected,
+                    found: found.to_string(),
+                }]);
+            }
+            _ => (),
+        }
+        let conn = self.redis_pool.get(key).map_err(check_failed)?;
+        let ids: Vec<String> = if expected == "zset" {
+            conn.zrange::<_, Vec<String>>(key, 0, -1)
+        } else {
+            conn.smembers::<_, Vec<String>>(key)
+        }
+        .map_err(|e| check_failed(DaoCause::from(e)))?;
+        Ok(ids
+            .into_iter()
+            .filter(|id| !indexes(id))
+            .map(|id| Problem::DanglingEntry {
+                key: key.to_string(),
+                id,
+            })
+            .collect())
+    }
+
+    /// The author and title term entries missing for the books that are not trashed.
+    /// Index keys already found to be of the wrong type are left to their own problem.
+    fn check_missing_entries(
+        &self,
+        books: &HashMap<String, (Book, bool)>,
+        found: &[Problem],
+    ) -> Result<Vec<Problem>, BookServiceError> {
+        let wrong_type: BTreeSet<&String> = found
+            .iter()
+            .filter_map(|problem| match problem {
+                Problem::WrongType { key, .. } => Some(key),
+                _ => None,
             })
-            .map(|connection_mgr| BookDao {
-                redis_pool: connection_mgr,
+            .collect();
+        let mut problems = Vec::new();
+        for (book, _) in books.values().filter(|(_, trashed)| !*trashed) {
+            let id = book.book_id.hyphenated().to_string();
+            let mut keys = vec![self.namespace.author_key(&book.author)];
+            keys.extend(
+                search::tokenise(&book.title)
+                    .iter()
+                    .map(|term| self.namespace.term_key(term)),
+            );
+            for key in keys.into_iter().filter(|key| !wrong_type.contains(key)) {
+                let listed: bool = self
+                    .redis_pool
+                    .get(&key)
+                    .and_then(|conn| {
+                        conn.sismember(key.to_owned(), id.to_owned())
+                            .map_err(DaoCause::from)
+                    })
+                    .map_err(check_failed)?;
+                if !listed {
+                    problems.push(Problem::MissingEntry {
+                        key,
+                        id: id.to_owned(),
+                    });
+                }
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Quarantines, removes, adds or deletes what the problem was found in. False when
+    /// the key no longer holds what was found.
+    fn repair(&self, problem: &Problem) -> Result<bool, DaoCause> {
+        match problem {
+            Problem::WrongType { key, .. } | Problem::Malformed { key, .. } => {
+                let quarantined = self.namespace.quarantine_key(key);
+                println!("Quarantining {} as {}", key, &quarantined);
+                self.move_key(key, &quarantined)
+            }
+            Problem::DanglingEntry { key, id } => {
+                let conn = self.redis_pool.get(key)?;
+                let removed: usize = if key == &self.namespace.trash_key() {
+                    conn.zrem(key.to_owned(), id.to_owned())?
+                } else {
+                    conn.srem(key.to_owned(), id.to_owned())?
+                };
+                Ok(removed > 0)
+            }
+            Problem::MissingEntry { key, id } => {
+                let conn = self.redis_pool.get(key)?;
+                let added: usize = conn.sadd(key.to_owned(), id.to_owned())?;
+                Ok(added > 0)
+            }
+            Problem::Orphaned { key } => {
+                let conn = self.redis_pool.get(key)?;
+                let deleted: usize = conn.del(key.to_owned())?;
+                Ok(deleted > 0)
+            }
+        }
+    }
+
+    /// Logs when the schema recorded for the namespace is not the one the service writes
+    fn report_schema(&self) {
+        let schema_key = self.namespace.schema_key();
+        let stored: Result<Option<u32>, DaoCause> = self
+            .redis_pool
+            .get(&schema_key)
+            .and_then(|conn| conn.get(schema_key.to_owned()).map_err(DaoCause::from));
+        match stored {
+            Ok(Some(stored)) if stored < SCHEMA_VERSION => println!(
+                "Books are at schema {} and upgraded to {} as they are read, run migrate-schema to upgrade them all",
+                stored, SCHEMA_VERSION
+            ),
+            Ok(Some(stored)) if stored > SCHEMA_VERSION => eprintln!(
+                "Books are at schema {}, newer than the schema {} this service understands",
+                stored, SCHEMA_VERSION
+            ),
+            Ok(_) => (),
+            Err(e) => eprintln!("Could not read the schema {}", e),
+        }
+    }
+
+    /// Brings the record under the key up to the current schema, under WATCH so a write
+    /// racing the upgrade is not overwritten. Returns false when it was not outdated.
+    fn upgrade_record(&self, key: &str) -> Result<bool, DaoCause> {
+        let conn = self.redis_pool.get(key)?;
+        redis::transaction(conn.deref(), &[key], |pipe| {
+            let record: HashMap<String, String> = conn.hgetall(key)?;
+            if !schema::is_outdated(&record) {
+                return Ok(Some(false));
+            }
+            queue_upgrade(pipe, key, &record)
+                .query(conn.deref())
+                .map(|result: Option<()>| result.map(|_| true))
+        })
+        .map_err(DaoCause::from)
+    }
+
+    /// Writes the outdated records a read came across back at the current schema. The
+    /// read has already migrated them in memory, so a failure is only logged and the
+    /// record is upgraded by a later read.
+    fn upgrade_outdated<'a, I>(&self, records: I)
+    where
+        I: IntoIterator<Item = (&'a String, &'a HashMap<String, String>)>,
+    {
+        if self.read_only {
+            return;
+        }
+        for (key, record) in records {
+            if schema::is_outdated(record) {
+                if let Err(e) = self.upgrade_record(key) {
+                    eprintln!("Could not upgrade {} {}", key, e);
+                }
+            }
+        }
+    }
+
+    /// RENAMENX, unless the key and its new name are on different slots of a cluster.
+    /// The key is then copied over with DUMP and RESTORE, keeping its expiry, and
+    /// deleted.
+    fn move_key(&self, key: &str, target: &str) -> Result<bool, DaoCause> {
+        let conn = self.redis_pool.get(key)?;
+        if !self.redis_pool.is_cluster() || key_slot(key) == key_slot(target) {
+            return redis::cmd("RENAMENX")
+                .arg(key)
+                .arg(target)
+                .query(conn.deref())
+                .map_err(DaoCause::from);
+        }
+        let target_conn = self.redis_pool.get(target)?;
+        let taken: bool = target_conn.exists(target)?;
+        if taken {
+            return Ok(false);
+        }
+        let (dump, ttl): (Vec<u8>, i64) = redis::pipe()
+            .cmd("DUMP")
+            .arg(key)
+            .cmd("PTTL")
+            .arg(key)
+            .query(conn.deref())?;
+        redis::cmd("RESTORE")
+            .arg(target)
+            .arg(ttl.max(0))
+            .arg(dump)
+            .query::<()>(target_conn.deref())?;
+        conn.del::<_, ()>(key)?;
+        Ok(true)
+    }
+
+    /// Queues the index changes of a write into its transaction, or in a cluster hands
+    /// them back to be applied once the transaction committed
+    fn stage_index_changes(
+        &self,
+        pipe: &mut redis::Pipeline,
+        changes: Vec<IndexChange>,
+    ) -> Vec<IndexChange> {
+        if self.redis_pool.is_cluster() {
+            changes
+        } else {
+            for change in &changes {
+                change.queue(pipe);
+            }
+            Vec::new()
+        }
+    }
+
+    /// Applies the index changes staged by a committed write, one pipeline per master
+    fn apply_index_changes(&self, changes: &[IndexChange]) -> Result<(), DaoCause> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let keys: Vec<String> = changes
+            .iter()
+            .map(|change| change.key().to_owned())
+            .collect();
+        for (conn, positions) in self.redis_pool.by_master(&keys)? {
+            let mut pipe = redis::pipe();
+            for position in positions {
+                changes[position].queue(&mut pipe);
+            }
+            pipe.query::<()>(conn.deref())?;
+        }
+        Ok(())
+    }
+
+    /// Reads every key with one pipeline per master, returning the replies in the order
+    /// of the keys
+    fn read_each<T, F>(&self, keys: &[String], read: F) -> Result<Vec<T>, DaoCause>
+    where
+        T: redis::FromRedisValue,
+        F: Fn(&mut redis::Pipeline, String),
+    {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut replies: Vec<Option<T>> = keys.iter().map(|_| None).collect();
+        for (conn, positions) in self.redis_pool.by_master(keys)? {
+            let mut pipe = redis::pipe();
+            for &position in &positions {
+                read(&mut pipe, keys[position].to_owned());
+            }
+            let master_replies: Vec<T> = pipe.query(conn.deref())?;
+            for (position, reply) in positions.into_iter().zip(master_replies) {
+                replies[position] = Some(reply);
+            }
+        }
+        Ok(replies.into_iter().flatten().collect())
+    }
+
+    /// Reads the books stored under the keys. Keys that no longer exist and trashed books
+    /// are skipped.
+    fn load_books(&self, keys: &[String]) -> Result<Vec<Book>, BookServiceError> {
+        self.read_each(keys, |pipe, key| {
+            pipe.hgetall(key);
+        })
+        .map_err(|e| {
+            eprintln!("Error loading books {}", &e);
+            BookServiceError::BookListError(e)
+        })
+        .and_then(|maps: Vec<HashMap<String, String>>| {
+            self.upgrade_outdated(keys.iter().zip(maps.iter()));
+            keys.iter()
+                .zip(maps.iter())
+                .filter(|(_, hm)| !hm.is_empty() && !is_trashed(hm))
+                .map(|(key, hm)| book_from_map(key, hm))
+                .collect()
+        })
+    }
+}
+
+fn check_failed(e: DaoCause) -> BookServiceError {
+    eprintln!("Failed to check namespace {}", &e);
+    BookServiceError::BookListError(e)
+}
+
+/// Rebuilds the pool against every master the Sentinels promote, on a thread of its own
+fn follow_failovers(
+    sentinel: Sentinel,
+    master: (String, u32),
+    pool: RedisPool,
+    settings: Settings,
+) {
+    let spawned = thread::Builder::new()
+        .name("sentinel".to_string())
+        .spawn(move || {
+            sentinel.follow_master(master, |host, port| {
+                println!("Switching to master {}:{}", host, port);
+                pool.replace(&settings.redis_master_url(host, port))
+                    .map_err(BookServiceError::DaoInitializationError)
             })
+        });
+    if let Err(e) = spawned {
+        eprintln!("Could not start following failovers {}", e);
     }
+}
 
-    pub fn put(&self, entry: &Book) -> Result<(), BookServiceError> {
+/// The Redis backend
+impl BookStore for BookDao {
+    /// Writes the book, moving it between author and title term index sets when those
+    /// changed. The previous fields are read under WATCH so the indexes can not drift
+    /// from the hash.
+    fn put(&self, entry: &Book, source: &str) -> Result<u64, BookServiceError> {
         println!("put for book {:?}", &entry);
+        let key = self.namespace.book_key(&entry.book_id);
+        let mut staged = Vec::new();
         self.redis_pool
-            .get()
+            .get(&key)
             .map_err(|e| {
                 eprintln!("Failed to put book {:?}", &e);
-                BookServiceError::BookCreateError(DaoCause::from(e))
+                BookServiceError::BookCreateError(e)
             })
             .and_then(|conn| {
-                let key = id_key(&entry.book_id);
-                redis::pipe()
-                    .atomic()
-                    .hset(key.to_owned(), AUTHOR, entry.author.to_owned())
-                    .hset(key.to_owned(), TITLE, entry.title.to_owned())
+                redis::transaction(conn.deref(), &[key.to_owned()], |pipe| {
+                    let previous: HashMap<String, String> = conn.hgetall(key.to_owned())?;
+                    let mut changes =
+                        index_changes(&self.namespace, &entry.book_id, &previous, Some(entry));
+                    let version = version_from_map(&previous) + 1;
+                    changes.push(self.outbox_change(Operation::Put, entry, version)?);
+                    staged = self.stage_index_changes(pipe, changes);
+                    queue_book_writes(
+                        pipe,
+                        &self.namespace,
+                        &key,
+                        entry,
+                        &previous,
+                        Operation::Put,
+                        source,
+                    )?
                     .query(conn.deref())
-                    .map_err(|e| {
-                        eprintln!("Failed to put book {:?}", &e);
-                        BookServiceError::BookCreateError(DaoCause::from(e))
-                    })
+                    .map(|result: Option<(u64,)>| result.map(|(version,)| version))
+                })
+                .map_err(|e| {
+                    eprintln!("Failed to put book {:?}", &e);
+                    BookServiceError::BookCreateError(DaoCause::from(e))
+                })
+            })
+            .and_then(|version| {
+                self.apply_index_changes(&staged).map_err(|e| {
+                    eprintln!("Failed to index book {:?}", &e);
+                    BookServiceError::BookCreateError(e)
+                })?;
+                self.publish(Operation::Put, entry, version);
+                Ok(version)
+            })
+    }
+
+    /// Writes the books a batch at a time over a single connection, each batch is one
+    /// WATCH/MULTI transaction over all of its keys. A book appearing twice in a batch
+    /// has its second write indexed from the state the first one left. The books of a
+    /// batch are spread over the slots of a cluster, so there each is put on its own.
+    fn put_many(&self, entries: &[Book], source: &str) -> Result<Vec<u64>, BookServiceError> {
+        println!("put for {} books", entries.len());
+        if self.redis_pool.is_cluster() {
+            return entries
+                .iter()
+                .map(|entry| self.put(entry, source))
+                .collect();
+        }
+        let conn = self.redis_pool.get("").map_err(|e| {
+            eprintln!("Failed to put books {:?}", &e);
+            BookServiceError::BookCreateError(e)
+        })?;
+        let mut versions = Vec::with_capacity(entries.len());
+        for batch in entries.chunks(BULK_BATCH_SIZE) {
+            let keys: Vec<String> = batch
+                .iter()
+                .map(|book| self.namespace.book_key(&book.book_id))
+                .collect();
+            let written: Vec<u64> = redis::transaction(conn.deref(), &keys, |pipe| {
+                let mut read = redis::pipe();
+                for key in &keys {
+                    read.hgetall(key.to_owned());
+                }
+                let stored: Vec<HashMap<String, String>> = read.query(conn.deref())?;
+                let mut state: HashMap<&String, HashMap<String, String>> =
+                    keys.iter().zip(stored).collect();
+                for (key, book) in keys.iter().zip(batch) {
+                    let previous = state.remove(key).unwrap_or_default();
+                    let mut changes =
+                        index_changes(&self.namespace, &book.book_id, &previous, Some(book));
+                    let version = version_from_map(&previous) + 1;
+                    changes.push(self.outbox_change(Operation::Put, book, version)?);
+                    self.stage_index_changes(pipe, changes);
+                    queue_book_writes(
+                        pipe,
+                        &self.namespace,
+                        key,
+                        book,
+                        &previous,
+                        Operation::Put,
+                        source,
+                    )?;
+                    state.insert(key, book_to_map(book, version));
+                }
+                pipe.query(conn.deref())
             })
+            .map_err(|e| {
+                eprintln!("Failed to put books {:?}", &e);
+                BookServiceError::BookCreateError(DaoCause::from(e))
+            })?;
+            for (book, version) in batch.iter().zip(&written) {
+                self.publish(Operation::Put, book, *version);
+            }
+            versions.extend(written);
+        }
+        Ok(versions)
     }
 
-    pub fn get(&self, uuid: &Uuid) -> Result<Book, BookServiceError> {
-        let key = id_key(uuid);
+    /// Read from the master, the version is the ETag a client sends back with its next
+    /// write, which a lagging replica would hand out stale
+    fn get_versioned(&self, uuid: &Uuid) -> Result<VersionedBook, BookServiceError> {
+        let key = self.namespace.book_key(uuid);
         self.redis_pool
-            .get()
+            .get(&key)
             .map_err(|e| {
                 eprintln!("Error Getting book {}", &e);
-                BookServiceError::BookGetError(DaoCause::from(e))
+                BookServiceError::BookGetError(e)
             })
             .and_then(|conn| {
                 conn.hgetall(key.to_owned()).map_err(|e| {
@@ -76,78 +1126,1740 @@ impl BookDao {
                     BookServiceError::BookGetError(DaoCause::from(e))
                 })
             })
-            .and_then(|ref hm: HashMap<String, String>| book_from_map(key.as_ref(), hm))
+            .and_then(|ref hm: HashMap<String, String>| {
+                self.upgrade_outdated(Some((&key, hm)));
+                if is_trashed(hm) {
+                    return Err(BookServiceError::NotFoundError);
+                }
+                book_from_map(key.as_ref(), hm).map(|book| VersionedBook {
+                    book,
+                    version: version_from_map(hm),
+                })
+            })
     }
-}
 
-fn book_from_map(key: &str, hm: &HashMap<String, String>) -> Result<Book, BookServiceError> {
-    uuid_from_key(key).and_then(|book_id| {
-        let author = hm.get(AUTHOR).ok_or_else(|| {
-            eprintln!("Book entry for key {} does not contain field author", key);
-            BookServiceError::MissingFieldError(AUTHOR.to_string())
-        })?;
-        let title = hm.get(TITLE).ok_or_else(|| {
-            eprintln!("Book entry for key {} does not contain field title", key);
-            BookServiceError::MissingFieldError(TITLE.to_string())
+    /// Returns a page of books walking the keyspace of each master in turn with SCAN,
+    /// starting at the opaque cursor handed out as `next` by the previous page. SCAN may
+    /// hand back a few more keys than asked for, so a page can be slightly larger than
+    /// the limit; nothing is skipped unless the slots of a cluster move between pages.
+    /// Listings stay on the master, a SCAN cursor means nothing to another node.
+    fn list(&self, cursor: Option<&str>, limit: usize) -> Result<BookPage, BookServiceError> {
+        let (mut master, mut scan_cursor) = cursor.map(decode_cursor).unwrap_or(Ok((0, 0)))?;
+        let masters = self.redis_pool.masters().map_err(|e| {
+            eprintln!("Error listing books {}", &e);
+            BookServiceError::BookListError(e)
         })?;
-        Ok(Book {
-            book_id,
-            author: author.to_owned(),
-            title: title.to_owned(),
-        })
-    })
-}
-
-fn id_key(uuid: &Uuid) -> String {
-    "BOOK-".to_string() + uuid.hyphenated().to_string().as_ref()
-}
+        let mut books = Vec::new();
+        while master < masters.len() {
+            let (next_cursor, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(scan_cursor)
+                .arg("MATCH")
+                .arg(self.namespace.book_pattern())
+                .arg("COUNT")
+                .arg(limit - books.len())
+                .query(masters[master].deref())
+                .map_err(|e| {
+                    eprintln!("Error listing books {}", &e);
+                    BookServiceError::BookListError(DaoCause::from(e))
+                })?;
 
-fn uuid_from_key(key: &str) -> Result<Uuid, BookServiceError> {
-    let minus_prefix = &key[5..];
-    Uuid::parse_str(minus_prefix).map_err(|e| {
-        eprintln!("Unable to parse UUID from key: {}", key);
-        BookServiceError::from(e)
-    })
-}
-
-#[cfg(test)]
-mod tests {
-    use testcontainers;
+            // keys deleted between the SCAN and the HGETALL are skipped
+            books.extend(self.load_books(&keys)?);
 
-    use self::testcontainers::*;
-    use super::*;
+            if next_cursor == 0 {
+                master += 1;
+                scan_cursor = 0;
+            } else {
+                scan_cursor = next_cursor;
+            }
+            if books.len() >= limit {
+                break;
+            }
+        }
+        Ok(BookPage {
+            books,
+            next: Some(master)
+                .filter(|master| *master < masters.len())
+                .map(|master| encode_cursor(master, scan_cursor)),
+        })
+    }
 
-    #[test]
-    fn test_uuid_from_key() {
-        let key = "BOOK-0bcd291d-b7c5-4390-965f-8a70707d22a5";
-        let result = uuid_from_key(key);
-        assert!(result.is_ok());
+    /// Replaces an existing book, returning None if there was no entry for its uuid.
+    /// The existence and version checks and the write happen in one WATCH/MULTI
+    /// transaction.
+    fn update(
+        &self,
+        entry: &Book,
+        expected_version: Option<u64>,
+        source: &str,
+    ) -> Result<Option<u64>, BookServiceError> {
+        println!("update for book {:?}", &entry);
+        let key = self.namespace.book_key(&entry.book_id);
+        let mut staged = Vec::new();
+        self.redis_pool
+            .get(&key)
+            .map_err(|e| {
+                eprintln!("Failed to update book {:?}", &e);
+                BookServiceError::BookUpdateError(e)
+            })
+            .and_then(|conn| {
+                let mut outcome = Ok(None);
+                redis::transaction(conn.deref(), &[key.to_owned()], |pipe| {
+                    staged.clear();
+                    let previous: HashMap<String, String> = conn.hgetall(key.to_owned())?;
+                    outcome = if previous.is_empty() || is_trashed(&previous) {
+                        Ok(None)
+                    } else {
+                        check_version(expected_version, version_from_map(&previous)).map(Some)
+                    };
+                    match outcome {
+                        Ok(Some(_)) => {
+                            let mut changes = index_changes(
+                                &self.namespace,
+                                &entry.book_id,
+                                &previous,
+                                Some(entry),
+                            );
+                            let version = version_from_map(&previous) + 1;
+                            changes.push(self.outbox_change(Operation::Update, entry, version)?);
+                            staged = self.stage_index_changes(pipe, changes);
+                            queue_book_writes(
+                                pipe,
+                                &self.namespace,
+                                &key,
+                                entry,
+                                &previous,
+                                Operation::Update,
+                                source,
+                            )?
+                            .query(conn.deref())
+                            .map(|result: Option<(u64,)>| {
+                                result.map(|(version,)| outcome = Ok(Some(version)))
+                            })
+                        }
+                        _ => Ok(Some(())),
+                    }
+                })
+                .map_err(|e| {
+                    eprintln!("Failed to update book {:?}", &e);
+                    BookServiceError::BookUpdateError(DaoCause::from(e))
+                })
+                .and_then(|_: ()| outcome)
+            })
+            .and_then(|version| {
+                self.apply_index_changes(&staged).map_err(|e| {
+                    eprintln!("Failed to index book {:?}", &e);
+                    BookServiceError::BookUpdateError(e)
+                })?;
+                if let Some(version) = version {
+                    self.publish(Operation::Update, entry, version);
+                }
+                Ok(version)
+            })
     }
 
-    #[test]
-    fn test_put_get() {
-        let docker = clients::Cli::default();
-        let node = docker.run(images::redis::Redis::default());
-        let host_port = node.get_host_port(6379).unwrap();
+    /// Applies a JSON Merge Patch to the stored book and returns the merged result.
+    /// The read, merge and write happen in one WATCH/MULTI transaction.
+    fn patch(
+        &self,
+        uuid: &Uuid,
+        patch: &Value,
+        expected_version: Option<u64>,
+        source: &str,
+    ) -> Result<VersionedBook, BookServiceError> {
+        println!("patch for book {} {}", uuid, patch);
+        let key = self.namespace.book_key(uuid);
+        let mut staged = Vec::new();
+        self.redis_pool
+            .get(&key)
+            .map_err(|e| {
+                eprintln!("Failed to patch book {:?}", &e);
+                BookServiceError::BookUpdateError(e)
+            })
+            .and_then(|conn| {
+                let mut outcome = Err(BookServiceError::NotFoundError);
+                redis::transaction(conn.deref(), &[key.to_owned()], |pipe| {
+                    staged.clear();
+                    let hm: HashMap<String, String> = conn.hgetall(key.to_owned())?;
+                    let merged = if hm.is_empty() || is_trashed(&hm) {
+                        Err(BookServiceError::NotFoundError)
+                    } else {
+                        check_version(expected_version, version_from_map(&hm))
+                            .and_then(|_| book_from_map(key.as_ref(), &hm))
+                            .and_then(|book| book.merge_patch(patch))
+                    };
+                    match merged {
+                        Ok(merged) => {
+                            let mut changes =
+                                index_changes(&self.namespace, uuid, &hm, Some(&merged));
+                            let version = version_from_map(&hm) + 1;
+                            changes.push(self.outbox_change(Operation::Patch, &merged, version)?);
+                            staged = self.stage_index_changes(pipe, changes);
+                            queue_book_writes(
+                                pipe,
+                                &self.namespace,
+                                &key,
+                                &merged,
+                                &hm,
+                                Operation::Patch,
+                                source,
+                            )?
+                            .query(conn.deref())
+                            .map(|result: Option<(u64,)>| {
+                                result.map(|(version,)| {
+                                    outcome = Ok(VersionedBook {
+                                        book: merged,
+                                        version,
+                                    })
+                                })
+                            })
+                        }
+                        Err(e) => {
+                            outcome = Err(e);
+                            Ok(Some(()))
+                        }
+                    }
+                })
+                .map_err(|e| {
+                    eprintln!("Failed to patch book {:?}", &e);
+                    BookServiceError::BookUpdateError(DaoCause::from(e))
+                })
+                .and_then(|_: ()| outcome)
+            })
+            .and_then(|versioned| {
+                self.apply_index_changes(&staged).map_err(|e| {
+                    eprintln!("Failed to index book {:?}", &e);
+                    BookServiceError::BookUpdateError(e)
+                })?;
+                self.publish(Operation::Patch, &versioned.book, versioned.version);
+                Ok(versioned)
+            })
+    }
 
-        let settings = Settings::default()
-            .with_redis_host("localhost")
-            .with_redis_port(host_port);
+    /// Moves the book to the trash, removing its index entries. Returns false if there
+    /// was no live entry for the uuid.
+    fn delete(
+        &self,
+        uuid: &Uuid,
+        expected_version: Option<u64>,
+        source: &str,
+    ) -> Result<bool, BookServiceError> {
+        println!("delete for book {}", uuid);
+        let key = self.namespace.book_key(uuid);
+        let mut staged = Vec::new();
+        // the state the book was trashed in along with the version, for the event
+        let mut trashed = None;
+        self.redis_pool
+            .get(&key)
+            .map_err(|e| {
+                eprintln!("Failed to delete book {:?}", &e);
+                BookServiceError::BookDeleteError(e)
+            })
+            .and_then(|conn| {
+                let mut outcome = Ok(false);
+                redis::transaction(conn.deref(), &[key.to_owned()], |pipe| {
+                    staged.clear();
+                    trashed = None;
+                    let previous: HashMap<String, String> = conn.hgetall(key.to_owned())?;
+                    outcome = if previous.is_empty() || is_trashed(&previous) {
+                        Ok(false)
+                    } else {
+                        check_version(expected_version, version_from_map(&previous)).map(|_| true)
+                    };
+                    match outcome {
+                        Ok(true) => {
+                            let deleted_at = unix_time();
+                            // a record missing its fields has no state worth a revision
+                            if let Ok(book) = book_from_map(key.as_ref(), &previous) {
+                                let version = version_from_map(&previous) + 1;
+                                let revision = Revision::new(
+                                    &book,
+                                    version,
+                                    Operation::Delete,
+                                    source,
+                                    deleted_at,
+                                );
+                                queue_revision(pipe, &self.namespace, &revision)?;
+                                trashed = Some((book, version));
+                            }
+                            let mut changes = index_changes(&self.namespace, uuid, &previous, None);
+                            changes.push(IndexChange::Trash(
+                                self.namespace.trash_key(),
+                                uuid.hyphenated().to_string(),
+                                deleted_at,
+                            ));
+                            if let Some((book, version)) = &trashed {
+                                changes.push(self.outbox_change(
+                                    Operation::Delete,
+                                    book,
+                                    *version,
+                                )?);
+                            }
+                            staged = self.stage_index_changes(pipe, changes);
+                            queue_upgrade(pipe, &key, &previous)
+                                .hset(key.to_owned(), DELETED_AT, deleted_at)
+                                .ignore()
+                                .hincr(key.to_owned(), VERSION, 1)
+                                .ignore()
+                                .query(conn.deref())
+                        }
+                        _ => Ok(Some(())),
+                    }
+                })
+                .map_err(|e| {
+                    eprintln!("Failed to delete book {:?}", &e);
+                    BookServiceError::BookDeleteError(DaoCause::from(e))
+                })
+                .and_then(|_: ()| outcome)
+            })
+            .and_then(|deleted| {
+                self.apply_index_changes(&staged).map_err(|e| {
+                    eprintln!("Failed to trash book {:?}", &e);
+                    BookServiceError::BookDeleteError(e)
+                })?;
+                if let (true, Some((book, version))) = (deleted, &trashed) {
+                    self.publish(Operation::Delete, book, *version);
+                }
+                Ok(deleted)
+            })
+    }
 
-        let key = "BOOK-0bcd291d-b7c5-4390-965f-8a70707d22a5";
-        let book_id = uuid_from_key(key).unwrap();
+    fn list_trash(&self) -> Result<Vec<TrashedBook>, BookServiceError> {
+        let trash_key = self.namespace.trash_key();
+        self.redis_pool
+            .get(&trash_key)
+            .map_err(|e| {
+                eprintln!("Error listing trash {}", &e);
+                BookServiceError::BookListError(e)
+            })
+            .and_then(|conn| {
+                conn.zrange(trash_key.to_owned(), 0, -1).map_err(|e| {
+                    eprintln!("Error listing trash {}", &e);
+                    BookServiceError::BookListError(DaoCause::from(e))
+                })
+            })
+            .and_then(|ids: Vec<String>| {
+                let keys: Vec<String> = ids
+                    .iter()
+                    .map(|id| self.namespace.book_key_for_id(id))
+                    .collect();
+                self.read_each(&keys, |pipe, key| {
+                    pipe.hgetall(key);
+                })
+                .map_err(|e| {
+                    eprintln!("Error listing trash {}", &e);
+                    BookServiceError::BookListError(e)
+                })
+                .and_then(|maps: Vec<HashMap<String, String>>| {
+                    self.upgrade_outdated(keys.iter().zip(maps.iter()));
+                    keys.iter()
+                        .zip(maps.iter())
+                        .filter_map(|(key, hm)| trashed_from_map(key, hm))
+                        .collect()
+                })
+            })
+    }
 
-        let book = Book::default()
-            .with_book_id(&book_id)
-            .with_author("Robert")
-            .with_title("Jordan");
+    /// Puts the book back into its index sets and removes the deletion mark, in one
+    /// WATCH/MULTI transaction
+    fn restore(
+        &self,
+        uuid: &Uuid,
+        source: &str,
+    ) -> Result<Option<VersionedBook>, BookServiceError> {
+        println!("restore for book {}", uuid);
+        let key = self.namespace.book_key(uuid);
+        let mut staged = Vec::new();
+        self.redis_pool
+            .get(&key)
+            .map_err(|e| {
+                eprintln!("Failed to restore book {:?}", &e);
+                BookServiceError::BookUpdateError(e)
+            })
+            .and_then(|conn| {
+                let mut outcome = Ok(None);
+                redis::transaction(conn.deref(), &[key.to_owned()], |pipe| {
+                    staged.clear();
+                    let previous: HashMap<String, String> = conn.hgetall(key.to_owned())?;
+                    let trashed = if is_trashed(&previous) {
+                        book_from_map(key.as_ref(), &previous).map(Some)
+                    } else {
+                        Ok(None)
+                    };
+                    match trashed {
+                        Ok(Some(book)) => {
+                            let mut changes =
+                                index_changes(&self.namespace, uuid, &previous, Some(&book));
+                            let version = version_from_map(&previous) + 1;
+                            changes.push(self.outbox_change(Operation::Restore, &book, version)?);
+                            staged = self.stage_index_changes(pipe, changes);
+                            queue_book_writes(
+                                pipe,
+                                &self.namespace,
+                                &key,
+                                &book,
+                                &previous,
+                                Operation::Restore,
+                                source,
+                            )?
+                            .query(conn.deref())
+                            .map(|result: Option<(u64,)>| {
+                                result.map(|(version,)| {
+                                    outcome = Ok(Some(VersionedBook { book, version }))
+                                })
+                            })
+                        }
+                        other => {
+                            outcome = other.map(|_| None);
+                            Ok(Some(()))
+                        }
+                    }
+                })
+                .map_err(|e| {
+                    eprintln!("Failed to restore book {:?}", &e);
+                    BookServiceError::BookUpdateError(DaoCause::from(e))
+                })
+                .and_then(|_: ()| outcome)
+            })
+            .and_then(|restored| {
+                self.apply_index_changes(&staged).map_err(|e| {
+                    eprintln!("Failed to index book {:?}", &e);
+                    BookServiceError::BookUpdateError(e)
+                })?;
+                if let Some(ref versioned) = restored {
+                    self.publish(Operation::Restore, &versioned.book, versioned.version);
+                }
+                Ok(restored)
+            })
+    }
 
-        let dao = BookDao::new(&settings).unwrap();
+    /// Deletes the hashes of books trashed at or before the time. Each book is checked
+    /// and removed in its own transaction so one restored meanwhile survives.
+    fn purge(&self, deleted_before: u64) -> Result<usize, BookServiceError> {
+        let trash_key = self.namespace.trash_key();
+        let ids: Vec<String> = self
+            .redis_pool
+            .get(&trash_key)
+            .map_err(|e| {
+                eprintln!("Failed to purge trash {:?}", &e);
+                BookServiceError::BookDeleteError(e)
+            })
+            .and_then(|conn| {
+                conn.zrangebyscore(trash_key.to_owned(), "-inf", deleted_before)
+                    .map_err(|e| {
+                        eprintln!("Failed to purge trash {:?}", &e);
+                        BookServiceError::BookDeleteError(DaoCause::from(e))
+                    })
+            })?;
+        let mut purged = 0;
+        for id in ids {
+            let key = self.namespace.book_key_for_id(&id);
+            let mut staged = Vec::new();
+            let removed = self
+                .redis_pool
+                .get(&key)
+                .and_then(|conn| {
+                    redis::transaction(conn.deref(), &[key.to_owned()], |pipe| {
+                        staged.clear();
+                        let deleted_at: Option<u64> = conn.hget(key.to_owned(), DELETED_AT)?;
+                        let changes =
+                            vec![IndexChange::Untrash(trash_key.to_owned(), id.to_owned())];
+                        match deleted_at {
+                            Some(deleted_at) if deleted_at <= deleted_before => {
+                                staged = self.stage_index_changes(pipe, changes);
+                                pipe.del(key.to_owned())
+                                    .ignore()
+                                    .del(self.namespace.history_key(&id))
+                                    .ignore()
+                                    .query(conn.deref())
+                                    .map(|result: Option<()>| result.map(|_| true))
+                            }
+                            Some(_) => Ok(Some(false)),
+                            // restored meanwhile, only its trash entry is stale
+                            None => {
+                                staged = self.stage_index_changes(pipe, changes);
+                                pipe.query(conn.deref())
+                                    .map(|result: Option<()>| result.map(|_| false))
+                            }
+                        }
+                    })
+                    .map_err(DaoCause::from)
+                })
+                .and_then(|removed| self.apply_index_changes(&staged).map(|_| removed))
+                .map_err(|e| {
+                    eprintln!("Failed to purge book {} {:?}", &id, &e);
+                    BookServiceError::BookDeleteError(e)
+                })?;
+            if removed {
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
 
-        let _ = dao.put(&book).unwrap();
+    fn history(&self, uuid: &Uuid) -> Result<Vec<Revision>, BookServiceError> {
+        if let Some(read) = self.on_replica_unless_empty(|replica| replica.history(uuid)) {
+            return read;
+        }
+        let key = self.namespace.history_key(&uuid.hyphenated().to_string());
+        self.redis_pool
+            .get(&key)
+            .map_err(|e| {
+                eprintln!("Error reading history {}", &e);
+                BookServiceError::BookGetError(e)
+            })
+            .and_then(|conn| {
+                conn.lrange(key.to_owned(), 0, -1).map_err(|e| {
+                    eprintln!("Error reading history {}", &e);
+                    BookServiceError::BookGetError(DaoCause::from(e))
+                })
+            })
+            .and_then(|entries: Vec<Vec<u8>>| {
+                entries
+                    .iter()
+                    .map(|entry| Revision::from_slice(entry))
+                    .collect()
+            })
+    }
+
+    /// Looks up every book by the author through the author index
+    fn find_by_author(&self, author: &str) -> Result<Vec<Book>, BookServiceError> {
+        if let Some(read) = self.on_replica_unless_empty(|replica| replica.find_by_author(author)) {
+            return read;
+        }
+        println!("find books by author {}", author);
+        let key = self.namespace.author_key(author);
+        self.redis_pool
+            .get(&key)
+            .map_err(|e| {
+                eprintln!("Error finding books by author {}", &e);
+                BookServiceError::BookListError(e)
+            })
+            .and_then(|conn| {
+                conn.smembers(key.to_owned()).map_err(|e| {
+                    eprintln!("Error finding books by author {}", &e);
+                    BookServiceError::BookListError(DaoCause::from(e))
+                })
+            })
+            .and_then(|ids: Vec<String>| {
+                let keys: Vec<String> = ids
+                    .iter()
+                    .map(|id| self.namespace.book_key_for_id(id))
+                    .collect();
+                self.load_books(&keys)
+            })
+    }
+
+    /// Searches titles through the term index. Books matching every term of the query
+    /// come first, followed by books matching fewer of them.
+    fn search(&self, query: &str, limit: usize) -> Result<Vec<Book>, BookServiceError> {
+        if let Some(read) = self.on_replica_unless_empty(|replica| replica.search(query, limit)) {
+            return read;
+        }
+        println!("search books for {}", query);
+        let terms = search::tokenise(query);
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+        let term_keys: Vec<String> = terms
+            .iter()
+            .map(|term| self.namespace.term_key(term))
+            .collect();
+        self.read_each(&term_keys, |pipe, key| {
+            pipe.smembers(key);
+        })
+        .map_err(|e| {
+            eprintln!("Error searching books {}", &e);
+            BookServiceError::BookListError(e)
+        })
+        .and_then(|term_matches: Vec<Vec<String>>| {
+            let keys: Vec<String> = search::rank(&term_matches)
+                .iter()
+                .take(limit)
+                .map(|id| self.namespace.book_key_for_id(id))
+                .collect();
+            self.load_books(&keys)
+        })
+    }
+
+    fn idempotent_response(
+        &self,
+        key: &str,
+    ) -> Result<Option<IdempotentResponse>, BookServiceError> {
+        let key = self.namespace.idempotency_key(key);
+        self.redis_pool
+            .get(&key)
+            .map_err(|e| {
+                eprintln!("Error reading idempotency key {}", &e);
+                BookServiceError::BookGetError(e)
+            })
+            .and_then(|conn| {
+                conn.get(key.to_owned()).map_err(|e| {
+                    eprintln!("Error reading idempotency key {}", &e);
+                    BookServiceError::BookGetError(DaoCause::from(e))
+                })
+            })
+            .and_then(|stored: Option<Vec<u8>>| match stored {
+                Some(ref stored) => IdempotentResponse::from_slice(stored).map(Some),
+                None => Ok(None),
+            })
+    }
+
+    /// SET NX EX, Redis expires the key once the ttl is up
+    fn save_idempotent_response(
+        &self,
+        key: &str,
+        response: &IdempotentResponse,
+        ttl_secs: u64,
+    ) -> Result<bool, BookServiceError> {
+        let value = response.to_vec()?;
+        let key = self.namespace.idempotency_key(key);
+        self.redis_pool
+            .get(&key)
+            .map_err(|e| {
+                eprintln!("Error saving idempotency key {}", &e);
+                BookServiceError::BookCreateError(e)
+            })
+            .and_then(|conn| {
+                redis::cmd("SET")
+                    .arg(key.to_owned())
+                    .arg(value)
+                    .arg("NX")
+                    .arg("EX")
+                    .arg(ttl_secs.max(1))
+                    .query(conn.deref())
+                    .map(|saved: Option<String>| saved.is_some())
+                    .map_err(|e| {
+                        eprintln!("Error saving idempotency key {}", &e);
+                        BookServiceError::BookCreateError(DaoCause::from(e))
+                    })
+            })
+    }
+
+    fn replace_idempotent_response(
+        &self,
+        key: &str,
+        response: &IdempotentResponse,
+        ttl_secs: u64,
+    ) -> Result<(), BookServiceError> {
+        let value = response.to_vec()?;
+        let key = self.namespace.idempotency_key(key);
+        self.redis_pool
+            .get(&key)
+            .map_err(|e| {
+                eprintln!("Error saving idempotency key {}", &e);
+                BookServiceError::BookCreateError(e)
+            })
+            .and_then(|conn| {
+                conn.set_ex(key.to_owned(), value, ttl_secs.max(1) as usize)
+                    .map_err(|e| {
+                        eprintln!("Error saving idempotency key {}", &e);
+                        BookServiceError::BookCreateError(DaoCause::from(e))
+                    })
+            })
+    }
+
+    fn remove_idempotent_response(&self, key: &str) -> Result<(), BookServiceError> {
+        let key = self.namespace.idempotency_key(key);
+        self.redis_pool
+            .get(&key)
+            .map_err(|e| {
+                eprintln!("Error removing idempotency key {}", &e);
+                BookServiceError::BookDeleteError(e)
+            })
+            .and_then(|conn| {
+                conn.del(key.to_owned()).map_err(|e| {
+                    eprintln!("Error removing idempotency key {}", &e);
+                    BookServiceError::BookDeleteError(DaoCause::from(e))
+                })
+            })
+    }
+
+    /// Follows the channel the writes of the namespace are published on
+    fn events(&self) -> Option<Result<EventStream, BookServiceError>> {
+        let channel = self.namespace.channel(&self.events_channel);
+        let subscribed = self
+            .redis_pool
+            .url()
+            .and_then(|url| {
+                self.relays
+                    .subscribe(&url, &channel)
+                    .map_err(DaoCause::from)
+            })
+            .map_err(|e| {
+                eprintln!("Could not subscribe to {} {}", &channel, &e);
+                BookServiceError::BookGetError(e)
+            });
+        Some(subscribed)
+    }
+}
+
+/// Queues the hash writes for a book, previous holds the fields stored before the write
+/// (empty for a new book). Writing a trashed book takes it out of the trash. A revision
+/// of the write is appended to the history of the book. The new version is the only
+/// reply the pipeline does not ignore.
+fn queue_book_writes<'a>(
+    pipe: &'a mut redis::Pipeline,
+    namespace: &Namespace,
+    key: &str,
+    book: &Book,
+    previous: &HashMap<String, String>,
+    operation: Operation,
+    source: &str,
+) -> redis::RedisResult<&'a mut redis::Pipeline> {
+    // the version is only ever bumped under WATCH, so HINCR will hand out the next one
+    let revision = Revision::new(
+        book,
+        version_from_map(previous) + 1,
+        operation,
+        source,
+        unix_time(),
+    );
+    queue_revision(pipe, namespace, &revision)?;
+    queue_upgrade(pipe, key, previous);
+    if is_trashed(previous) {
+        pipe.hdel(key.to_owned(), DELETED_AT).ignore();
+    }
+    Ok(pipe
+        .hset(key.to_owned(), AUTHOR, book.author.to_owned())
+        .ignore()
+        .hset(key.to_owned(), TITLE, book.title.to_owned())
+        .ignore()
+        .hset(key.to_owned(), SCHEMA, SCHEMA_VERSION)
+        .ignore()
+        .hincr(key.to_owned(), VERSION, 1))
+}
+
+/// Queues the writes bringing a stored record up to the current schema, nothing for one
+/// that is already at it
+fn queue_upgrade<'a>(
+    pipe: &'a mut redis::Pipeline,
+    key: &str,
+    record: &HashMap<String, String>,
+) -> &'a mut redis::Pipeline {
+    if schema::is_outdated(record) {
+        let migrated = schema::migrate(record);
+        for (field, value) in migrated.iter() {
+            if record.get(field) != Some(value) {
+                pipe.hset(key.to_owned(), field.to_owned(), value.to_owned())
+                    .ignore();
+            }
+        }
+        for field in record.keys().filter(|field| !migrated.contains_key(*field)) {
+            pipe.hdel(key.to_owned(), field.to_owned()).ignore();
+        }
+    }
+    pipe
+}
+
+/// Queues appending the revision to the history list of its book
+fn queue_revision<'a>(
+    pipe: &'a mut redis::Pipeline,
+    namespace: &Namespace,
+    revision: &Revision,
+) -> redis::RedisResult<&'a mut redis::Pipeline> {
+    let entry = revision.to_vec().map_err(|e| {
+        eprintln!("Could not serialize the revision {}", e);
+        RedisError::from((ErrorKind::TypeError, "unserializable revision"))
+    })?;
+    Ok(pipe
+        .rpush(
+            namespace.history_key(&revision.book.book_id.hyphenated().to_string()),
+            entry,
+        )
+        .ignore())
+}
+
+/// The index maintenance of a write: the book leaves the author and title term sets its
+/// stored fields placed it in and joins those of the book written, None when it is
+/// trashed. Writing a trashed book takes it out of the trash.
+fn index_changes(
+    namespace: &Namespace,
+    uuid: &Uuid,
+    previous: &HashMap<String, String>,
+    book: Option<&Book>,
+) -> Vec<IndexChange> {
+    let id = uuid.hyphenated().to_string();
+    let mut changes = Vec::new();
+    if let Some(author) = previous.get(AUTHOR) {
+        changes.push(IndexChange::Remove(
+            namespace.author_key(author),
+            id.to_owned(),
+        ));
+    }
+    if let Some(title) = previous.get(TITLE) {
+        for term in search::tokenise(title) {
+            changes.push(IndexChange::Remove(
+                namespace.term_key(&term),
+                id.to_owned(),
+            ));
+        }
+    }
+    if let Some(book) = book {
+        if is_trashed(previous) {
+            changes.push(IndexChange::Untrash(namespace.trash_key(), id.to_owned()));
+        }
+        changes.push(IndexChange::Add(
+            namespace.author_key(&book.author),
+            id.to_owned(),
+        ));
+        for term in search::tokenise(&book.title) {
+            changes.push(IndexChange::Add(namespace.term_key(&term), id.to_owned()));
+        }
+    }
+    changes
+}
+
+/// Builds a book from the fields stored under the key
+pub fn book_from_map(key: &str, hm: &HashMap<String, String>) -> Result<Book, BookServiceError> {
+    let hm = schema::migrate(hm);
+    uuid_from_key(key).and_then(|book_id| {
+        let author = hm.get(AUTHOR).ok_or_else(|| {
+            eprintln!("Book entry for key {} does not contain field author", key);
+            BookServiceError::MissingFieldError(AUTHOR.to_string())
+        })?;
+        let title = hm.get(TITLE).ok_or_else(|| {
+            eprintln!("Book entry for key {} does not contain field title", key);
+            BookServiceError::MissingFieldError(TITLE.to_string())
+        })?;
+        Ok(Book {
+            book_id,
+            author: author.to_owned(),
+            title: title.to_owned(),
+        })
+    })
+}
+
+/// The fields a book is stored as, at the current schema
+pub fn book_to_map(book: &Book, version: u64) -> HashMap<String, String> {
+    let mut record = HashMap::new();
+    record.insert(AUTHOR.to_string(), book.author.to_owned());
+    record.insert(TITLE.to_string(), book.title.to_owned());
+    record.insert(VERSION.to_string(), version.to_string());
+    record.insert(SCHEMA.to_string(), SCHEMA_VERSION.to_string());
+    record
+}
+
+/// Books written before versioning was introduced are at version 0
+pub fn version_from_map(hm: &HashMap<String, String>) -> u64 {
+    hm.get(VERSION)
+        .and_then(|version| version.parse().ok())
+        .unwrap_or(0)
+}
+
+pub fn is_trashed(hm: &HashMap<String, String>) -> bool {
+    hm.contains_key(DELETED_AT)
+}
+
+/// Builds the trash entry for a book, None when it is no longer in the trash
+fn trashed_from_map(
+    key: &str,
+    hm: &HashMap<String, String>,
+) -> Option<Result<TrashedBook, BookServiceError>> {
+    hm.get(DELETED_AT)
+        .and_then(|deleted_at| deleted_at.parse().ok())
+        .map(|deleted_at| book_from_map(key, hm).map(|book| TrashedBook { book, deleted_at }))
+}
+
+/// Authors are indexed case and whitespace insensitively
+pub fn normalise_author(author: &str) -> String {
+    author
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// The key of the book in the unprefixed namespace
+pub fn id_key(uuid: &Uuid) -> String {
+    Namespace::default().book_key(uuid)
+}
+
+/// The uuid follows the BOOK- prefix, after whatever namespace the key is in, and may be
+/// wrapped in a hash tag
+fn uuid_from_key(key: &str) -> Result<Uuid, BookServiceError> {
+    let minus_prefix = key.rsplit(BOOK_PREFIX).next().unwrap_or(key);
+    Uuid::parse_str(minus_prefix.trim_start_matches('{').trim_end_matches('}')).map_err(|e| {
+        eprintln!("Unable to parse UUID from key: {}", key);
+        BookServiceError::from(e)
+    })
+}
+
+/// A listing cursor is the SCAN cursor on the master the listing is at, the master is
+/// left out for the first one so cursors outside of a cluster are plain SCAN cursors
+fn encode_cursor(master: usize, scan_cursor: u64) -> String {
+    let cursor = if master == 0 {
+        scan_cursor.to_string()
+    } else {
+        format!("{}:{}", master, scan_cursor)
+    };
+    base64::encode_config(&cursor, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_cursor(cursor: &str) -> Result<(usize, u64), BookServiceError> {
+    base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|decoded| {
+            let mut parts = decoded.rsplitn(2, ':');
+            let scan_cursor = parts.next()?.parse().ok()?;
+            match parts.next() {
+                Some(master) => master.parse().ok().map(|master| (master, scan_cursor)),
+                None => Some((0, scan_cursor)),
+            }
+        })
+        .ok_or_else(|| BookServiceError::InvalidQueryError(format!("invalid cursor {}", cursor)))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Stream;
+    use std::net::TcpListener;
+    use std::process::{Child, Command, Stdio};
+    use std::str;
+    use std::time::Instant;
+    use std::{env, fs};
+    use testcontainers;
+
+    use self::testcontainers::*;
+    use super::*;
+
+    #[test]
+    fn test_uuid_from_key() {
+        let key = "BOOK-0bcd291d-b7c5-4390-965f-8a70707d22a5";
+        let result = uuid_from_key(key);
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            uuid_from_key(&("staging:".to_string() + key)).unwrap()
+        );
+        assert_eq!(
+            result.unwrap(),
+            uuid_from_key("staging:BOOK-{0bcd291d-b7c5-4390-965f-8a70707d22a5}").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_author_key() {
+        let namespace = Namespace::default();
+        assert_eq!(
+            "AUTHOR-robert jordan",
+            namespace.author_key("Robert Jordan")
+        );
+        assert_eq!(
+            namespace.author_key("Robert Jordan"),
+            namespace.author_key("  robert   JORDAN ")
+        );
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        assert_eq!((0, 1234), decode_cursor(&encode_cursor(0, 1234)).unwrap());
+        assert_eq!((2, 1234), decode_cursor(&encode_cursor(2, 1234)).unwrap());
+        // cursors handed out before listing spanned cluster masters still work
+        assert_eq!(
+            (0, 1234),
+            decode_cursor(&base64::encode_config("1234", base64::URL_SAFE_NO_PAD)).unwrap()
+        );
+        assert!(decode_cursor("not a cursor").is_err());
+    }
+
+    #[test]
+    fn test_put_get() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+
+        let key = "BOOK-0bcd291d-b7c5-4390-965f-8a70707d22a5";
+        let book_id = uuid_from_key(key).unwrap();
+
+        let book = Book::default()
+            .with_book_id(&book_id)
+            .with_author("Robert")
+            .with_title("Jordan");
+
+        let dao = BookDao::new(&settings).unwrap();
+
+        let _ = dao.put(&book, "test").unwrap();
 
         let result = dao.get(&book_id).unwrap();
 
         assert_eq!(book, result);
     }
+
+    #[test]
+    fn test_list() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+
+        let dao = BookDao::new(&settings).unwrap();
+
+        let mut expected: Vec<Book> = (0..5)
+            .map(|i| Book::new(Uuid::new_v4(), "Robert Jordan", &format!("Volume {}", i)))
+            .collect();
+        for book in &expected {
+            let _ = dao.put(book, "test").unwrap();
+        }
+
+        let mut listed = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = dao.list(cursor.as_ref().map(String::as_ref), 2).unwrap();
+            listed.extend(page.books);
+            cursor = page.next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        expected.sort_by_key(|book| book.book_id);
+        listed.sort_by_key(|book| book.book_id);
+        assert_eq!(expected, listed);
+    }
+
+    #[test]
+    fn test_find_by_author() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+
+        let dao = BookDao::new(&settings).unwrap();
+
+        let eye = Book::new(Uuid::new_v4(), "Robert Jordan", "Eye of the World");
+        let hunt = Book::new(Uuid::new_v4(), "Robert Jordan", "The Great Hunt");
+        let bell = Book::new(
+            Uuid::new_v4(),
+            "Ernest Hemmingway",
+            "For Whom the Bell Tolls",
+        );
+        for book in &[&eye, &hunt, &bell] {
+            let _ = dao.put(book, "test").unwrap();
+        }
+
+        let mut found = dao.find_by_author("robert jordan").unwrap();
+        found.sort_by_key(|book| book.title.to_owned());
+        assert_eq!(vec![eye, hunt], found);
+
+        let moved = Book::new(bell.book_id, "Ernest Hemingway", "For Whom the Bell Tolls");
+        assert!(dao.update(&moved, None, "test").unwrap().is_some());
+        assert!(dao.find_by_author("Ernest Hemmingway").unwrap().is_empty());
+        assert_eq!(vec![moved], dao.find_by_author("Ernest Hemingway").unwrap());
+
+        assert!(dao.delete(&bell.book_id, None, "test").unwrap());
+        assert!(dao.find_by_author("Ernest Hemingway").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_search() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+
+        let dao = BookDao::new(&settings).unwrap();
+
+        let eye = Book::new(Uuid::new_v4(), "Robert Jordan", "The Eye of the World");
+        let world = Book::new(
+            Uuid::new_v4(),
+            "Gregory Maguire",
+            "Wicked: The Life and Times",
+        );
+        let bell = Book::new(
+            Uuid::new_v4(),
+            "Ernest Hemmingway",
+            "For Whom the Bell Tolls",
+        );
+        for book in &[&eye, &world, &bell] {
+            let _ = dao.put(book, "test").unwrap();
+        }
+
+        let found = dao.search("eye world", 10).unwrap();
+        assert_eq!(vec![eye], found);
+
+        let retitled = Book::new(world.book_id, "Gregory Maguire", "Out of Oz: The World");
+        assert!(dao.update(&retitled, None, "test").unwrap().is_some());
+        assert!(dao.search("wicked", 10).unwrap().is_empty());
+
+        let found = dao.search("the world", 10).unwrap();
+        assert_eq!(2, found.len());
+
+        assert!(dao.delete(&retitled.book_id, None, "test").unwrap());
+        assert_eq!(1, dao.search("world", 10).unwrap().len());
+        assert!(dao.search("the", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_update() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the World");
+
+        let dao = BookDao::new(&settings).unwrap();
+
+        assert!(dao.update(&book, None, "test").unwrap().is_none());
+        assert!(dao.get(&book_id).is_err());
+
+        let _ = dao.put(&book, "test").unwrap();
+        let updated = Book::new(book_id, "Robert Jordan", "The Great Hunt");
+        assert!(dao.update(&updated, None, "test").unwrap().is_some());
+        assert_eq!(updated, dao.get(&book_id).unwrap());
+    }
+
+    #[test]
+    fn test_patch() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the Wrold");
+        let patch: Value = serde_json::from_str("{\"title\": \"Eye of the World\"}").unwrap();
+
+        let dao = BookDao::new(&settings).unwrap();
+
+        assert!(dao.patch(&book_id, &patch, None, "test").is_err());
+
+        let _ = dao.put(&book, "test").unwrap();
+        let merged = dao.patch(&book_id, &patch, None, "test").unwrap();
+        assert_eq!("Eye of the World", merged.book.title);
+        assert_eq!(merged.book, dao.get(&book_id).unwrap());
+    }
+
+    #[test]
+    fn test_versions() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the Wrold");
+        let patch: Value = serde_json::from_str("{\"title\": \"Eye of the World\"}").unwrap();
+
+        let dao = BookDao::new(&settings).unwrap();
+
+        assert_eq!(1, dao.put(&book, "test").unwrap());
+        assert_eq!(Some(2), dao.update(&book, Some(1), "test").unwrap());
+        match dao.update(&book, Some(1), "test") {
+            Err(BookServiceError::PreconditionFailedError(2)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        assert_eq!(
+            3,
+            dao.patch(&book_id, &patch, Some(2), "test")
+                .unwrap()
+                .version
+        );
+        match dao.patch(&book_id, &patch, Some(2), "test") {
+            Err(BookServiceError::PreconditionFailedError(3)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        match dao.delete(&book_id, Some(2), "test") {
+            Err(BookServiceError::PreconditionFailedError(3)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(3, dao.get_versioned(&book_id).unwrap().version);
+        assert!(dao.delete(&book_id, Some(3), "test").unwrap());
+    }
+
+    #[test]
+    fn test_put_delete() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+
+        let book_id = Uuid::new_v4();
+        let book = Book::default()
+            .with_book_id(&book_id)
+            .with_author("Robert")
+            .with_title("Jordan");
+
+        let dao = BookDao::new(&settings).unwrap();
+
+        let _ = dao.put(&book, "test").unwrap();
+
+        assert!(dao.delete(&book_id, None, "test").unwrap());
+        assert!(!dao.delete(&book_id, None, "test").unwrap());
+        assert!(dao.get(&book_id).is_err());
+    }
+
+    #[test]
+    fn test_history() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the Wrold");
+        let fixed = Book::new(book_id, "Robert Jordan", "Eye of the World");
+
+        let dao = BookDao::new(&settings).unwrap();
+
+        let _ = dao.put(&book, "import").unwrap();
+        let _ = dao.update(&fixed, None, "editor").unwrap();
+        assert!(dao.delete(&book_id, None, "editor").unwrap());
+
+        let history = dao.history(&book_id).unwrap();
+        let versions: Vec<u64> = history.iter().map(|revision| revision.version).collect();
+        assert_eq!(vec![1, 2, 3], versions);
+        assert_eq!(Operation::Delete, history[2].operation);
+        assert_eq!("Eye of the Wrold", history[0].book.title);
+        assert_eq!("editor", dao.revision(&book_id, 2).unwrap().source);
+
+        assert_eq!(1, dao.purge(unix_time()).unwrap());
+        assert!(dao.history(&book_id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_put_many() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+
+        let dao = BookDao::new(&settings).unwrap();
+
+        let mut books: Vec<Book> = (0..150)
+            .map(|i| Book::new(Uuid::new_v4(), "Robert Jordan", &format!("Volume {}", i)))
+            .collect();
+        // rewritten within the same batch
+        let repeated = books[0].book_id;
+        books.insert(
+            1,
+            Book::new(repeated, "Brandon Sanderson", "A Memory of Light"),
+        );
+
+        let versions = dao.put_many(&books, "test").unwrap();
+        assert_eq!(books.len(), versions.len());
+        assert_eq!(2, versions[1]);
+        assert_eq!(149, dao.find_by_author("Robert Jordan").unwrap().len());
+        assert_eq!(
+            vec![Book::new(
+                repeated,
+                "Brandon Sanderson",
+                "A Memory of Light"
+            )],
+            dao.find_by_author("Brandon Sanderson").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_trash() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the World");
+
+        let dao = BookDao::new(&settings).unwrap();
+
+        let _ = dao.put(&book, "test").unwrap();
+        assert!(dao.delete(&book_id, None, "test").unwrap());
+        assert!(dao.list(None, 10).unwrap().books.is_empty());
+        assert!(dao.find_by_author("Robert Jordan").unwrap().is_empty());
+        assert!(dao.search("world", 10).unwrap().is_empty());
+
+        let trash = dao.list_trash().unwrap();
+        assert_eq!(1, trash.len());
+        assert_eq!(book, trash[0].book);
+
+        let restored = dao.restore(&book_id, "test").unwrap().unwrap();
+        assert_eq!(book, restored.book);
+        assert_eq!(book, dao.get(&book_id).unwrap());
+        assert_eq!(1, dao.search("world", 10).unwrap().len());
+        assert!(dao.restore(&book_id, "test").unwrap().is_none());
+
+        assert!(dao.delete(&book_id, None, "test").unwrap());
+        assert_eq!(0, dao.purge(trash[0].deleted_at - 1).unwrap());
+        assert_eq!(1, dao.purge(unix_time()).unwrap());
+        assert!(dao.list_trash().unwrap().is_empty());
+        assert!(dao.restore(&book_id, "test").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_purge_restored() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the World");
+
+        let dao = BookDao::new(&settings).unwrap();
+
+        let _ = dao.put(&book, "test").unwrap();
+        assert!(dao.delete(&book_id, None, "test").unwrap());
+        let _ = dao.restore(&book_id, "test").unwrap().unwrap();
+
+        // a trash entry left behind by a restore must not take the book with it
+        let conn = dao.redis_pool.get(&dao.namespace.trash_key()).unwrap();
+        let _: () = conn
+            .zadd(dao.namespace.trash_key(), book_id.to_string(), 0)
+            .unwrap();
+        assert_eq!(0, dao.purge(unix_time()).unwrap());
+        assert!(dao.list_trash().unwrap().is_empty());
+
+        assert_eq!(book, dao.get(&book_id).unwrap());
+        assert_eq!(3, dao.history(&book_id).unwrap().len());
+        assert_eq!(1, dao.find_by_author("Robert Jordan").unwrap().len());
+        assert_eq!(1, dao.search("world", 10).unwrap().len());
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the World");
+
+        let unprefixed = BookDao::new(&settings).unwrap();
+        let staging = BookDao::new(&settings.clone().with_redis_namespace("staging")).unwrap();
+        let qa = BookDao::new(&settings.with_redis_namespace("qa")).unwrap();
+
+        let _ = unprefixed.put(&book, "test").unwrap();
+        let _ = staging.put(&book, "test").unwrap();
+        assert!(staging.delete(&book_id, None, "test").unwrap());
+        assert!(qa.get(&book_id).is_err());
+        assert!(qa.list(None, 10).unwrap().books.is_empty());
+        assert_eq!(book, unprefixed.get(&book_id).unwrap());
+
+        // the trashed book, its history and the trash move, its emptied index sets are gone
+        assert_eq!(3, staging.rename_namespace(&Namespace::new("qa")).unwrap());
+        assert_eq!(1, qa.list_trash().unwrap().len());
+        assert_eq!(2, qa.history(&book_id).unwrap().len());
+        assert!(staging.list_trash().unwrap().is_empty());
+        assert_eq!(1, unprefixed.history(&book_id).unwrap().len());
+    }
+
+    #[test]
+    fn test_replicas() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        // the master stands in for a healthy replica, nothing listens on port 1
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port)
+            .with_redis_replicas(&format!("localhost:{}, localhost:1", host_port));
+        let dao = BookDao::new(&settings).unwrap();
+
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the World");
+        let _ = dao.put(&book, "test").unwrap();
+        for _ in 0..4 {
+            assert_eq!(book, dao.get(&book_id).unwrap());
+            assert_eq!(1, dao.find_by_author("Robert Jordan").unwrap().len());
+        }
+
+        assert!(BookDao::new(&settings.with_redis_replicas("localhost")).is_err());
+    }
+
+    #[test]
+    fn test_lagging_replica() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+        let lagging = docker.run(images::redis::Redis::default());
+        let lagging_port = lagging.get_host_port(6379).unwrap();
+
+        // a replica that has not seen any write yet
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port)
+            .with_redis_replicas(&format!("localhost:{}", lagging_port));
+        let dao = BookDao::new(&settings).unwrap();
+
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the World");
+        let _ = dao.put(&book, "test").unwrap();
+
+        assert_eq!(book, dao.get(&book_id).unwrap());
+        assert_eq!(1, dao.history(&book_id).unwrap().len());
+        assert_eq!(1, dao.find_by_author("Robert Jordan").unwrap().len());
+        assert_eq!(1, dao.search("world", 10).unwrap().len());
+
+        // the replica holding the first version of a book updated since
+        let stale = BookDao::new(
+            &Settings::default()
+                .with_redis_host("localhost")
+                .with_redis_port(lagging_port),
+        )
+        .unwrap();
+        let _ = stale.put(&book, "test").unwrap();
+        assert_eq!(Some(2), dao.update(&book, Some(1), "test").unwrap());
+        assert_eq!(2, dao.get_versioned(&book_id).unwrap().version);
+
+        assert!(dao.delete(&book_id, Some(2), "test").unwrap());
+        assert_eq!(1, dao.list_trash().unwrap().len());
+    }
+
+    /// A redis-server process, killed once dropped
+    struct RedisProcess(Child);
+
+    impl Drop for RedisProcess {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn redis_server(args: &[&str]) -> RedisProcess {
+        Command::new("redis-server")
+            .args(args)
+            .stdout(Stdio::null())
+            .spawn()
+            .map(RedisProcess)
+            .expect("redis-server is not on the PATH")
+    }
+
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .map(|address| address.port())
+            .unwrap()
+    }
+
+    fn wait_until<F: Fn() -> bool>(condition: F) {
+        let started = Instant::now();
+        while !condition() {
+            assert!(
+                started.elapsed() < Duration::from_secs(60),
+                "gave up waiting"
+            );
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+
+    /// Fails a master with one replica over through a Sentinel, all run as local
+    /// processes: cargo test -- --ignored with redis-server on the PATH
+    #[test]
+    #[ignore]
+    fn test_sentinel_failover() {
+        let master_port = free_port().to_string();
+        let replica_port = free_port().to_string();
+        let sentinel_port = free_port();
+        let _master = redis_server(&["--port", &master_port, "--save", ""]);
+        let _replica = redis_server(&[
+            "--port",
+            &replica_port,
+            "--save",
+            "",
+            "--replicaof",
+            "127.0.0.1",
+            &master_port,
+        ]);
+        let config = env::temp_dir().join(format!("bookstore-sentinel-{}.conf", sentinel_port));
+        fs::write(
+            &config,
+            format!(
+                "port {}\n\
+                 sentinel monitor mymaster 127.0.0.1 {} 1\n\
+                 sentinel down-after-milliseconds mymaster 1000\n\
+                 sentinel failover-timeout mymaster 5000\n",
+                sentinel_port, master_port
+            ),
+        )
+        .unwrap();
+        let _sentinel = redis_server(&[config.to_str().unwrap(), "--sentinel"]);
+
+        let settings =
+            Settings::default().with_redis_sentinels(&format!("127.0.0.1:{}", sentinel_port));
+        let sentinel = Sentinel::from_settings(&settings).unwrap();
+        wait_until(|| sentinel.master_address().is_ok());
+        let dao = BookDao::new(&settings).unwrap();
+
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the World");
+        let _ = dao.put(&book, "test").unwrap();
+        let replica = redis::Client::open(format!("redis://127.0.0.1:{}", replica_port).as_ref())
+            .and_then(|client| client.get_connection())
+            .unwrap();
+        let key = dao.namespace.book_key(&book_id);
+        wait_until(|| replica.exists(key.to_owned()).unwrap_or(false));
+
+        let sentinel_conn =
+            redis::Client::open(format!("redis://127.0.0.1:{}", sentinel_port).as_ref())
+                .and_then(|client| client.get_connection())
+                .unwrap();
+        redis::cmd("SENTINEL")
+            .arg("failover")
+            .arg("mymaster")
+            .query::<()>(&sentinel_conn)
+            .unwrap();
+        wait_until(|| {
+            dao.redis_pool
+                .url()
+                .map(|url| url.ends_with(&format!(":{}", replica_port)))
+                .unwrap_or(false)
+        });
+
+        // the promoted replica takes writes, the demoted master would refuse them
+        assert_eq!(book, dao.get(&book_id).unwrap());
+        assert_eq!(Some(2), dao.update(&book, Some(1), "test").unwrap());
+        let _ = fs::remove_file(&config);
+    }
+
+    #[test]
+    fn test_migrate_schema() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+        let dao = BookDao::new(&settings).unwrap();
+
+        // books as they were stored before versioning was introduced
+        let write_unversioned = |book: &Book| {
+            let key = dao.namespace.book_key(&book.book_id);
+            let conn = dao.redis_pool.get(&key).unwrap();
+            conn.hset_multiple::<_, _, _, ()>(key, &[(AUTHOR, &book.author), (TITLE, &book.title)])
+                .unwrap();
+        };
+        let read_schema = |book: &Book| {
+            let key = dao.namespace.book_key(&book.book_id);
+            let conn = dao.redis_pool.get(&key).unwrap();
+            conn.hget::<_, _, Option<u32>>(key, SCHEMA).unwrap()
+        };
+
+        let read = Book::new(Uuid::new_v4(), "Robert Jordan", "Eye of the World");
+        write_unversioned(&read);
+        assert_eq!(None, read_schema(&read));
+        let versioned = dao.get_versioned(&read.book_id).unwrap();
+        assert_eq!(read, versioned.book);
+        assert_eq!(0, versioned.version);
+        assert_eq!(Some(SCHEMA_VERSION), read_schema(&read));
+
+        let unread = Book::new(Uuid::new_v4(), "Robert Jordan", "The Great Hunt");
+        write_unversioned(&unread);
+        assert_eq!(1, dao.migrate_schema().unwrap());
+        assert_eq!(Some(SCHEMA_VERSION), read_schema(&unread));
+        assert_eq!(0, dao.migrate_schema().unwrap());
+
+        let schema_key = dao.namespace.schema_key();
+        let conn = dao.redis_pool.get(&schema_key).unwrap();
+        assert_eq!(
+            Some(SCHEMA_VERSION),
+            conn.get::<_, Option<u32>>(schema_key).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+        let dao = BookDao::new(&settings).unwrap();
+        let book = Book::new(Uuid::new_v4(), "Robert Jordan", "Eye of the World");
+        let _ = dao.put(&book, "test").unwrap();
+        assert!(dao.check(false).unwrap().is_clean());
+
+        // what half-done writes and stray tools leave behind
+        let half_written = dao.namespace.book_key(&Uuid::new_v4());
+        let wrong_type = dao.namespace.book_key(&Uuid::new_v4());
+        let missing_id = Uuid::new_v4().hyphenated().to_string();
+        let author_key = dao.namespace.author_key("Robert Jordan");
+        let orphaned = dao.namespace.history_key(&missing_id);
+        let conn = dao.redis_pool.get(&half_written).unwrap();
+        redis::pipe()
+            .hset(half_written.to_owned(), TITLE, "The Great Hunt")
+            .set(wrong_type.to_owned(), "Eye of the World")
+            .sadd(author_key.to_owned(), missing_id.to_owned())
+            .zadd(
+                dao.namespace.trash_key(),
+                book.book_id.hyphenated().to_string(),
+                1,
+            )
+            .rpush(orphaned.to_owned(), "{}")
+            .query::<()>(conn.deref())
+            .unwrap();
+        // as books written before the author index existed are
+        let book_id = book.book_id.hyphenated().to_string();
+        let _: () = conn
+            .srem(author_key.to_owned(), book_id.to_owned())
+            .unwrap();
+
+        let report = dao.check(false).unwrap();
+        assert_eq!(2, report.books);
+        assert_eq!(6, report.problems.len());
+        assert!(report.problems.contains(&Problem::WrongType {
+            key: wrong_type.to_owned(),
+            expected: "hash",
+            found: "string".to_string(),
+        }));
+        assert!(report.problems.contains(&Problem::DanglingEntry {
+            key: author_key.to_owned(),
+            id: missing_id.to_owned(),
+        }));
+        assert!(report.problems.contains(&Problem::Orphaned {
+            key: orphaned.to_owned(),
+        }));
+        assert!(report.problems.contains(&Problem::MissingEntry {
+            key: author_key.to_owned(),
+            id: book_id.to_owned(),
+        }));
+        assert_eq!(0, report.repaired);
+
+        let report = dao.check(true).unwrap();
+        assert_eq!(6, report.repaired);
+        assert!(dao.check(false).unwrap().is_clean());
+        assert_eq!(book, dao.get(&book.book_id).unwrap());
+        assert_eq!(1, dao.find_by_author("Robert Jordan").unwrap().len());
+        let quarantined: bool = conn
+            .exists(dao.namespace.quarantine_key(&half_written))
+            .unwrap();
+        assert!(quarantined);
+    }
+
+    #[test]
+    fn test_events() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port)
+            .with_redis_namespace("qa");
+        let dao = BookDao::new(&settings).unwrap();
+        let mut events = dao.events().unwrap().unwrap().wait();
+        let mut next_event = || {
+            let chunk = events.next().unwrap().unwrap();
+            let frame = str::from_utf8(chunk.as_ref()).unwrap().to_string();
+            assert!(frame.starts_with("data: ") && frame.ends_with("\n\n"));
+            serde_json::from_str::<BookEvent>(&frame["data: ".len()..]).unwrap()
+        };
+
+        let book = Book::new(Uuid::new_v4(), "Robert Jordan", "Eye of the World");
+        let _ = dao.put(&book, "test").unwrap();
+        assert_eq!(
+            BookEvent::new(Operation::Put, &book, 1, 0),
+            BookEvent {
+                timestamp: 0,
+                ..next_event()
+            }
+        );
+        assert!(dao.delete(&book.book_id, None, "test").unwrap());
+        let deleted = next_event();
+        assert_eq!(Operation::Delete, deleted.event_type);
+        assert_eq!(2, deleted.version);
+        assert_eq!(book, deleted.book);
+
+        // other namespaces do not hear of it
+        let tenant = dao.for_tenant("shop-a");
+        let _ = tenant.put(&book, "test").unwrap();
+        let _ = dao.restore(&book.book_id, "test").unwrap();
+        assert_eq!(Operation::Restore, next_event().event_type);
+    }
+
+    #[test]
+    fn test_outbox() {
+        let docker = clients::Cli::default();
+        let node = docker.run(images::redis::Redis::default());
+        let host_port = node.get_host_port(6379).unwrap();
+
+        let settings = Settings::default()
+            .with_redis_host("localhost")
+            .with_redis_port(host_port);
+        let dao = BookDao::new(&settings).unwrap();
+        let book = Book::new(Uuid::new_v4(), "Robert Jordan", "Eye of the Wrold");
+        let _ = dao.put(&book, "test").unwrap();
+        let fixed = Book::new(book.book_id, "Robert Jordan", "Eye of the World");
+        assert_eq!(Some(2), dao.update(&fixed, None, "test").unwrap());
+        assert!(dao.delete(&book.book_id, None, "test").unwrap());
+        // a write that does not happen leaves nothing in the outbox
+        assert_eq!(None, dao.update(&fixed, None, "test").unwrap());
+
+        let outbox = dao.namespace.outbox_key();
+        let conn = dao.redis_pool.get(&outbox).unwrap();
+        let entries: Vec<(String, HashMap<String, String>)> = redis::cmd("XRANGE")
+            .arg(outbox)
+            .arg("-")
+            .arg("+")
+            .query(conn.deref())
+            .unwrap();
+        let records: Vec<BookEvent> = entries
+            .iter()
+            .map(|(_, fields)| serde_json::from_str(&fields[OUTBOX_FIELD]).unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                (Operation::Put, 1, "Eye of the Wrold"),
+                (Operation::Update, 2, "Eye of the World"),
+                (Operation::Delete, 3, "Eye of the World"),
+            ],
+            records
+                .iter()
+                .map(|record| (
+                    record.event_type,
+                    record.version,
+                    record.book.title.as_str()
+                ))
+                .collect::<Vec<_>>()
+        );
+    }
 }
@@ -2,11 +2,12 @@ use config::ConfigError;
 use hyper::Error as HyperError;
 use r2d2_redis::r2d2::Error as R2D2RedisError;
 use redis::RedisError;
+use rusqlite::Error as SqliteError;
 use serde_json::Error as SerdeJsonError;
 use std::convert::From;
 use std::error::Error;
 use std::{fmt, io};
-use uuid::ParseError;
+use uuid::{ParseError, Uuid};
 
 #[derive(Debug)]
 pub enum BookServiceError {
@@ -16,6 +17,12 @@ pub enum BookServiceError {
     BookCreateError(DaoCause),
     /// Wrapper around redis get failure
     BookGetError(DaoCause),
+    /// Wrapper around redis scan failure
+    BookListError(DaoCause),
+    /// Wrapper around redis update failure
+    BookUpdateError(DaoCause),
+    /// Wrapper around redis delete failure
+    BookDeleteError(DaoCause),
     /// Wrapper for serde parsing errors
     BookParseError(SerdeJsonError),
     BookSerializationError(SerdeJsonError),
@@ -23,9 +30,29 @@ pub enum BookServiceError {
     BookBodyError(HyperError),
     DaoInitializationError(DaoCause),
     MissingFieldError(String),
+    /// A query parameter (or a listing cursor) that could not be understood
+    InvalidQueryError(String),
+    /// A merge patch that can not be applied to a book
+    InvalidPatchError(String),
+    /// An If-Match precondition did not hold, carries the current version when known
+    PreconditionFailedError(u64),
+    /// The book_id of a request body disagrees with the id in the path
+    BookIdMismatchError(Uuid),
+    /// An Idempotency-Key sent again with a different request body
+    IdempotencyKeyReusedError(String),
+    /// An Idempotency-Key sent again while the first request is still being answered
+    IdempotencyKeyInFlightError(String),
     SettingsError(ConfigError),
     RedisHostError,
     RedisPortError,
+    /// BOOKSTORE_STORAGE named a backend that does not exist
+    UnknownStorageError(String),
+    /// BOOKSTORE_TENANTS holds a tenant id that can not be used
+    InvalidTenantError(String),
+    /// BOOKSTORE_REDISREPLICAS holds an address that is not host:port
+    InvalidReplicaError(String),
+    /// None of the configured Sentinels could name the current Redis master
+    SentinelError(String),
 }
 
 impl fmt::Display for BookServiceError {
@@ -37,6 +64,15 @@ impl fmt::Display for BookServiceError {
                 write!(f, "Root Cause: {:?}", pie.cause())
             }
             BookServiceError::BookGetError(ref gie) => write!(f, "Root Cause: {:?}", gie.cause()),
+            BookServiceError::BookListError(ref lie) => {
+                write!(f, "Root Cause: {:?}", lie.cause())
+            }
+            BookServiceError::BookUpdateError(ref uie) => {
+                write!(f, "Root Cause: {:?}", uie.cause())
+            }
+            BookServiceError::BookDeleteError(ref die) => {
+                write!(f, "Root Cause: {:?}", die.cause())
+            }
             BookServiceError::BookParseError(ref sje) => write!(f, "Root Cause: {}", sje),
             BookServiceError::BookSerializationError(ref sje) => write!(f, "Root Cause: {}", sje),
             BookServiceError::BookBodyError(ref he) => write!(f, "Root Cause: {}", he),
@@ -46,11 +82,51 @@ impl fmt::Display for BookServiceError {
             BookServiceError::MissingFieldError(ref field) => {
                 write!(f, "Invalid Book, missing field {} ", field)
             }
+            BookServiceError::InvalidQueryError(ref reason) => {
+                write!(f, "Invalid query, {}", reason)
+            }
+            BookServiceError::InvalidPatchError(ref reason) => {
+                write!(f, "Invalid patch, {}", reason)
+            }
+            BookServiceError::PreconditionFailedError(ref version) => {
+                write!(f, "Precondition failed, the book is at version {}", version)
+            }
+            BookServiceError::BookIdMismatchError(ref uuid) => {
+                write!(f, "Invalid Book, book_id does not match {}", uuid)
+            }
+            BookServiceError::IdempotencyKeyReusedError(ref key) => {
+                write!(
+                    f,
+                    "Idempotency-Key {} was used with a different request",
+                    key
+                )
+            }
+            BookServiceError::IdempotencyKeyInFlightError(ref key) => write!(
+                f,
+                "Idempotency-Key {} is still being used by another request",
+                key
+            ),
             BookServiceError::SettingsError(ref e) => {
                 write!(f, "Configuration Issue - Root Cause: {}", e)
             }
             BookServiceError::RedisHostError => write!(f, "Redis host was missing"),
             BookServiceError::RedisPortError => write!(f, "Redis port was missing"),
+            BookServiceError::UnknownStorageError(ref storage) => {
+                write!(f, "Unknown storage backend {}", storage)
+            }
+            BookServiceError::InvalidTenantError(ref tenant) => write!(
+                f,
+                "Invalid tenant {:?}, only letters, digits, - and _ are allowed",
+                tenant
+            ),
+            BookServiceError::InvalidReplicaError(ref address) => {
+                write!(f, "Invalid replica address {}, expected host:port", address)
+            }
+            BookServiceError::SentinelError(ref master_name) => write!(
+                f,
+                "No Sentinel knows the address of the master {}",
+                master_name
+            ),
         }
     }
 }
@@ -61,6 +137,9 @@ impl Error for BookServiceError {
             BookServiceError::InvalidUuidError(cause) => Some(cause),
             BookServiceError::BookCreateError(cause) => cause.cause(),
             BookServiceError::BookGetError(cause) => cause.cause(),
+            BookServiceError::BookListError(cause) => cause.cause(),
+            BookServiceError::BookUpdateError(cause) => cause.cause(),
+            BookServiceError::BookDeleteError(cause) => cause.cause(),
             BookServiceError::BookParseError(cause) => Some(cause),
             BookServiceError::BookSerializationError(cause) => Some(cause),
             BookServiceError::BookBodyError(cause) => Some(cause),
@@ -97,24 +176,35 @@ impl From<BookServiceError> for io::Error {
 
 impl From<R2D2RedisError> for BookServiceError {
     fn from(err: R2D2RedisError) -> Self {
-        BookServiceError::DaoInitializationError(DaoCause(None, Some(err)))
+        BookServiceError::DaoInitializationError(DaoCause::from(err))
     }
 }
 
 impl From<RedisError> for BookServiceError {
     fn from(err: RedisError) -> Self {
-        BookServiceError::DaoInitializationError(DaoCause(Some(err), None))
+        BookServiceError::DaoInitializationError(DaoCause::from(err))
+    }
+}
+
+impl From<SqliteError> for BookServiceError {
+    fn from(err: SqliteError) -> Self {
+        BookServiceError::DaoInitializationError(DaoCause::from(err))
     }
 }
 
 #[derive(Debug)]
-pub struct DaoCause(Option<RedisError>, Option<R2D2RedisError>);
+pub struct DaoCause(
+    Option<RedisError>,
+    Option<R2D2RedisError>,
+    Option<SqliteError>,
+);
 
 impl DaoCause {
     pub fn cause(&self) -> Option<&(dyn Error + 'static)> {
         match self {
-            DaoCause(Some(ref e), _) => Some(e),
-            DaoCause(_, Some(e)) => Some(e),
+            DaoCause(Some(ref e), _, _) => Some(e),
+            DaoCause(_, Some(e), _) => Some(e),
+            DaoCause(_, _, Some(e)) => Some(e),
             _ => None,
         }
     }
@@ -124,14 +214,29 @@ impl DaoCause {
     }
 }
 
+impl fmt::Display for DaoCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.cause() {
+            Some(cause) => write!(f, "{}", cause),
+            None => write!(f, "unknown cause"),
+        }
+    }
+}
+
 impl From<RedisError> for DaoCause {
     fn from(e: RedisError) -> Self {
-        DaoCause(Some(e), None)
+        DaoCause(Some(e), None, None)
     }
 }
 
 impl From<R2D2RedisError> for DaoCause {
     fn from(e: R2D2RedisError) -> Self {
-        DaoCause(None, Some(e))
+        DaoCause(None, Some(e), None)
+    }
+}
+
+impl From<SqliteError> for DaoCause {
+    fn from(e: SqliteError) -> Self {
+        DaoCause(None, None, Some(e))
     }
 }
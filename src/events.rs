@@ -0,0 +1,138 @@
+use futures::sync::mpsc;
+use futures::Stream;
+use hyper::Chunk;
+use redis::{self, RedisResult};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long a quiet subscription waits before sending a keep-alive comment, which is
+/// also how a subscriber that went away is noticed
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// How many events are held for a subscriber reading slower than they are published
+const SUBSCRIBER_BUFFER: usize = 64;
+const KEEP_ALIVE: &'static str = ": keep-alive\n\n";
+
+/// The events of a catalog as Server-Sent Events, ready to be written out
+pub type EventStream = Box<dyn Stream<Item = Chunk, Error = io::Error> + Send>;
+
+/// The subscribers of a channel, None while no relay is running for it
+type Subscribers = Arc<Mutex<Option<Vec<mpsc::Sender<String>>>>>;
+
+/// The relays of a process, one Redis subscription and one thread per channel however
+/// many clients follow it
+#[derive(Debug, Default)]
+pub struct Relays {
+    channels: Mutex<HashMap<(String, String), Subscribers>>,
+}
+
+impl Relays {
+    /// Follows the channel, relaying every event published on it as a Server-Sent Event.
+    /// The first subscriber starts the relay, which stops once the last one goes away.
+    /// Every stream ends when the subscription is lost, EventSource clients then
+    /// reconnect on their own, and a subscriber falling too far behind is dropped.
+    pub fn subscribe(&self, url: &str, channel: &str) -> RedisResult<EventStream> {
+        let subscribers = self
+            .channels
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry((url.to_string(), channel.to_string()))
+            .or_insert_with(Default::default)
+            .clone();
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+        let mut relayed = subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        match *relayed {
+            Some(ref mut senders) => senders.push(sender),
+            None => {
+                let client = redis::Client::open(url)?;
+                let mut pubsub = client.get_pubsub()?;
+                pubsub.set_read_timeout(Some(KEEP_ALIVE_INTERVAL))?;
+                pubsub.subscribe(channel)?;
+                let relay_subscribers = subscribers.clone();
+                let channel = channel.to_string();
+                thread::Builder::new()
+                    .name("event-relay".to_string())
+                    .spawn(move || relay(pubsub, relay_subscribers, &channel))?;
+                *relayed = Some(vec![sender]);
+            }
+        }
+        Ok(Box::new(receiver.map(Chunk::from).map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "event relay failed")
+        })))
+    }
+}
+
+fn relay(mut pubsub: redis::PubSub, subscribers: Subscribers, channel: &str) {
+    println!("Relaying events of {}", channel);
+    loop {
+        let frame = match pubsub.get_message() {
+            Ok(message) => match message.get_payload::<String>() {
+                Ok(payload) => sse_frame(&payload),
+                Err(e) => {
+                    eprintln!("Skipping an unreadable event on {} {}", channel, e);
+                    continue;
+                }
+            },
+            Err(ref e) if e.is_timeout() => KEEP_ALIVE.to_string(),
+            Err(e) => {
+                eprintln!("Lost the subscription to {} {}", channel, e);
+                // ends the stream of every subscriber
+                subscribers.lock().unwrap_or_else(|e| e.into_inner()).take();
+                break;
+            }
+        };
+        let mut relayed = subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        let remaining = broadcast(relayed.take().unwrap_or_default(), &frame);
+        if remaining.is_empty() {
+            // the next subscriber starts a relay of its own
+            break;
+        }
+        *relayed = Some(remaining);
+    }
+    println!("Stopped relaying events of {}", channel);
+}
+
+/// Hands the frame to every subscriber, keeping those still there with room for it
+fn broadcast(subscribers: Vec<mpsc::Sender<String>>, frame: &str) -> Vec<mpsc::Sender<String>> {
+    subscribers
+        .into_iter()
+        .filter_map(|mut sender| sender.try_send(frame.to_string()).ok().map(|_| sender))
+        .collect()
+}
+
+/// The payload as the data of a Server-Sent Event, one data field per line
+fn sse_frame(payload: &str) -> String {
+    let mut frame = String::with_capacity(payload.len() + 8);
+    for line in payload.lines() {
+        frame.push_str("data: ");
+        frame.push_str(line);
+        frame.push('\n');
+    }
+    frame.push('\n');
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sse_frame() {
+        assert_eq!(
+            "data: {\"type\":\"put\"}\n\n",
+            sse_frame("{\"type\":\"put\"}")
+        );
+        assert_eq!("data: first\ndata: second\n\n", sse_frame("first\nsecond"));
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let (gone, _) = mpsc::channel(SUBSCRIBER_BUFFER);
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+        let remaining = broadcast(vec![gone, sender], KEEP_ALIVE);
+        assert_eq!(1, remaining.len());
+        assert_eq!(Some(Ok(KEEP_ALIVE.to_string())), receiver.wait().next());
+    }
+}
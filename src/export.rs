@@ -0,0 +1,168 @@
+use futures::{future, stream, Stream};
+use hyper::Chunk;
+use std::io;
+use std::sync::Arc;
+
+use crate::errors::BookServiceError;
+use crate::model::Book;
+use crate::store::BookStore;
+
+/// How many books are read from the store, and written out, per chunk
+const EXPORT_PAGE_SIZE: usize = 500;
+const CSV_HEADER: &'static str = "book_id,author,title\r\n";
+
+/// The formats GET /book/_export can write
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Ndjson,
+    Csv,
+}
+
+impl ExportFormat {
+    /// Looks up a format by the name given as `format=`
+    pub fn from_name(name: &str) -> Option<ExportFormat> {
+        match name.to_lowercase().as_str() {
+            "ndjson" | "jsonl" => Some(ExportFormat::Ndjson),
+            "csv" => Some(ExportFormat::Csv),
+            _ => None,
+        }
+    }
+
+    /// Picks the format from an Accept header, NDJSON unless CSV is asked for
+    pub fn from_accept(accept: &str) -> ExportFormat {
+        if accept.to_lowercase().contains("text/csv") {
+            ExportFormat::Csv
+        } else {
+            ExportFormat::Ndjson
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    fn write_book(&self, out: &mut Vec<u8>, book: &Book) -> Result<(), BookServiceError> {
+        match self {
+            ExportFormat::Ndjson => {
+                out.extend(book.to_vec()?);
+                out.push(b'\n');
+            }
+            ExportFormat::Csv => {
+                let fields = [
+                    book.book_id.hyphenated().to_string(),
+                    csv_field(&book.author),
+                    csv_field(&book.title),
+                ];
+                out.extend(fields.join(",").as_bytes());
+                out.extend(b"\r\n");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Streams the whole catalog a page at a time, only one page is held in memory.
+/// The listing cursors are the ones handed out to clients, so the export sees books
+/// written while it runs the same way a client paging through GET /book/ would.
+/// A store failure part way ends the stream with an error, cutting the response short.
+pub fn export_stream(
+    store: Arc<dyn BookStore>,
+    format: ExportFormat,
+) -> impl Stream<Item = Chunk, Error = io::Error> + Send {
+    // None once the last page has been written, otherwise the cursor of the next page
+    let start: Option<Option<String>> = Some(None);
+    stream::unfold(start, move |position| {
+        position.map(|cursor| {
+            let mut chunk = Vec::new();
+            if cursor.is_none() && format == ExportFormat::Csv {
+                chunk.extend(CSV_HEADER.as_bytes());
+            }
+            let page = store
+                .list(cursor.as_ref().map(String::as_str), EXPORT_PAGE_SIZE)
+                .and_then(|page| {
+                    for book in &page.books {
+                        format.write_book(&mut chunk, book)?;
+                    }
+                    Ok((Chunk::from(chunk), page.next.map(Some)))
+                })
+                .map_err(|e| {
+                    eprintln!("Export failed {}", &e);
+                    io::Error::new(io::ErrorKind::Other, e.to_string())
+                });
+            future::result(page)
+        })
+    })
+}
+
+/// Quotes a CSV field when it holds a separator, quote or line break (RFC 4180)
+fn csv_field(value: &str) -> String {
+    if value.contains(|c: char| c == ',' || c == '"' || c == '\r' || c == '\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use futures::Future;
+    use uuid::Uuid;
+
+    fn export(store: MemoryStore, format: ExportFormat) -> String {
+        let chunk = export_stream(Arc::new(store), format)
+            .concat2()
+            .wait()
+            .unwrap();
+        String::from_utf8(chunk.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!("Robert Jordan", csv_field("Robert Jordan"));
+        assert_eq!("\"Jordan, Robert\"", csv_field("Jordan, Robert"));
+        assert_eq!(
+            "\"The \"\"Great\"\" Hunt\"",
+            csv_field("The \"Great\" Hunt")
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        let store = MemoryStore::new();
+        for i in 0..(EXPORT_PAGE_SIZE + 1) {
+            let book = Book::new(Uuid::new_v4(), "Jordan, Robert", &format!("Volume {}", i));
+            let _ = store.put(&book, "test").unwrap();
+        }
+
+        let csv = export(store, ExportFormat::Csv);
+        let lines: Vec<&str> = csv.split_terminator("\r\n").collect();
+        assert_eq!(EXPORT_PAGE_SIZE + 2, lines.len());
+        assert_eq!(CSV_HEADER.trim_end(), lines[0]);
+        assert!(lines[1].contains(",\"Jordan, Robert\",Volume "));
+
+        let store = MemoryStore::new();
+        let book = Book::new(Uuid::new_v4(), "Robert Jordan", "Eye of the World");
+        let _ = store.put(&book, "test").unwrap();
+        let ndjson = export(store, ExportFormat::Ndjson);
+        assert_eq!(
+            book,
+            Book::from_slice(ndjson.trim_end().as_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_format_selection() {
+        assert_eq!(Some(ExportFormat::Csv), ExportFormat::from_name("CSV"));
+        assert_eq!(None, ExportFormat::from_name("xml"));
+        assert_eq!(
+            ExportFormat::Csv,
+            ExportFormat::from_accept("text/csv, */*;q=0.1")
+        );
+        assert_eq!(ExportFormat::Ndjson, ExportFormat::from_accept("*/*"));
+    }
+}
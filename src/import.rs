@@ -0,0 +1,151 @@
+use serde_json::Value;
+
+use crate::errors::BookServiceError;
+use crate::model::Book;
+use crate::store::BookStore;
+
+/// What became of one item of a bulk import
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportStatus {
+    Created,
+    Invalid,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ImportItem {
+    /// Position of the item in the request, counting from 0
+    pub index: usize,
+    pub status: ImportStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub book_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    /// Why an invalid item was rejected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// The per item results of POST /book/_bulk
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ImportReport {
+    pub created: usize,
+    pub invalid: usize,
+    pub items: Vec<ImportItem>,
+}
+
+/// Splits a bulk body into its books. A body starting with `[` is a JSON array, anything
+/// else is read as NDJSON with one book per non blank line. Items that are not valid
+/// books are returned as the reason they were rejected.
+pub fn parse_items(body: &[u8]) -> Result<Vec<Result<Book, String>>, BookServiceError> {
+    let is_array = body
+        .iter()
+        .find(|byte| !byte.is_ascii_whitespace())
+        .map(|byte| *byte == b'[')
+        .unwrap_or(false);
+    if is_array {
+        serde_json::from_slice::<Vec<Value>>(body)
+            .map_err(BookServiceError::BookParseError)
+            .map(|values| {
+                values
+                    .into_iter()
+                    .map(|value| serde_json::from_value(value).map_err(|e| e.to_string()))
+                    .collect()
+            })
+    } else {
+        Ok(body
+            .split(|byte| *byte == b'\n')
+            .filter(|line| line.iter().any(|byte| !byte.is_ascii_whitespace()))
+            .map(|line| Book::from_slice(line).map_err(|e| e.to_string()))
+            .collect())
+    }
+}
+
+/// Writes the valid books of a bulk body with one put_many and reports on every item.
+/// A storage failure fails the whole import, though books written by earlier batches
+/// stay written.
+pub fn import(
+    store: &dyn BookStore,
+    body: &[u8],
+    source: &str,
+) -> Result<ImportReport, BookServiceError> {
+    let items = parse_items(body)?;
+    let books: Vec<Book> = items
+        .iter()
+        .filter_map(|item| item.as_ref().ok().cloned())
+        .collect();
+    println!("Importing {} of {} books", books.len(), items.len());
+    let mut versions = store.put_many(&books, source)?.into_iter();
+
+    let mut report = ImportReport {
+        created: 0,
+        invalid: 0,
+        items: Vec::with_capacity(items.len()),
+    };
+    for (index, item) in items.into_iter().enumerate() {
+        let result = match item {
+            Ok(book) => {
+                report.created += 1;
+                ImportItem {
+                    index,
+                    status: ImportStatus::Created,
+                    book_id: Some(book.book_id.hyphenated().to_string()),
+                    version: versions.next(),
+                    reason: None,
+                }
+            }
+            Err(reason) => {
+                report.invalid += 1;
+                ImportItem {
+                    index,
+                    status: ImportStatus::Invalid,
+                    book_id: None,
+                    version: None,
+                    reason: Some(reason),
+                }
+            }
+        };
+        report.items.push(result);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+
+    const EYE: &'static str = "{\"book_id\":\"0bcd291d-b7c5-4390-965f-8a70707d22a5\",\"author\":\"Robert Jordan\",\"title\":\"Eye of the World\"}";
+    const HUNT: &'static str = "{\"book_id\":\"87b17841-c677-4451-8bb9-64355b59c585\",\"author\":\"Robert Jordan\",\"title\":\"The Great Hunt\"}";
+
+    #[test]
+    fn test_parse_items() {
+        let array = format!(" [{}, {{\"author\": \"Nobody\"}}, {}]", EYE, HUNT);
+        let items = parse_items(array.as_bytes()).unwrap();
+        assert_eq!(3, items.len());
+        assert!(items[0].is_ok());
+        assert!(items[1].is_err());
+        assert!(items[2].is_ok());
+
+        let ndjson = format!("{}\n\nnot json\n{}\n", EYE, HUNT);
+        let items = parse_items(ndjson.as_bytes()).unwrap();
+        assert_eq!(3, items.len());
+        assert!(items[1].is_err());
+
+        assert!(parse_items(b"[{\"author\"").is_err());
+    }
+
+    #[test]
+    fn test_import() {
+        let store = MemoryStore::new();
+        let ndjson = format!("{}\n{{}}\n{}", EYE, HUNT);
+
+        let report = import(&store, ndjson.as_bytes(), "test").unwrap();
+        assert_eq!(2, report.created);
+        assert_eq!(1, report.invalid);
+        assert_eq!(ImportStatus::Invalid, report.items[1].status);
+        assert!(report.items[1].reason.is_some());
+        assert_eq!(Some(1), report.items[2].version);
+        assert_eq!(2, store.find_by_author("Robert Jordan").unwrap().len());
+    }
+}
@@ -6,32 +6,62 @@ use hyper;
 use env_logger;
 use redis;
 use serde_json;
+mod cache;
+mod check;
+mod cluster;
 mod dao;
 mod errors;
+mod events;
+mod export;
+mod import;
+mod memory;
 mod model;
+mod namespace;
 mod network;
 mod request;
+mod schema;
+mod search;
+mod sentinel;
 mod service;
 mod settings;
+mod sqlite;
+mod store;
+mod tenant;
+mod trash;
 
+use crate::dao::BookDao;
+use crate::namespace::Namespace;
 use crate::network::NetworkInfo;
 use crate::service::BookService;
 use crate::settings::Settings;
 use futures::Future;
 use hyper::server::Server;
 use hyper::service::service_fn;
+use std::env;
 
 fn main() {
     let log_result = env_logger::init();
     debug!("Log initialization: {:?}", &log_result);
 
-    match Settings::new() {
-        Ok(settings) => {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match (Settings::new(), args.as_slice()) {
+        (Ok(settings), [command, from, to]) if command == "rename-namespace" => {
+            rename_namespace(&settings, from, to)
+        }
+        (Ok(settings), [command]) if command == "migrate-schema" => migrate_schema(&settings),
+        (Ok(settings), [command]) if command == "check" => check(&settings, false),
+        (Ok(settings), [command, flag]) if command == "check" && flag == "--repair" => {
+            check(&settings, true)
+        }
+        (Ok(settings), []) => {
             let network_info = NetworkInfo::new();
             let socket_info = network_info.build_server_socket_info(&settings);
 
             match BookService::new(&settings) {
                 Ok(book_service) => {
+                    if let Err(e) = trash::spawn_purger(book_service.stores(), &settings) {
+                        eprintln!("Could not start the trash purger: {}", e);
+                    }
                     // Cloning to avoid reconstruction every time, clone is cheap
                     let cloned_service = book_service.clone();
                     let server = Server::bind(&socket_info.socket_addr)
@@ -48,7 +78,90 @@ fn main() {
                 Err(e) => eprintln!("Could not construct BookService: {}", e),
             }
         }
-        Err(e) => eprintln!("Could not load settings {}", e),
+        (Ok(_), _) => eprintln!(
+            "Usage: bookstore [rename-namespace <from> <to> | migrate-schema | check [--repair]]"
+        ),
+        (Err(e), _) => eprintln!("Could not load settings {}", e),
     }
     println!("BookStore service exiting")
 }
+
+/// Moves every Redis key from one namespace to another, the catalogs of the tenants
+/// within it included, use an empty name for the unprefixed keys written before
+/// namespaces were configurable
+fn rename_namespace(settings: &Settings, from: &str, to: &str) {
+    let from_settings = settings.clone().with_redis_namespace(from);
+    let to_namespace = Namespace::new(to);
+    let renamed = BookDao::new(&from_settings).and_then(|dao| {
+        let mut renamed = dao.rename_namespace(&to_namespace)?;
+        for tenant in settings.tenants()? {
+            renamed += dao.for_tenant(&tenant).rename_namespace(&to_namespace.child(&tenant))?;
+        }
+        Ok(renamed)
+    });
+    match renamed {
+        Ok(renamed) => println!("Renamed {} keys from namespace {:?} to {:?}", renamed, from, to),
+        Err(e) => eprintln!("Could not rename namespace {:?} to {:?}: {}", from, to, e),
+    }
+}
+
+/// Upgrades every book in Redis to the current schema, those of each tenant included,
+/// rather than waiting for them to be read
+fn migrate_schema(settings: &Settings) {
+    let migrated = BookDao::new(settings).and_then(|dao| {
+        let mut daos = vec![dao.clone()];
+        for tenant in settings.tenants()? {
+            daos.push(dao.for_tenant(&tenant));
+        }
+        daos.iter()
+            .map(|dao| dao.migrate_schema())
+            .sum::<Result<usize, _>>()
+    });
+    match migrated {
+        Ok(migrated) => println!("Migrated {} books to the current schema", migrated),
+        Err(e) => eprintln!("Could not migrate the books: {}", e),
+    }
+}
+
+/// Reports the books in Redis the service can not read and whatever half-done writes
+/// left behind, for every tenant too, and with `repair` cleans them up
+fn check(settings: &Settings, repair: bool) {
+    let daos = BookDao::new(settings).and_then(|dao| {
+        let mut daos = vec![dao.clone()];
+        for tenant in settings.tenants()? {
+            daos.push(dao.for_tenant(&tenant));
+        }
+        Ok(daos)
+    });
+    match daos {
+        Ok(daos) => {
+            for dao in daos {
+                match dao.check(repair) {
+                    Ok(report) => {
+                        for problem in &report.problems {
+                            println!("{}", problem);
+                        }
+                        if report.is_clean() {
+                            println!("Checked {} books, no problems found", report.books);
+                        } else if repair {
+                            println!(
+                                "Checked {} books, repaired {} of {} problems",
+                                report.books,
+                                report.repaired,
+                                report.problems.len()
+                            );
+                        } else {
+                            println!(
+                                "Checked {} books, found {} problems, run check --repair to fix them",
+                                report.books,
+                                report.problems.len()
+                            );
+                        }
+                    }
+                    Err(e) => eprintln!("Could not check the books: {}", e),
+                }
+            }
+        }
+        Err(e) => eprintln!("Could not check the books: {}", e),
+    }
+}
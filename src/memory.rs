@@ -0,0 +1,577 @@
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound::{Excluded, Unbounded};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use uuid::Uuid;
+
+use crate::dao::{self, AUTHOR, DELETED_AT, TITLE, VERSION};
+use crate::errors::BookServiceError;
+use crate::model::{
+    Book, BookPage, IdempotentResponse, Operation, Revision, TrashedBook, VersionedBook,
+};
+use crate::search;
+use crate::store::{check_version, unix_time, BookStore};
+
+type Records = BTreeMap<String, HashMap<String, String>>;
+type History = HashMap<String, Vec<Revision>>;
+/// Saved responses along with the unix time they expire at
+type Idempotency = HashMap<String, (IdempotentResponse, u64)>;
+
+/// A BookStore kept in process memory, for local development and tests.
+/// Books are held as the same field maps the Redis backend stores and are read back
+/// through the same code, so missing fields and missing books fail the same way.
+/// Listings walk the keys in order rather than in Redis hash table order.
+/// The history lock is only ever taken while holding the records lock.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    records: RwLock<Records>,
+    history: RwLock<History>,
+    idempotency: RwLock<Idempotency>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    // A panic while holding the lock can not leave a record half written, so a
+    // poisoned lock is still safe to use
+    fn read(&self) -> RwLockReadGuard<'_, Records> {
+        self.records.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Records> {
+        self.records.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn history_mut(&self) -> RwLockWriteGuard<'_, History> {
+        self.history.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn append_revision(&self, book: &Book, version: u64, operation: Operation, source: &str) {
+        self.history_mut()
+            .entry(dao::id_key(&book.book_id))
+            .or_insert_with(Vec::new)
+            .push(Revision::new(book, version, operation, source, unix_time()));
+    }
+}
+
+impl BookStore for MemoryStore {
+    fn put(&self, entry: &Book, source: &str) -> Result<u64, BookServiceError> {
+        println!("put for book {:?}", &entry);
+        let key = dao::id_key(&entry.book_id);
+        let mut records = self.write();
+        let version = records.get(&key).map(dao::version_from_map).unwrap_or(0) + 1;
+        records.insert(key, dao::book_to_map(entry, version));
+        self.append_revision(entry, version, Operation::Put, source);
+        Ok(version)
+    }
+
+    fn get_versioned(&self, uuid: &Uuid) -> Result<VersionedBook, BookServiceError> {
+        let key = dao::id_key(uuid);
+        let records = self.read();
+        let empty = HashMap::new();
+        let record = records.get(&key).unwrap_or(&empty);
+        if dao::is_trashed(record) {
+            return Err(BookServiceError::NotFoundError);
+        }
+        dao::book_from_map(&key, record).map(|book| VersionedBook {
+            book,
+            version: dao::version_from_map(record),
+        })
+    }
+
+    fn update(
+        &self,
+        entry: &Book,
+        expected_version: Option<u64>,
+        source: &str,
+    ) -> Result<Option<u64>, BookServiceError> {
+        println!("update for book {:?}", &entry);
+        let mut records = self.write();
+        match records
+            .get_mut(&dao::id_key(&entry.book_id))
+            .filter(|record| !dao::is_trashed(record))
+        {
+            Some(record) => {
+                let version = dao::version_from_map(record);
+                check_version(expected_version, version)?;
+                *record = dao::book_to_map(entry, version + 1);
+                self.append_revision(entry, version + 1, Operation::Update, source);
+                Ok(Some(version + 1))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn patch(
+        &self,
+        uuid: &Uuid,
+        patch: &Value,
+        expected_version: Option<u64>,
+        source: &str,
+    ) -> Result<VersionedBook, BookServiceError> {
+        println!("patch for book {} {}", uuid, patch);
+        let key = dao::id_key(uuid);
+        let mut records = self.write();
+        let (merged, version) = {
+            let record = records
+                .get(&key)
+                .filter(|record| !dao::is_trashed(record))
+                .ok_or(BookServiceError::NotFoundError)?;
+            let version = dao::version_from_map(record);
+            check_version(expected_version, version)?;
+            let merged =
+                dao::book_from_map(&key, record).and_then(|book| book.merge_patch(patch))?;
+            (merged, version + 1)
+        };
+        records.insert(key, dao::book_to_map(&merged, version));
+        self.append_revision(&merged, version, Operation::Patch, source);
+        Ok(VersionedBook {
+            book: merged,
+            version,
+        })
+    }
+
+    fn delete(
+        &self,
+        uuid: &Uuid,
+        expected_version: Option<u64>,
+        source: &str,
+    ) -> Result<bool, BookServiceError> {
+        println!("delete for book {}", uuid);
+        let key = dao::id_key(uuid);
+        let mut records = self.write();
+        match records
+            .get_mut(&key)
+            .filter(|record| !dao::is_trashed(record))
+        {
+            Some(record) => {
+                let version = dao::version_from_map(record);
+                check_version(expected_version, version)?;
+                record.insert(DELETED_AT.to_string(), unix_time().to_string());
+                record.insert(VERSION.to_string(), (version + 1).to_string());
+                if let Ok(book) = dao::book_from_map(&key, record) {
+                    self.append_revision(&book, version + 1, Operation::Delete, source);
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn list_trash(&self) -> Result<Vec<TrashedBook>, BookServiceError> {
+        let mut trash = self
+            .read()
+            .iter()
+            .filter_map(|(key, record)| {
+                record
+                    .get(DELETED_AT)
+                    .and_then(|deleted_at| deleted_at.parse().ok())
+                    .map(|deleted_at| {
+                        dao::book_from_map(key, record).map(|book| TrashedBook { book, deleted_at })
+                    })
+            })
+            .collect::<Result<Vec<TrashedBook>, BookServiceError>>()?;
+        trash.sort_by_key(|trashed| trashed.deleted_at);
+        Ok(trash)
+    }
+
+    fn restore(
+        &self,
+        uuid: &Uuid,
+        source: &str,
+    ) -> Result<Option<VersionedBook>, BookServiceError> {
+        println!("restore for book {}", uuid);
+        let key = dao::id_key(uuid);
+        let mut records = self.write();
+        match records
+            .get_mut(&key)
+            .filter(|record| dao::is_trashed(record))
+        {
+            Some(record) => {
+                let restored = VersionedBook {
+                    book: dao::book_from_map(&key, record)?,
+                    version: dao::version_from_map(record) + 1,
+                };
+                *record = dao::book_to_map(&restored.book, restored.version);
+                self.append_revision(&restored.book, restored.version, Operation::Restore, source);
+                Ok(Some(restored))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn purge(&self, deleted_before: u64) -> Result<usize, BookServiceError> {
+        let mut records = self.write();
+        let expired: Vec<String> = records
+            .iter()
+            .filter(|(_, record)| {
+                record
+                    .get(DELETED_AT)
+                    .and_then(|deleted_at| deleted_at.parse::<u64>().ok())
+                    .map(|deleted_at| deleted_at <= deleted_before)
+                    .unwrap_or(false)
+            })
+            .map(|(key, _)| key.to_owned())
+            .collect();
+        let mut history = self.history_mut();
+        for key in &expired {
+            records.remove(key);
+            history.remove(key);
+        }
+        Ok(expired.len())
+    }
+
+    fn history(&self, uuid: &Uuid) -> Result<Vec<Revision>, BookServiceError> {
+        Ok(self
+            .history
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&dao::id_key(uuid))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// The cursor is the last key of the previous page, so books written or deleted
+    /// between pages never cause others to be skipped or repeated
+    fn list(&self, cursor: Option<&str>, limit: usize) -> Result<BookPage, BookServiceError> {
+        let start = match cursor {
+            Some(cursor) => Excluded(decode_cursor(cursor)?),
+            None => Unbounded,
+        };
+        let records = self.read();
+        let mut entries = records
+            .range((start, Unbounded))
+            .filter(|(_, record)| !dao::is_trashed(record));
+        let books = entries
+            .by_ref()
+            .take(limit)
+            .map(|(key, record)| dao::book_from_map(key, record))
+            .collect::<Result<Vec<Book>, BookServiceError>>()?;
+        let next = match entries.next() {
+            Some(_) => books
+                .last()
+                .map(|book| encode_cursor(&dao::id_key(&book.book_id))),
+            None => None,
+        };
+        Ok(BookPage { books, next })
+    }
+
+    fn find_by_author(&self, author: &str) -> Result<Vec<Book>, BookServiceError> {
+        println!("find books by author {}", author);
+        let author = dao::normalise_author(author);
+        self.read()
+            .iter()
+            .filter(|(_, record)| !dao::is_trashed(record))
+            .filter(|(_, record)| {
+                record
+                    .get(AUTHOR)
+                    .map(|stored| dao::normalise_author(stored) == author)
+                    .unwrap_or(false)
+            })
+            .map(|(key, record)| dao::book_from_map(key, record))
+            .collect()
+    }
+
+    fn search(&self, query: &str, limit: usize) -> Result<Vec<Book>, BookServiceError> {
+        println!("search books for {}", query);
+        let records = self.read();
+        let title_terms: Vec<(String, Vec<String>)> = records
+            .iter()
+            .filter(|(_, record)| !dao::is_trashed(record))
+            .filter_map(|(key, record)| {
+                record
+                    .get(TITLE)
+                    .map(|title| (key.to_owned(), search::tokenise(title)))
+            })
+            .collect();
+        let term_matches: Vec<Vec<String>> = search::tokenise(query)
+            .iter()
+            .map(|term| {
+                title_terms
+                    .iter()
+                    .filter(|(_, terms)| terms.contains(term))
+                    .map(|(key, _)| key.to_owned())
+                    .collect()
+            })
+            .collect();
+        let ranked = search::rank(&term_matches);
+        ranked
+            .iter()
+            .take(limit)
+            .filter_map(|key| records.get(key).map(|record| (key, record)))
+            .map(|(key, record)| dao::book_from_map(key, record))
+            .collect()
+    }
+
+    fn idempotent_response(
+        &self,
+        key: &str,
+    ) -> Result<Option<IdempotentResponse>, BookServiceError> {
+        let now = unix_time();
+        Ok(self
+            .idempotency
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(key)
+            .filter(|(_, expires_at)| *expires_at > now)
+            .map(|(response, _)| response.clone()))
+    }
+
+    /// Expired responses are dropped as new ones are saved
+    fn save_idempotent_response(
+        &self,
+        key: &str,
+        response: &IdempotentResponse,
+        ttl_secs: u64,
+    ) -> Result<bool, BookServiceError> {
+        let now = unix_time();
+        let mut saved = self.idempotency.write().unwrap_or_else(|e| e.into_inner());
+        saved.retain(|_, (_, expires_at)| *expires_at > now);
+        if saved.contains_key(key) {
+            return Ok(false);
+        }
+        saved.insert(key.to_string(), (response.clone(), now + ttl_secs));
+        Ok(true)
+    }
+
+    fn replace_idempotent_response(
+        &self,
+        key: &str,
+        response: &IdempotentResponse,
+        ttl_secs: u64,
+    ) -> Result<(), BookServiceError> {
+        let expires_at = unix_time() + ttl_secs;
+        self.idempotency
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key.to_string(), (response.clone(), expires_at));
+        Ok(())
+    }
+
+    fn remove_idempotent_response(&self, key: &str) -> Result<(), BookServiceError> {
+        self.idempotency
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(key);
+        Ok(())
+    }
+}
+
+fn encode_cursor(key: &str) -> String {
+    base64::encode_config(key, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_cursor(cursor: &str) -> Result<String, BookServiceError> {
+    base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| BookServiceError::InvalidQueryError(format!("invalid cursor {}", cursor)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_put_get() {
+        let book_id = Uuid::parse_str("0bcd291d-b7c5-4390-965f-8a70707d22a5").unwrap();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the World");
+
+        let store = MemoryStore::new();
+        let _ = store.put(&book, "test").unwrap();
+
+        assert_eq!(book, store.get(&book_id).unwrap());
+    }
+
+    #[test]
+    fn test_get_missing() {
+        let store = MemoryStore::new();
+        match store.get(&Uuid::new_v4()) {
+            Err(BookServiceError::MissingFieldError(ref field)) => {
+                assert_eq!(AUTHOR, field.as_str())
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_list() {
+        let store = MemoryStore::new();
+        let mut expected: Vec<Book> = (0..5)
+            .map(|i| Book::new(Uuid::new_v4(), "Robert Jordan", &format!("Volume {}", i)))
+            .collect();
+        for book in &expected {
+            let _ = store.put(book, "test").unwrap();
+        }
+
+        let mut listed = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = store.list(cursor.as_ref().map(String::as_ref), 2).unwrap();
+            assert!(page.books.len() <= 2);
+            listed.extend(page.books);
+            cursor = page.next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        expected.sort_by_key(|book| book.book_id);
+        assert_eq!(expected, listed);
+    }
+
+    #[test]
+    fn test_update_patch_delete() {
+        let store = MemoryStore::new();
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the Wrold");
+        let patch: Value = serde_json::from_str("{\"title\": \"Eye of the World\"}").unwrap();
+
+        assert!(store.update(&book, None, "test").unwrap().is_none());
+        assert!(store.patch(&book_id, &patch, None, "test").is_err());
+
+        let _ = store.put(&book, "test").unwrap();
+        let merged = store.patch(&book_id, &patch, None, "test").unwrap();
+        assert_eq!("Eye of the World", merged.book.title);
+        assert_eq!(merged.book, store.get(&book_id).unwrap());
+
+        let updated = Book::new(book_id, "Robert Jordan", "The Great Hunt");
+        assert!(store.update(&updated, None, "test").unwrap().is_some());
+        assert_eq!(updated, store.get(&book_id).unwrap());
+
+        assert!(store.delete(&book_id, None, "test").unwrap());
+        assert!(!store.delete(&book_id, None, "test").unwrap());
+    }
+
+    #[test]
+    fn test_versions() {
+        let store = MemoryStore::new();
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the World");
+
+        assert_eq!(1, store.put(&book, "test").unwrap());
+        assert_eq!(1, store.get_versioned(&book_id).unwrap().version);
+        assert_eq!(Some(2), store.update(&book, Some(1), "test").unwrap());
+        match store.update(&book, Some(1), "test") {
+            Err(BookServiceError::PreconditionFailedError(2)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(store.delete(&book_id, Some(1), "test").is_err());
+        assert!(store.delete(&book_id, Some(2), "test").unwrap());
+    }
+
+    #[test]
+    fn test_trash() {
+        let store = MemoryStore::new();
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the World");
+
+        let _ = store.put(&book, "test").unwrap();
+        assert!(store.delete(&book_id, None, "test").unwrap());
+        assert!(store.get(&book_id).is_err());
+        assert!(store.update(&book, None, "test").unwrap().is_none());
+        assert!(store.list(None, 10).unwrap().books.is_empty());
+        assert!(store.search("world", 10).unwrap().is_empty());
+
+        let trash = store.list_trash().unwrap();
+        assert_eq!(1, trash.len());
+        assert_eq!(book, trash[0].book);
+
+        let restored = store.restore(&book_id, "test").unwrap().unwrap();
+        assert_eq!(3, restored.version);
+        assert_eq!(book, store.get(&book_id).unwrap());
+        assert!(store.restore(&book_id, "test").unwrap().is_none());
+
+        assert!(store.delete(&book_id, None, "test").unwrap());
+        assert_eq!(0, store.purge(trash[0].deleted_at - 1).unwrap());
+        assert_eq!(1, store.purge(unix_time()).unwrap());
+        assert!(store.list_trash().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_idempotent_response() {
+        let store = MemoryStore::new();
+        let response = IdempotentResponse::new(b"{}", 202, Some(1));
+        assert_eq!(None, store.idempotent_response("import-42").unwrap());
+
+        assert!(store
+            .save_idempotent_response("import-42", &response, 60)
+            .unwrap());
+        let replaced = IdempotentResponse::new(b"{}", 400, None);
+        assert!(!store
+            .save_idempotent_response("import-42", &replaced, 60)
+            .unwrap());
+        assert_eq!(
+            Some(response),
+            store.idempotent_response("import-42").unwrap()
+        );
+
+        store
+            .replace_idempotent_response("import-42", &replaced, 60)
+            .unwrap();
+        assert_eq!(
+            Some(replaced.clone()),
+            store.idempotent_response("import-42").unwrap()
+        );
+        store.remove_idempotent_response("import-42").unwrap();
+        assert_eq!(None, store.idempotent_response("import-42").unwrap());
+
+        store
+            .save_idempotent_response("expired", &replaced, 0)
+            .unwrap();
+        assert_eq!(None, store.idempotent_response("expired").unwrap());
+    }
+
+    #[test]
+    fn test_history() {
+        let store = MemoryStore::new();
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the Wrold");
+        let patch: Value = serde_json::from_str("{\"title\": \"Eye of the World\"}").unwrap();
+
+        assert!(store.history(&book_id).unwrap().is_empty());
+        let _ = store.put(&book, "import").unwrap();
+        let _ = store.patch(&book_id, &patch, None, "editor").unwrap();
+        assert!(store.delete(&book_id, None, "editor").unwrap());
+
+        let history = store.history(&book_id).unwrap();
+        let operations: Vec<Operation> = history.iter().map(|r| r.operation).collect();
+        assert_eq!(
+            vec![Operation::Put, Operation::Patch, Operation::Delete],
+            operations
+        );
+        assert_eq!("import", history[0].source);
+        assert_eq!("Eye of the Wrold", history[0].book.title);
+        assert_eq!(history[1], store.revision(&book_id, 2).unwrap());
+        assert!(store.revision(&book_id, 4).is_err());
+
+        assert_eq!(1, store.purge(unix_time()).unwrap());
+        assert!(store.history(&book_id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_find_by_author_and_search() {
+        let store = MemoryStore::new();
+        let eye = Book::new(Uuid::new_v4(), "Robert Jordan", "The Eye of the World");
+        let hunt = Book::new(Uuid::new_v4(), "Robert Jordan", "The Great Hunt");
+        let bell = Book::new(
+            Uuid::new_v4(),
+            "Ernest Hemmingway",
+            "For Whom the Bell Tolls",
+        );
+        for book in &[&eye, &hunt, &bell] {
+            let _ = store.put(book, "test").unwrap();
+        }
+
+        let mut found = store.find_by_author("  robert JORDAN").unwrap();
+        found.sort_by_key(|book| book.title.to_owned());
+        assert_eq!(vec![&eye, &hunt], found.iter().collect::<Vec<&Book>>());
+
+        let found = store.search("great world", 10).unwrap();
+        assert_eq!(2, found.len());
+        assert!(store.search("the", 10).unwrap().is_empty());
+        assert_eq!(vec![bell], store.search("bell", 10).unwrap());
+    }
+}
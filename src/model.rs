@@ -1,11 +1,11 @@
 use crate::errors::BookServiceError;
 use serde::de::{self, Visitor};
 use serde::{Deserializer, Serializer};
-use serde_json;
+use serde_json::{self, Map, Value};
 use std::fmt;
 use uuid::Uuid;
 
-#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Default)]
 pub struct Book {
     #[serde(
         serialize_with = "serialize_uuid",
@@ -16,6 +16,234 @@ pub struct Book {
     pub title: String,
 }
 
+/// A stored book along with the version its latest write gave it, the version is
+/// handed to clients as the ETag of the book
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionedBook {
+    pub book: Book,
+    pub version: u64,
+}
+
+/// A soft deleted book, kept in the trash until it is restored or purged
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TrashedBook {
+    #[serde(flatten)]
+    pub book: Book,
+    /// Seconds since the unix epoch
+    pub deleted_at: u64,
+}
+
+/// The kinds of write a revision can record
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Put,
+    Update,
+    Patch,
+    Delete,
+    Restore,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Put => "put",
+            Operation::Update => "update",
+            Operation::Patch => "patch",
+            Operation::Delete => "delete",
+            Operation::Restore => "restore",
+        }
+    }
+
+    pub fn parse(operation: &str) -> Option<Operation> {
+        [
+            Operation::Put,
+            Operation::Update,
+            Operation::Patch,
+            Operation::Delete,
+            Operation::Restore,
+        ]
+        .iter()
+        .find(|known| known.as_str() == operation)
+        .cloned()
+    }
+}
+
+/// One entry in the history of a book, the state a write left the book in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Revision {
+    /// The version the write gave the book
+    pub version: u64,
+    #[serde(flatten)]
+    pub book: Book,
+    pub operation: Operation,
+    /// Who or what made the change, from the X-Change-Source header
+    pub source: String,
+    /// Seconds since the unix epoch
+    pub changed_at: u64,
+}
+
+/// A field whose value differs between two revisions
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub from: String,
+    pub to: String,
+}
+
+/// The field level differences between two revisions of a book
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RevisionDiff {
+    pub from: u64,
+    pub to: u64,
+    pub changes: Vec<FieldChange>,
+}
+
+impl Revision {
+    pub fn new(
+        book: &Book,
+        version: u64,
+        operation: Operation,
+        source: &str,
+        changed_at: u64,
+    ) -> Self {
+        Revision {
+            version,
+            book: book.clone(),
+            operation,
+            source: source.to_owned(),
+            changed_at,
+        }
+    }
+
+    pub fn from_slice(slice: &[u8]) -> Result<Revision, BookServiceError> {
+        serde_json::from_slice(slice).map_err(BookServiceError::BookParseError)
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>, BookServiceError> {
+        serde_json::to_vec(self).map_err(BookServiceError::BookSerializationError)
+    }
+
+    /// Lists the book fields that changed going from this revision to the other one
+    pub fn diff(&self, to: &Revision) -> RevisionDiff {
+        let fields = [
+            ("author", &self.book.author, &to.book.author),
+            ("title", &self.book.title, &to.book.title),
+        ];
+        RevisionDiff {
+            from: self.version,
+            to: to.version,
+            changes: fields
+                .iter()
+                .filter(|(_, before, after)| before != after)
+                .map(|(field, before, after)| FieldChange {
+                    field: field.to_string(),
+                    from: before.to_string(),
+                    to: after.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A committed write to a book, published for downstream services
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookEvent {
+    #[serde(rename = "type")]
+    pub event_type: Operation,
+    #[serde(
+        serialize_with = "serialize_uuid",
+        deserialize_with = "deserialize_uuid"
+    )]
+    pub book_id: Uuid,
+    /// The book as the write left it, as it was trashed for a delete
+    pub book: Book,
+    pub version: u64,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+}
+
+impl BookEvent {
+    pub fn new(event_type: Operation, book: &Book, version: u64, timestamp: u64) -> Self {
+        BookEvent {
+            event_type,
+            book_id: book.book_id,
+            book: book.clone(),
+            version,
+            timestamp,
+        }
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>, BookServiceError> {
+        serde_json::to_vec(self).map_err(BookServiceError::BookSerializationError)
+    }
+}
+
+/// The first response to a request sent with an Idempotency-Key, replayed to its retries
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IdempotentResponse {
+    /// The base64 encoded body of the request the key was first used with
+    pub payload: String,
+    /// 0 while the first request is still being answered
+    pub status: u16,
+    /// The version handed out as the ETag, when there was one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    #[serde(default)]
+    pub body: String,
+}
+
+impl IdempotentResponse {
+    pub fn new(request_body: &[u8], status: u16, version: Option<u64>) -> Self {
+        IdempotentResponse {
+            payload: base64::encode(request_body),
+            status,
+            version,
+            body: String::new(),
+        }
+    }
+
+    /// Reserves a key for the request with the body until its response is saved
+    pub fn pending(request_body: &[u8]) -> Self {
+        IdempotentResponse::new(request_body, 0, None)
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.status == 0
+    }
+
+    /// Whether a request body is the one the key was first used with
+    pub fn matches(&self, request_body: &[u8]) -> bool {
+        self.payload == base64::encode(request_body)
+    }
+
+    pub fn from_slice(slice: &[u8]) -> Result<IdempotentResponse, BookServiceError> {
+        serde_json::from_slice(slice).map_err(BookServiceError::BookParseError)
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>, BookServiceError> {
+        serde_json::to_vec(self).map_err(BookServiceError::BookSerializationError)
+    }
+}
+
+/// How a book cache has been doing, served by GET /admin/cache
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    /// Books currently cached
+    pub size: usize,
+    pub capacity: usize,
+}
+
+/// One page of a book listing along with the cursor for the page that follows it
+#[derive(Debug, PartialEq, Serialize, Default)]
+pub struct BookPage {
+    pub books: Vec<Book>,
+    /// None once the listing is complete
+    pub next: Option<String>,
+}
+
 impl Book {
     #[allow(dead_code)]
     pub fn new(book_id: Uuid, author: &str, title: &str) -> Self {
@@ -34,6 +262,49 @@ impl Book {
         serde_json::to_vec(self).map_err(BookServiceError::BookSerializationError)
     }
 
+    /// Applies an RFC 7396 JSON Merge Patch, returning the merged book.
+    /// The book_id can not be changed and the required fields can not be removed.
+    pub fn merge_patch(&self, patch: &Value) -> Result<Book, BookServiceError> {
+        let fields = patch.as_object().ok_or_else(|| {
+            BookServiceError::InvalidPatchError("a book patch must be a JSON object".to_string())
+        })?;
+        for (field, value) in fields {
+            match field.as_str() {
+                "book_id" => {
+                    // the id may be spelled in any form Uuid parses
+                    let same_id = value
+                        .as_str()
+                        .and_then(|id| Uuid::parse_str(id).ok())
+                        .map_or(false, |id| id == self.book_id);
+                    if !same_id {
+                        return Err(BookServiceError::InvalidPatchError(
+                            "book_id is immutable".to_string(),
+                        ));
+                    }
+                }
+                "author" | "title" => {
+                    if value.is_null() {
+                        return Err(BookServiceError::InvalidPatchError(format!(
+                            "{} is required and can not be removed",
+                            field
+                        )));
+                    }
+                }
+                _ => {
+                    return Err(BookServiceError::InvalidPatchError(format!(
+                        "unknown field {}",
+                        field
+                    )));
+                }
+            }
+        }
+
+        let mut merged =
+            serde_json::to_value(self).map_err(BookServiceError::BookSerializationError)?;
+        apply_merge_patch(&mut merged, patch);
+        serde_json::from_value(merged).map_err(BookServiceError::BookParseError)
+    }
+
     #[allow(dead_code)]
     pub fn with_book_id(self, book_id: &Uuid) -> Self {
         Book {
@@ -59,6 +330,30 @@ impl Book {
     }
 }
 
+/// The merge algorithm from RFC 7396 section 2
+fn apply_merge_patch(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(patch_fields) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+            if let Value::Object(target_fields) = target {
+                for (field, value) in patch_fields {
+                    if value.is_null() {
+                        target_fields.remove(field);
+                    } else {
+                        apply_merge_patch(
+                            target_fields.entry(field.to_owned()).or_insert(Value::Null),
+                            value,
+                        );
+                    }
+                }
+            }
+        }
+        _ => *target = patch.clone(),
+    }
+}
+
 fn serialize_uuid<S>(uuid: &Uuid, s: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -135,4 +430,78 @@ mod tests {
             book.book_id.hyphenated().to_string()
         );
     }
+
+    #[test]
+    fn test_revision_diff() {
+        let book = Book::new(Uuid::new_v4(), "Robert Jordan", "Eye of the Wrold");
+        let first = Revision::new(&book, 1, Operation::Put, "import", 100);
+        let fixed = Book::new(book.book_id, "Robert Jordan", "Eye of the World");
+        let second = Revision::new(&fixed, 2, Operation::Patch, "editor", 200);
+
+        let diff = first.diff(&second);
+        assert_eq!(1, diff.from);
+        assert_eq!(2, diff.to);
+        assert_eq!(
+            vec![FieldChange {
+                field: "title".to_string(),
+                from: "Eye of the Wrold".to_string(),
+                to: "Eye of the World".to_string(),
+            }],
+            diff.changes
+        );
+        assert!(second.diff(&second).changes.is_empty());
+
+        let json = second.to_vec().unwrap();
+        assert_eq!(second, Revision::from_slice(&json).unwrap());
+    }
+
+    #[test]
+    fn test_book_event() {
+        let book = Book::new(
+            Uuid::parse_str("0bcd291d-b7c5-4390-965f-8a70707d22a5").unwrap(),
+            "Robert Jordan",
+            "Eye of the World",
+        );
+        let event = BookEvent::new(Operation::Delete, &book, 3, 100);
+        let json: Value = serde_json::from_slice(&event.to_vec().unwrap()).unwrap();
+        assert_eq!("delete", json["type"]);
+        assert_eq!("0bcd291d-b7c5-4390-965f-8a70707d22a5", json["book_id"]);
+        assert_eq!("Eye of the World", json["book"]["title"]);
+        assert_eq!(3, json["version"]);
+        assert_eq!(100, json["timestamp"]);
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let book = Book::new(Uuid::new_v4(), "Robert Jordan", "Eye of the Wrold");
+        let patch = serde_json::from_str("{\"title\": \"Eye of the World\"}").unwrap();
+        let merged = book.merge_patch(&patch).unwrap();
+        assert_eq!(book.book_id, merged.book_id);
+        assert_eq!("Robert Jordan", merged.author);
+        assert_eq!("Eye of the World", merged.title);
+
+        let book_id = book.book_id.simple().to_string().to_uppercase();
+        let patch = serde_json::from_str(&format!("{{\"book_id\": \"{}\"}}", book_id)).unwrap();
+        assert_eq!(book.book_id, book.merge_patch(&patch).unwrap().book_id);
+    }
+
+    #[test]
+    fn test_merge_patch_rejects_invalid_changes() {
+        let book = Book::new(Uuid::new_v4(), "Robert Jordan", "Eye of the World");
+        for json in &[
+            "{\"author\": null}",
+            "{\"book_id\": \"87b17841-c677-4451-8bb9-64355b59c585\"}",
+            "{\"book_id\": 42}",
+            "{\"isbn\": \"0312850093\"}",
+            "{\"title\": 42}",
+            "[\"title\"]",
+        ] {
+            let patch = serde_json::from_str(json).unwrap();
+            assert!(
+                book.merge_patch(&patch).is_err(),
+                "patch {} was accepted",
+                json
+            );
+        }
+    }
 }
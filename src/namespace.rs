@@ -0,0 +1,313 @@
+use uuid::Uuid;
+
+use crate::dao::normalise_author;
+
+pub const BOOK_PREFIX: &'static str = "BOOK-";
+const AUTHOR_PREFIX: &'static str = "AUTHOR-";
+const TERM_PREFIX: &'static str = "TERM-";
+const HISTORY_PREFIX: &'static str = "HISTORY-";
+/// Responses saved for an Idempotency-Key, expired by Redis
+const IDEMPOTENCY_PREFIX: &'static str = "IDEMPOTENCY-";
+/// Sorted set of the trashed book ids scored by their deletion time
+const TRASH_KEY: &'static str = "TRASH";
+const SCHEMA_KEY: &'static str = "SCHEMA";
+/// Stream of the change record of every write, read by downstream consumers
+const OUTBOX_KEY: &'static str = "OUTBOX";
+/// Keys a catalog check moved aside as the service could not read them
+const QUARANTINE_PREFIX: &'static str = "QUARANTINE-";
+const SEPARATOR: char = ':';
+
+/// Builds every Redis key the service writes, placing them under a namespace so several
+/// environments can share one Redis instance. A namespace of `staging` keeps books at
+/// `staging:BOOK-{id}`; the empty namespace is the unprefixed layout used before
+/// namespaces were configurable.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Namespace {
+    prefix: String,
+    /// Wraps the id of per-book keys in a hash tag, `BOOK-{id}`, so that in a Redis
+    /// Cluster a book and its history share a slot
+    hash_tags: bool,
+}
+
+impl Namespace {
+    pub fn new(name: &str) -> Self {
+        let name = name.trim().trim_end_matches(SEPARATOR);
+        Namespace {
+            prefix: if name.is_empty() {
+                String::new()
+            } else {
+                format!("{}{}", name, SEPARATOR)
+            },
+            hash_tags: false,
+        }
+    }
+
+    /// The namespace with per-book keys hash tagged, the layout used in a Redis Cluster
+    pub fn with_hash_tags(self) -> Self {
+        Namespace {
+            hash_tags: true,
+            ..self
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.prefix.trim_end_matches(SEPARATOR)
+    }
+
+    pub fn book_key(&self, uuid: &Uuid) -> String {
+        self.book_key_for_id(uuid.hyphenated().to_string().as_ref())
+    }
+
+    /// The key of a book from its id as kept in the index sets
+    pub fn book_key_for_id(&self, id: &str) -> String {
+        format!("{}{}{}", self.prefix, BOOK_PREFIX, self.book_tag(id))
+    }
+
+    /// Matches the keys of every book in the namespace
+    pub fn book_pattern(&self) -> String {
+        self.pattern(BOOK_PREFIX)
+    }
+
+    /// Matches the author index sets of the namespace
+    pub fn author_pattern(&self) -> String {
+        self.pattern(AUTHOR_PREFIX)
+    }
+
+    /// Matches the title term index sets of the namespace
+    pub fn term_pattern(&self) -> String {
+        self.pattern(TERM_PREFIX)
+    }
+
+    /// Matches the history lists of the namespace
+    pub fn history_pattern(&self) -> String {
+        self.pattern(HISTORY_PREFIX)
+    }
+
+    fn pattern(&self, kind: &str) -> String {
+        format!("{}{}*", glob_escape(&self.prefix), kind)
+    }
+
+    pub fn author_key(&self, author: &str) -> String {
+        format!(
+            "{}{}{}",
+            self.prefix,
+            AUTHOR_PREFIX,
+            normalise_author(author)
+        )
+    }
+
+    pub fn term_key(&self, term: &str) -> String {
+        format!("{}{}{}", self.prefix, TERM_PREFIX, term)
+    }
+
+    pub fn history_key(&self, id: &str) -> String {
+        format!("{}{}{}", self.prefix, HISTORY_PREFIX, self.book_tag(id))
+    }
+
+    /// The key of the book a history list belongs to, None for a key that is not a
+    /// history list of this namespace
+    pub fn history_book_key(&self, history_key: &str) -> Option<String> {
+        let history_prefix = format!("{}{}", self.prefix, HISTORY_PREFIX);
+        if history_key.starts_with(&history_prefix) {
+            Some(format!(
+                "{}{}{}",
+                self.prefix,
+                BOOK_PREFIX,
+                &history_key[history_prefix.len()..]
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn book_tag(&self, id: &str) -> String {
+        if self.hash_tags {
+            format!("{{{}}}", id)
+        } else {
+            id.to_string()
+        }
+    }
+
+    pub fn idempotency_key(&self, key: &str) -> String {
+        format!("{}{}{}", self.prefix, IDEMPOTENCY_PREFIX, key)
+    }
+
+    pub fn trash_key(&self) -> String {
+        format!("{}{}", self.prefix, TRASH_KEY)
+    }
+
+    pub fn outbox_key(&self) -> String {
+        format!("{}{}", self.prefix, OUTBOX_KEY)
+    }
+
+    /// Holds the schema every book of the namespace was last migrated to
+    pub fn schema_key(&self) -> String {
+        format!("{}{}", self.prefix, SCHEMA_KEY)
+    }
+
+    /// The Pub/Sub channel of the namespace, channels are not keys but are kept apart
+    /// the same way
+    pub fn channel(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    /// Where a catalog check moves a key of the namespace the service can not read
+    pub fn quarantine_key(&self, key: &str) -> String {
+        let name = if key.starts_with(&self.prefix) {
+            &key[self.prefix.len()..]
+        } else {
+            key
+        };
+        format!("{}{}{}", self.prefix, QUARANTINE_PREFIX, name)
+    }
+
+    /// A namespace nested in this one, keeping its keys apart from those of this one
+    pub fn child(&self, name: &str) -> Namespace {
+        Namespace {
+            hash_tags: self.hash_tags,
+            ..Namespace::new(&format!("{}{}", self.prefix, name))
+        }
+    }
+
+    /// Patterns matching every key the service writes in the namespace, and nothing
+    /// written by another namespace
+    pub fn patterns(&self) -> Vec<String> {
+        let escaped = glob_escape(&self.prefix);
+        let mut patterns: Vec<String> = [
+            BOOK_PREFIX,
+            AUTHOR_PREFIX,
+            TERM_PREFIX,
+            HISTORY_PREFIX,
+            IDEMPOTENCY_PREFIX,
+            QUARANTINE_PREFIX,
+        ]
+        .iter()
+        .map(|kind| self.pattern(kind))
+        .collect();
+        patterns.push(format!("{}{}", escaped, TRASH_KEY));
+        patterns.push(format!("{}{}", escaped, SCHEMA_KEY));
+        patterns.push(format!("{}{}", escaped, OUTBOX_KEY));
+        patterns
+    }
+
+    /// The name the key has in the other namespace, None for a key outside this one
+    pub fn move_key(&self, key: &str, to: &Namespace) -> Option<String> {
+        if key.starts_with(&self.prefix) {
+            Some(to.prefix.to_owned() + &key[self.prefix.len()..])
+        } else {
+            None
+        }
+    }
+}
+
+/// Escapes the characters SCAN MATCH treats as a glob
+fn glob_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "*?[]\\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let uuid = Uuid::parse_str("0bcd291d-b7c5-4390-965f-8a70707d22a5").unwrap();
+        let staging = Namespace::new("staging");
+        assert_eq!("staging", staging.name());
+        assert_eq!(
+            "staging:BOOK-0bcd291d-b7c5-4390-965f-8a70707d22a5",
+            staging.book_key(&uuid)
+        );
+        assert_eq!(
+            "staging:AUTHOR-robert jordan",
+            staging.author_key(" Robert Jordan")
+        );
+        assert_eq!("staging:TRASH", staging.trash_key());
+        assert_eq!("staging:SCHEMA", staging.schema_key());
+        assert_eq!("staging:book-events", staging.channel("book-events"));
+        assert_eq!(
+            "BOOK-0bcd291d-b7c5-4390-965f-8a70707d22a5",
+            Namespace::default().book_key(&uuid)
+        );
+        assert_eq!(Namespace::default(), Namespace::new(" "));
+        assert_eq!(staging, Namespace::new("staging:"));
+        assert_eq!("staging:shop-a:TRASH", staging.child("shop-a").trash_key());
+        assert_eq!(
+            "shop-a:TRASH",
+            Namespace::default().child("shop-a").trash_key()
+        );
+    }
+
+    #[test]
+    fn test_hash_tags() {
+        let uuid = Uuid::parse_str("0bcd291d-b7c5-4390-965f-8a70707d22a5").unwrap();
+        let tagged = Namespace::new("staging").with_hash_tags();
+        assert_eq!(
+            "staging:BOOK-{0bcd291d-b7c5-4390-965f-8a70707d22a5}",
+            tagged.book_key(&uuid)
+        );
+        assert_eq!(
+            "staging:HISTORY-{0bcd291d-b7c5-4390-965f-8a70707d22a5}",
+            tagged.history_key("0bcd291d-b7c5-4390-965f-8a70707d22a5")
+        );
+        assert_eq!("staging:TRASH", tagged.trash_key());
+        assert_eq!(
+            "staging:shop-a:BOOK-{0bcd291d-b7c5-4390-965f-8a70707d22a5}",
+            tagged.child("shop-a").book_key(&uuid)
+        );
+    }
+
+    #[test]
+    fn test_patterns() {
+        assert_eq!("BOOK-*", Namespace::default().book_pattern());
+        assert_eq!("qa\\*:BOOK-*", Namespace::new("qa*").book_pattern());
+        let patterns = Namespace::new("qa").patterns();
+        assert!(patterns.contains(&"qa:HISTORY-*".to_string()));
+        assert!(patterns.contains(&"qa:TRASH".to_string()));
+        assert!(patterns.contains(&"qa:SCHEMA".to_string()));
+        assert!(patterns.contains(&"qa:OUTBOX".to_string()));
+        assert!(patterns.contains(&"qa:QUARANTINE-*".to_string()));
+        assert_eq!("qa:TERM-*", Namespace::new("qa").term_pattern());
+    }
+
+    #[test]
+    fn test_check_keys() {
+        let tagged = Namespace::new("qa").with_hash_tags();
+        assert_eq!(
+            Some("qa:BOOK-{0bcd291d-b7c5-4390-965f-8a70707d22a5}".to_string()),
+            tagged.history_book_key("qa:HISTORY-{0bcd291d-b7c5-4390-965f-8a70707d22a5}")
+        );
+        assert_eq!(
+            None,
+            tagged.history_book_key("HISTORY-0bcd291d-b7c5-4390-965f-8a70707d22a5")
+        );
+        assert_eq!(
+            "qa:QUARANTINE-BOOK-{0bcd291d-b7c5-4390-965f-8a70707d22a5}",
+            tagged.quarantine_key("qa:BOOK-{0bcd291d-b7c5-4390-965f-8a70707d22a5}")
+        );
+    }
+
+    #[test]
+    fn test_move_key() {
+        let qa = Namespace::new("qa");
+        assert_eq!(
+            Some("qa:TERM-world".to_string()),
+            Namespace::default().move_key("TERM-world", &qa)
+        );
+        assert_eq!(
+            Some("TERM-world".to_string()),
+            qa.move_key("qa:TERM-world", &Namespace::default())
+        );
+        assert_eq!(
+            None,
+            qa.move_key("staging:TERM-world", &Namespace::default())
+        );
+    }
+}
@@ -1,20 +1,131 @@
 use crate::errors::BookServiceError;
+use crate::export::ExportFormat;
+use hyper::header::{ACCEPT, IF_MATCH};
 use hyper::{Body, Method, Request};
+use std::collections::HashMap;
+use std::str;
 use uuid::Uuid;
 
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 1000;
+const TRASH_PATH: &'static str = "/admin/trash";
+const CACHE_PATH: &'static str = "/admin/cache";
+const CHANGE_SOURCE: &'static str = "x-change-source";
+const UNKNOWN_SOURCE: &'static str = "unknown";
+const IDEMPOTENCY_KEY: &'static str = "idempotency-key";
+const TENANT: &'static str = "x-tenant";
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
 #[derive(Debug, PartialEq)]
 pub enum BookRequest {
     GetBook(Uuid),
-    PostBook,
+    ListBooks(ListParams),
+    BooksByAuthor(String),
+    SearchBooks(SearchParams),
+    /// Carries the Idempotency-Key the response is saved under, if one was sent
+    PostBook(Option<String>),
+    BulkImport,
+    ExportBooks(ExportFormat),
+    /// Subscribes to the writes made to the catalog
+    BookEvents,
+    /// Writes carry the version required by If-Match, None when any version will do
+    PutBook(Uuid, Option<u64>),
+    PatchBook(Uuid, Option<u64>),
+    DeleteBook(Uuid, Option<u64>),
+    ListTrash,
+    CacheStats,
+    RestoreBook(Uuid),
+    BookHistory(Uuid),
+    BookRevision(Uuid, u64),
+    DiffRevisions(Uuid, DiffParams),
     Health,
 }
 
+/// Paging parameters for GET /book/
+#[derive(Debug, PartialEq)]
+pub struct ListParams {
+    /// Opaque cursor returned as `next` by the previous page
+    pub cursor: Option<String>,
+    pub limit: usize,
+}
+
+impl ListParams {
+    fn from_query(params: &HashMap<String, String>) -> Result<Self, BookServiceError> {
+        let limit = match params.get("limit") {
+            Some(limit) => limit
+                .parse::<usize>()
+                .ok()
+                .filter(|limit| *limit > 0 && *limit <= MAX_LIMIT)
+                .ok_or_else(|| {
+                    BookServiceError::InvalidQueryError(format!(
+                        "limit must be between 1 and {}",
+                        MAX_LIMIT
+                    ))
+                })?,
+            None => DEFAULT_LIMIT,
+        };
+        Ok(ListParams {
+            cursor: params.get("cursor").cloned(),
+            limit,
+        })
+    }
+}
+
+/// Parameters for GET /book/search
+#[derive(Debug, PartialEq)]
+pub struct SearchParams {
+    pub query: String,
+    pub limit: usize,
+}
+
+impl SearchParams {
+    fn from_query(params: &HashMap<String, String>) -> Result<Self, BookServiceError> {
+        let query = params
+            .get("q")
+            .filter(|q| !q.trim().is_empty())
+            .ok_or_else(|| BookServiceError::InvalidQueryError("q is required".to_string()))?;
+        ListParams::from_query(params).map(|list_params| SearchParams {
+            query: query.to_owned(),
+            limit: list_params.limit,
+        })
+    }
+}
+
+/// The revisions compared by GET /book/{id}/diff
+#[derive(Debug, PartialEq)]
+pub struct DiffParams {
+    pub from: u64,
+    /// The latest revision when not given
+    pub to: Option<u64>,
+}
+
+impl DiffParams {
+    fn from_query(params: &HashMap<String, String>) -> Result<Self, BookServiceError> {
+        let from = params
+            .get("from")
+            .ok_or_else(|| BookServiceError::InvalidQueryError("from is required".to_string()))
+            .and_then(|from| parse_revision(from))?;
+        let to = match params.get("to") {
+            Some(to) => Some(parse_revision(to)?),
+            None => None,
+        };
+        Ok(DiffParams { from, to })
+    }
+}
+
 //todo - move to TryFrom when available
 impl BookRequest {
     pub fn from_request(req: &Request<Body>) -> Result<BookRequest, BookServiceError> {
         match (req.method(), req.uri().path()) {
+            (&Method::GET, TRASH_PATH) => Ok(BookRequest::ListTrash),
+            (&Method::GET, CACHE_PATH) => Ok(BookRequest::CacheStats),
+            (&Method::POST, path) if path.starts_with(TRASH_PATH) => Self::handle_restore(path),
             (&Method::GET, _) => Self::handle_get(req),
-            (&Method::POST, "/book/") => Self::handle_post(),
+            (&Method::POST, "/book/") => Self::handle_post(req),
+            (&Method::POST, "/book/_bulk") => Ok(BookRequest::BulkImport),
+            (&Method::PUT, _) => Self::handle_put(req),
+            (&Method::PATCH, _) => Self::handle_patch(req),
+            (&Method::DELETE, _) => Self::handle_delete(req),
             _ => Err(BookServiceError::NotFoundError),
         }
     }
@@ -23,15 +134,141 @@ impl BookRequest {
         let path = req.uri().path();
         if path.starts_with("/book/health") {
             Ok(BookRequest::Health)
+        } else if path == "/book/_events" {
+            Ok(BookRequest::BookEvents)
+        } else if path == "/book/_export" {
+            Self::export_format(req).map(BookRequest::ExportBooks)
+        } else if path == "/book/search" {
+            SearchParams::from_query(&query_params(req)).map(BookRequest::SearchBooks)
+        } else if path == "/book/" || path == "/book" {
+            let params = query_params(req);
+            match params.get("author") {
+                Some(author) => Ok(BookRequest::BooksByAuthor(author.to_owned())),
+                None => ListParams::from_query(&params).map(BookRequest::ListBooks),
+            }
         } else if path.starts_with("/book/") {
-            Self::parse_id(req).map(|uuid| BookRequest::GetBook(uuid))
+            let segments: Vec<&str> = path["/book/".len()..].split('/').collect();
+            match segments.as_slice() {
+                [_] => Self::parse_id(req).map(|uuid| BookRequest::GetBook(uuid)),
+                [id, "history"] => parse_uuid(id).map(BookRequest::BookHistory),
+                [id, "history", revision] => {
+                    let uuid = parse_uuid(id)?;
+                    parse_revision(revision).map(|version| BookRequest::BookRevision(uuid, version))
+                }
+                [id, "diff"] => {
+                    let uuid = parse_uuid(id)?;
+                    DiffParams::from_query(&query_params(req))
+                        .map(|params| BookRequest::DiffRevisions(uuid, params))
+                }
+                _ => Err(BookServiceError::NotFoundError),
+            }
+        } else {
+            Err(BookServiceError::NotFoundError)
+        }
+    }
+
+    fn handle_post(req: &Request<Body>) -> Result<BookRequest, BookServiceError> {
+        Self::parse_idempotency_key(req).map(BookRequest::PostBook)
+    }
+
+    fn handle_put(req: &Request<Body>) -> Result<BookRequest, BookServiceError> {
+        if req.uri().path().starts_with("/book/") {
+            let uuid = Self::parse_id(req)?;
+            Self::parse_if_match(req).map(|version| BookRequest::PutBook(uuid, version))
         } else {
             Err(BookServiceError::NotFoundError)
         }
     }
 
-    fn handle_post() -> Result<BookRequest, BookServiceError> {
-        Ok(BookRequest::PostBook)
+    fn handle_patch(req: &Request<Body>) -> Result<BookRequest, BookServiceError> {
+        if req.uri().path().starts_with("/book/") {
+            let uuid = Self::parse_id(req)?;
+            Self::parse_if_match(req).map(|version| BookRequest::PatchBook(uuid, version))
+        } else {
+            Err(BookServiceError::NotFoundError)
+        }
+    }
+
+    fn handle_delete(req: &Request<Body>) -> Result<BookRequest, BookServiceError> {
+        if req.uri().path().starts_with("/book/") {
+            let uuid = Self::parse_id(req)?;
+            Self::parse_if_match(req).map(|version| BookRequest::DeleteBook(uuid, version))
+        } else {
+            Err(BookServiceError::NotFoundError)
+        }
+    }
+
+    /// The format=csv|ndjson query parameter wins over the Accept header
+    fn export_format(req: &Request<Body>) -> Result<ExportFormat, BookServiceError> {
+        match query_params(req).get("format") {
+            Some(name) => ExportFormat::from_name(name).ok_or_else(|| {
+                BookServiceError::InvalidQueryError(format!("unknown export format {}", name))
+            }),
+            None => Ok(req
+                .headers()
+                .get(ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .map(ExportFormat::from_accept)
+                .unwrap_or(ExportFormat::Ndjson)),
+        }
+    }
+
+    /// Restores are posted to /admin/trash/{id}/restore
+    fn handle_restore(path: &str) -> Result<BookRequest, BookServiceError> {
+        let id = path[TRASH_PATH.len()..]
+            .trim_start_matches('/')
+            .trim_end_matches("/restore");
+        if id.contains('/') || !path.ends_with("/restore") {
+            Err(BookServiceError::NotFoundError)
+        } else {
+            Uuid::parse_str(id)
+                .map(BookRequest::RestoreBook)
+                .map_err(BookServiceError::InvalidUuidError)
+        }
+    }
+
+    /// Parses the version out of an If-Match header, the ETag of a book being its
+    /// quoted version. A missing header or `*` places no condition on the write.
+    fn parse_if_match(req: &Request<Body>) -> Result<Option<u64>, BookServiceError> {
+        match req.headers().get(IF_MATCH) {
+            None => Ok(None),
+            Some(value) => value
+                .to_str()
+                .ok()
+                .map(|tag| tag.trim())
+                .and_then(|tag| match tag {
+                    "*" => Some(None),
+                    _ => tag
+                        .trim_start_matches("W/")
+                        .trim_matches('"')
+                        .parse::<u64>()
+                        .ok()
+                        .map(Some),
+                })
+                .ok_or_else(|| {
+                    BookServiceError::InvalidQueryError(format!("invalid If-Match {:?}", value))
+                }),
+        }
+    }
+
+    /// Reads the Idempotency-Key header, which must be printable ASCII of at most 255
+    /// characters
+    fn parse_idempotency_key(req: &Request<Body>) -> Result<Option<String>, BookServiceError> {
+        match req.headers().get(IDEMPOTENCY_KEY) {
+            None => Ok(None),
+            Some(value) => value
+                .to_str()
+                .ok()
+                .map(str::trim)
+                .filter(|key| !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN)
+                .map(|key| Some(key.to_string()))
+                .ok_or_else(|| {
+                    BookServiceError::InvalidQueryError(format!(
+                        "invalid Idempotency-Key {:?}",
+                        value
+                    ))
+                }),
+        }
     }
 
     /// Parses the uuid off the request path
@@ -46,6 +283,92 @@ impl BookRequest {
     }
 }
 
+/// Who or what is making a change, as sent in the X-Change-Source header
+pub fn change_source(req: &Request<Body>) -> String {
+    req.headers()
+        .get(CHANGE_SOURCE)
+        .and_then(|source| source.to_str().ok())
+        .map(str::trim)
+        .filter(|source| !source.is_empty())
+        .unwrap_or(UNKNOWN_SOURCE)
+        .to_string()
+}
+
+/// The tenant whose catalog the request is for, as sent in the X-Tenant header
+pub fn tenant(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get(TENANT)
+        .and_then(|tenant| tenant.to_str().ok())
+        .map(str::trim)
+        .filter(|tenant| !tenant.is_empty())
+        .map(str::to_string)
+}
+
+fn parse_uuid(id: &str) -> Result<Uuid, BookServiceError> {
+    Uuid::parse_str(id).map_err(BookServiceError::InvalidUuidError)
+}
+
+fn parse_revision(revision: &str) -> Result<u64, BookServiceError> {
+    revision
+        .parse()
+        .map_err(|_| BookServiceError::InvalidQueryError(format!("invalid revision {}", revision)))
+}
+
+/// Splits the query string of the request into decoded name value pairs
+fn query_params(req: &Request<Body>) -> HashMap<String, String> {
+    req.uri()
+        .query()
+        .map(|query| {
+            query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let mut parts = pair.splitn(2, '=');
+                    let name = percent_decode(parts.next().unwrap_or(""));
+                    let value = percent_decode(parts.next().unwrap_or(""));
+                    (name, value)
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Decodes application/x-www-form-urlencoded text, percent escapes and '+' for a space
+fn percent_decode(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        // from_str_radix would take a sign, so both digits are checked first
+        let escaped = if bytes[i] == b'%'
+            && i + 2 < bytes.len()
+            && bytes[i + 1].is_ascii_hexdigit()
+            && bytes[i + 2].is_ascii_hexdigit()
+        {
+            str::from_utf8(&bytes[i + 1..i + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match (bytes[i], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', None) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, None) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -59,7 +382,80 @@ mod tests {
             .body(Body::empty())
             .unwrap();
         let request_type = BookRequest::from_request(&request).unwrap();
-        assert_eq!(BookRequest::PostBook, request_type);
+        assert_eq!(BookRequest::PostBook(None), request_type);
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/book/")
+            .header("Idempotency-Key", " import-42 ")
+            .body(Body::empty())
+            .unwrap();
+        let request_type = BookRequest::from_request(&request).unwrap();
+        assert_eq!(
+            BookRequest::PostBook(Some("import-42".to_string())),
+            request_type
+        );
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/book/")
+            .header(
+                "Idempotency-Key",
+                "x".repeat(MAX_IDEMPOTENCY_KEY_LEN + 1).as_str(),
+            )
+            .body(Body::empty())
+            .unwrap();
+        assert!(BookRequest::from_request(&request).is_err());
+    }
+
+    #[test]
+    fn test_bulk_import() {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/book/_bulk")
+            .body(Body::empty())
+            .unwrap();
+        let request_type = BookRequest::from_request(&request).unwrap();
+        assert_eq!(BookRequest::BulkImport, request_type);
+    }
+
+    #[test]
+    fn test_export() {
+        let request = Request::builder()
+            .method("GET")
+            .uri("/book/_export?format=csv")
+            .header("Accept", "application/x-ndjson")
+            .body(Body::empty())
+            .unwrap();
+        let request_type = BookRequest::from_request(&request).unwrap();
+        assert_eq!(BookRequest::ExportBooks(ExportFormat::Csv), request_type);
+
+        let request = Request::builder()
+            .method("GET")
+            .uri("/book/_export")
+            .header("Accept", "text/csv")
+            .body(Body::empty())
+            .unwrap();
+        let request_type = BookRequest::from_request(&request).unwrap();
+        assert_eq!(BookRequest::ExportBooks(ExportFormat::Csv), request_type);
+
+        let request = Request::builder()
+            .method("GET")
+            .uri("/book/_export?format=xml")
+            .body(Body::empty())
+            .unwrap();
+        assert!(BookRequest::from_request(&request).is_err());
+    }
+
+    #[test]
+    fn test_events() {
+        let request = Request::builder()
+            .method("GET")
+            .uri("/book/_events")
+            .body(Body::empty())
+            .unwrap();
+        let request_type = BookRequest::from_request(&request).unwrap();
+        assert_eq!(BookRequest::BookEvents, request_type);
     }
 
     #[test]
@@ -76,4 +472,272 @@ mod tests {
         let request_type = BookRequest::from_request(&request).unwrap();
         assert_eq!(BookRequest::GetBook(uuid), request_type);
     }
+
+    #[test]
+    fn test_list() {
+        let request = Request::builder()
+            .method("GET")
+            .uri("/book/?limit=5&cursor=MTIz")
+            .body(Body::empty())
+            .unwrap();
+        let request_type = BookRequest::from_request(&request).unwrap();
+        let expected = ListParams {
+            cursor: Some("MTIz".to_string()),
+            limit: 5,
+        };
+        assert_eq!(BookRequest::ListBooks(expected), request_type);
+
+        let request = Request::builder()
+            .method("GET")
+            .uri("/book/")
+            .body(Body::empty())
+            .unwrap();
+        let request_type = BookRequest::from_request(&request).unwrap();
+        let expected = ListParams {
+            cursor: None,
+            limit: DEFAULT_LIMIT,
+        };
+        assert_eq!(BookRequest::ListBooks(expected), request_type);
+
+        let request = Request::builder()
+            .method("GET")
+            .uri("/book/?limit=0")
+            .body(Body::empty())
+            .unwrap();
+        assert!(BookRequest::from_request(&request).is_err());
+    }
+
+    #[test]
+    fn test_books_by_author() {
+        let request = Request::builder()
+            .method("GET")
+            .uri("/book/?author=Robert%20Jordan")
+            .body(Body::empty())
+            .unwrap();
+        let request_type = BookRequest::from_request(&request).unwrap();
+        assert_eq!(
+            BookRequest::BooksByAuthor("Robert Jordan".to_string()),
+            request_type
+        );
+    }
+
+    #[test]
+    fn test_search() {
+        let request = Request::builder()
+            .method("GET")
+            .uri("/book/search?q=eye+of+the+world&limit=5")
+            .body(Body::empty())
+            .unwrap();
+        let request_type = BookRequest::from_request(&request).unwrap();
+        let expected = SearchParams {
+            query: "eye of the world".to_string(),
+            limit: 5,
+        };
+        assert_eq!(BookRequest::SearchBooks(expected), request_type);
+
+        let request = Request::builder()
+            .method("GET")
+            .uri("/book/search")
+            .body(Body::empty())
+            .unwrap();
+        assert!(BookRequest::from_request(&request).is_err());
+    }
+
+    #[test]
+    fn test_cache_stats() {
+        let request = Request::builder()
+            .method("GET")
+            .uri("/admin/cache")
+            .body(Body::empty())
+            .unwrap();
+        let request_type = BookRequest::from_request(&request).unwrap();
+        assert_eq!(BookRequest::CacheStats, request_type);
+    }
+
+    #[test]
+    fn test_trash() {
+        let request = Request::builder()
+            .method("GET")
+            .uri("/admin/trash")
+            .body(Body::empty())
+            .unwrap();
+        let request_type = BookRequest::from_request(&request).unwrap();
+        assert_eq!(BookRequest::ListTrash, request_type);
+
+        let uuid = Uuid::new_v4();
+        let request = Request::builder()
+            .method("POST")
+            .uri(format!("/admin/trash/{}/restore", uuid.hyphenated()))
+            .body(Body::empty())
+            .unwrap();
+        let request_type = BookRequest::from_request(&request).unwrap();
+        assert_eq!(BookRequest::RestoreBook(uuid), request_type);
+
+        let request = Request::builder()
+            .method("POST")
+            .uri(format!("/admin/trash/{}", uuid.hyphenated()))
+            .body(Body::empty())
+            .unwrap();
+        assert!(BookRequest::from_request(&request).is_err());
+    }
+
+    #[test]
+    fn test_history() {
+        let uuid = Uuid::new_v4();
+        let base = format!("/book/{}", uuid.hyphenated());
+        let cases = vec![
+            (format!("{}/history", base), BookRequest::BookHistory(uuid)),
+            (
+                format!("{}/history/3", base),
+                BookRequest::BookRevision(uuid, 3),
+            ),
+            (
+                format!("{}/diff?from=1&to=3", base),
+                BookRequest::DiffRevisions(
+                    uuid,
+                    DiffParams {
+                        from: 1,
+                        to: Some(3),
+                    },
+                ),
+            ),
+            (
+                format!("{}/diff?from=1", base),
+                BookRequest::DiffRevisions(uuid, DiffParams { from: 1, to: None }),
+            ),
+        ];
+        for (uri, expected) in cases {
+            let request = Request::builder()
+                .method("GET")
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(expected, BookRequest::from_request(&request).unwrap());
+        }
+
+        for uri in &[format!("{}/history/latest", base), format!("{}/diff", base)] {
+            let request = Request::builder()
+                .method("GET")
+                .uri(uri.as_str())
+                .body(Body::empty())
+                .unwrap();
+            assert!(BookRequest::from_request(&request).is_err());
+        }
+    }
+
+    #[test]
+    fn test_change_source() {
+        let request = Request::builder()
+            .method("PUT")
+            .uri("/book/")
+            .header("X-Change-Source", "catalogue-import")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!("catalogue-import", change_source(&request));
+
+        let request = Request::builder()
+            .method("PUT")
+            .uri("/book/")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(UNKNOWN_SOURCE, change_source(&request));
+    }
+
+    #[test]
+    fn test_tenant() {
+        let request = Request::builder()
+            .method("GET")
+            .uri("/book/")
+            .header("X-Tenant", " shop-a ")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(Some("shop-a".to_string()), tenant(&request));
+
+        let request = Request::builder()
+            .method("GET")
+            .uri("/book/")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(None, tenant(&request));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!("Robert Jordan", percent_decode("Robert+Jordan"));
+        assert_eq!("Robert Jordan", percent_decode("Robert%20Jordan"));
+        assert_eq!("Gabriel García", percent_decode("Gabriel%20Garc%C3%ADa"));
+        assert_eq!("100%", percent_decode("100%"));
+        assert_eq!("% 1", percent_decode("%+1"));
+    }
+
+    #[test]
+    fn test_put() {
+        let uuid = Uuid::new_v4();
+        let url_str = "/book/".to_owned() + uuid.hyphenated().to_string().as_ref();
+
+        let request = Request::builder()
+            .method("PUT")
+            .uri(url_str)
+            .body(Body::empty())
+            .unwrap();
+
+        let request_type = BookRequest::from_request(&request).unwrap();
+        assert_eq!(BookRequest::PutBook(uuid, None), request_type);
+
+        let request = Request::builder()
+            .method("PUT")
+            .uri("/book/".to_owned() + uuid.hyphenated().to_string().as_ref())
+            .header("If-Match", "\"3\"")
+            .body(Body::empty())
+            .unwrap();
+        let request_type = BookRequest::from_request(&request).unwrap();
+        assert_eq!(BookRequest::PutBook(uuid, Some(3)), request_type);
+    }
+
+    #[test]
+    fn test_patch() {
+        let uuid = Uuid::new_v4();
+        let url_str = "/book/".to_owned() + uuid.hyphenated().to_string().as_ref();
+
+        let request = Request::builder()
+            .method("PATCH")
+            .uri(url_str)
+            .body(Body::empty())
+            .unwrap();
+
+        let request_type = BookRequest::from_request(&request).unwrap();
+        assert_eq!(BookRequest::PatchBook(uuid, None), request_type);
+
+        let request = Request::builder()
+            .method("PATCH")
+            .uri("/book/".to_owned() + uuid.hyphenated().to_string().as_ref())
+            .header("If-Match", "*")
+            .body(Body::empty())
+            .unwrap();
+        let request_type = BookRequest::from_request(&request).unwrap();
+        assert_eq!(BookRequest::PatchBook(uuid, None), request_type);
+    }
+
+    #[test]
+    fn test_delete() {
+        let uuid = Uuid::new_v4();
+        let url_str = "/book/".to_owned() + uuid.hyphenated().to_string().as_ref();
+
+        let request = Request::builder()
+            .method("DELETE")
+            .uri(url_str)
+            .body(Body::empty())
+            .unwrap();
+
+        let request_type = BookRequest::from_request(&request).unwrap();
+        assert_eq!(BookRequest::DeleteBook(uuid, None), request_type);
+
+        let request = Request::builder()
+            .method("DELETE")
+            .uri("/book/".to_owned() + uuid.hyphenated().to_string().as_ref())
+            .header("If-Match", "not-a-version")
+            .body(Body::empty())
+            .unwrap();
+        assert!(BookRequest::from_request(&request).is_err());
+    }
 }
@@ -0,0 +1,88 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use crate::dao::VERSION;
+
+/// The hash field holding the schema a record was written with
+pub const SCHEMA: &'static str = "schema";
+/// The schema records are written with, older records are migrated up to it
+pub const SCHEMA_VERSION: u32 = 1;
+
+type Record = HashMap<String, String>;
+/// Upgrades a record from one schema to the next
+type Migration = fn(&mut Record);
+
+/// The migrations in order, the one at index n upgrades a record from schema n to n + 1.
+/// Records written before the schema was recorded are at schema 0.
+const MIGRATIONS: &[Migration] = &[add_version];
+
+/// Books written before versioning was introduced are at version 0
+fn add_version(record: &mut Record) {
+    record
+        .entry(VERSION.to_string())
+        .or_insert_with(|| "0".to_string());
+}
+
+/// The schema the record was written with
+pub fn record_schema(record: &Record) -> u32 {
+    record
+        .get(SCHEMA)
+        .and_then(|schema| schema.parse().ok())
+        .unwrap_or(0)
+}
+
+/// True for a stored record written with an older schema than the current one
+pub fn is_outdated(record: &Record) -> bool {
+    !record.is_empty() && record_schema(record) < SCHEMA_VERSION
+}
+
+/// The record brought up to the current schema by the migrations it has not been
+/// through yet. Current records, and those written by a newer service, are left alone.
+pub fn migrate(record: &Record) -> Cow<'_, Record> {
+    if !is_outdated(record) {
+        return Cow::Borrowed(record);
+    }
+    let mut migrated = record.to_owned();
+    for migration in MIGRATIONS.iter().skip(record_schema(record) as usize) {
+        migration(&mut migrated);
+    }
+    migrated.insert(SCHEMA.to_string(), SCHEMA_VERSION.to_string());
+    Cow::Owned(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dao::{AUTHOR, TITLE};
+
+    fn record(fields: &[(&str, &str)]) -> Record {
+        fields
+            .iter()
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_migrations_reach_current_schema() {
+        assert_eq!(SCHEMA_VERSION as usize, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn test_migrate() {
+        let unversioned = record(&[(AUTHOR, "Robert Jordan"), (TITLE, "Eye of the World")]);
+        assert!(is_outdated(&unversioned));
+        let migrated = migrate(&unversioned);
+        assert_eq!(Some(&"0".to_string()), migrated.get(VERSION));
+        assert_eq!(SCHEMA_VERSION, record_schema(&migrated));
+        assert!(!is_outdated(&migrated));
+
+        let current = record(&[(AUTHOR, "Robert Jordan"), (VERSION, "3"), (SCHEMA, "1")]);
+        assert_eq!(current, migrate(&current).into_owned());
+
+        let newer = record(&[(AUTHOR, "Robert Jordan"), (SCHEMA, "7")]);
+        assert!(!is_outdated(&newer));
+        assert_eq!(newer, migrate(&newer).into_owned());
+
+        assert!(!is_outdated(&Record::new()));
+    }
+}
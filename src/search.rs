@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Words too common to be worth an index entry
+const STOP_WORDS: &'static [&'static str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "by", "for", "from", "in", "into", "is", "it", "of",
+    "on", "or", "that", "the", "to", "with",
+];
+
+/// Splits text into the distinct lowercase terms it is indexed under.
+/// Word boundaries follow Unicode (UAX #29) and stop words are dropped.
+pub fn tokenise(text: &str) -> Vec<String> {
+    let mut terms: Vec<String> = text
+        .unicode_words()
+        .map(str::to_lowercase)
+        .filter(|term| !STOP_WORDS.contains(&term.as_ref()))
+        .collect();
+    terms.sort();
+    terms.dedup();
+    terms
+}
+
+/// Ranks the ids found under each query term by how many terms matched them.
+/// Ids in the intersection of every term set come first; ties are ordered by id
+/// so that results are stable between calls.
+pub fn rank(term_matches: &[Vec<String>]) -> Vec<String> {
+    let mut overlap: HashMap<&String, usize> = HashMap::new();
+    for id in term_matches.iter().flat_map(|ids| ids.iter()) {
+        *overlap.entry(id).or_insert(0) += 1;
+    }
+    let mut ranked: Vec<(&String, usize)> = overlap.into_iter().collect();
+    ranked.sort_by(|(a_id, a_count), (b_id, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_id.cmp(b_id))
+    });
+    ranked.into_iter().map(|(id, _)| id.to_owned()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenise() {
+        assert_eq!(
+            vec!["bell", "tolls", "whom"],
+            tokenise("For Whom the Bell Tolls")
+        );
+        assert_eq!(
+            vec!["años", "cien", "de", "soledad"],
+            tokenise("Cien Años de Soledad")
+        );
+        assert_eq!(vec!["world"], tokenise("The World, the world!"));
+    }
+
+    #[test]
+    fn test_rank() {
+        let matches = vec![
+            vec!["b".to_string(), "c".to_string()],
+            vec!["a".to_string(), "c".to_string()],
+        ];
+        assert_eq!(vec!["c", "a", "b"], rank(&matches));
+    }
+}
@@ -0,0 +1,215 @@
+use redis::{self, RedisResult};
+use std::thread;
+use std::time::Duration;
+
+use crate::errors::BookServiceError;
+use crate::settings::Settings;
+
+/// The channel Sentinels announce a promoted master on, as
+/// `<master name> <old ip> <old port> <new ip> <new port>`
+const SWITCH_MASTER_CHANNEL: &'static str = "+switch-master";
+/// How long to listen to a quiet Sentinel before asking for the master again, so a
+/// failover announced while the subscription was down is not missed for long
+const MASTER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How long to wait before trying the next Sentinel once one is lost
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The Sentinels watching a Redis master, asked for its address in turn until one of
+/// them knows it
+#[derive(Debug, Clone)]
+pub struct Sentinel {
+    addresses: Vec<String>,
+    master_name: String,
+}
+
+impl Sentinel {
+    /// The Sentinels configured in BOOKSTORE_REDISSENTINELS, None when Redis is reached
+    /// directly
+    pub fn from_settings(settings: &Settings) -> Option<Sentinel> {
+        let addresses = settings.redis_sentinels();
+        if addresses.is_empty() {
+            None
+        } else {
+            Some(Sentinel {
+                addresses,
+                master_name: settings.redis_master_name().to_string(),
+            })
+        }
+    }
+
+    /// The host and port of the current master, from the first Sentinel that knows it
+    pub fn master_address(&self) -> Result<(String, u32), BookServiceError> {
+        for address in &self.addresses {
+            match self.ask_master_address(address) {
+                Ok(Some(master)) => return Ok(master),
+                Ok(None) => eprintln!(
+                    "Sentinel {} does not monitor {}",
+                    address, &self.master_name
+                ),
+                Err(e) => eprintln!("Could not ask Sentinel {} for the master {}", address, e),
+            }
+        }
+        Err(BookServiceError::SentinelError(self.master_name.to_owned()))
+    }
+
+    fn ask_master_address(&self, address: &str) -> RedisResult<Option<(String, u32)>> {
+        let conn = redis::Client::open(sentinel_url(address).as_ref())?.get_connection()?;
+        redis::cmd("SENTINEL")
+            .arg("get-master-addr-by-name")
+            .arg(&self.master_name)
+            .query(&conn)
+    }
+
+    /// Follows the master through failovers, starting from the one at `master`, calling
+    /// on_failover with the address of every newly promoted one. Listens to one Sentinel
+    /// at a time and moves on to the next when it goes away, checking the master before
+    /// each subscription. A master on_failover fails to switch to is tried again on the
+    /// next check. Never returns, meant to run on a thread of its own.
+    pub fn follow_master<F>(&self, master: (String, u32), on_failover: F)
+    where
+        F: Fn(&str, u32) -> Result<(), BookServiceError>,
+    {
+        let mut master = master;
+        for address in self.addresses.iter().cycle() {
+            self.check_master(&mut master, &on_failover);
+            if let Err(e) = self.listen(address, &mut master, &on_failover) {
+                eprintln!("Stopped listening to Sentinel {} {}", address, e);
+            }
+            thread::sleep(RECONNECT_DELAY);
+        }
+    }
+
+    /// Asks for the master, calling on_failover when it is not the one followed so far
+    fn check_master<F>(&self, master: &mut (String, u32), on_failover: &F)
+    where
+        F: Fn(&str, u32) -> Result<(), BookServiceError>,
+    {
+        if let Ok(current) = self.master_address() {
+            switch_master(master, current, on_failover);
+        }
+    }
+
+    /// Listens for failovers on one Sentinel until its connection fails, checking the
+    /// master whenever it stays quiet for MASTER_CHECK_INTERVAL
+    fn listen<F>(
+        &self,
+        address: &str,
+        master: &mut (String, u32),
+        on_failover: &F,
+    ) -> RedisResult<()>
+    where
+        F: Fn(&str, u32) -> Result<(), BookServiceError>,
+    {
+        let client = redis::Client::open(sentinel_url(address).as_ref())?;
+        let mut pubsub = client.get_pubsub()?;
+        pubsub.set_read_timeout(Some(MASTER_CHECK_INTERVAL))?;
+        pubsub.subscribe(SWITCH_MASTER_CHANNEL)?;
+        println!("Listening to Sentinel {} for failovers", address);
+        loop {
+            let message = match pubsub.get_message() {
+                Ok(message) => message,
+                Err(ref e) if e.is_timeout() => {
+                    self.check_master(master, on_failover);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let payload: String = message.get_payload()?;
+            if let Some(promoted) = parse_switch_master(&payload, &self.master_name) {
+                println!(
+                    "Sentinel {} reports {} failed over to {}:{}",
+                    address, &self.master_name, &promoted.0, promoted.1
+                );
+                switch_master(master, promoted, on_failover);
+            }
+        }
+    }
+}
+
+/// Hands a master other than the followed one to on_failover, following it from then on
+/// only once on_failover has switched to it
+fn switch_master<F>(master: &mut (String, u32), promoted: (String, u32), on_failover: &F)
+where
+    F: Fn(&str, u32) -> Result<(), BookServiceError>,
+{
+    if &promoted == master {
+        return;
+    }
+    match on_failover(&promoted.0, promoted.1) {
+        Ok(()) => *master = promoted,
+        Err(e) => eprintln!(
+            "Could not switch to master {}:{}, trying again on the next check {}",
+            &promoted.0, promoted.1, e
+        ),
+    }
+}
+
+/// The address of the new master in a +switch-master message, None when the message
+/// is about another master or can not be understood
+fn parse_switch_master(payload: &str, master_name: &str) -> Option<(String, u32)> {
+    let fields: Vec<&str> = payload.split_whitespace().collect();
+    if fields.len() != 5 || fields[0] != master_name {
+        return None;
+    }
+    fields[4]
+        .parse()
+        .ok()
+        .map(|port| (fields[3].to_string(), port))
+}
+
+fn sentinel_url(address: &str) -> String {
+    format!("redis://{}", address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_switch_master() {
+        assert_eq!(
+            Some(("10.0.0.8".to_string(), 6380)),
+            parse_switch_master("mymaster 10.0.0.7 6379 10.0.0.8 6380", "mymaster")
+        );
+        assert_eq!(
+            None,
+            parse_switch_master("othermaster 10.0.0.7 6379 10.0.0.8 6380", "mymaster")
+        );
+        assert_eq!(
+            None,
+            parse_switch_master("mymaster 10.0.0.7 6379", "mymaster")
+        );
+        assert_eq!(
+            None,
+            parse_switch_master("mymaster 10.0.0.7 6379 10.0.0.8 port", "mymaster")
+        );
+    }
+
+    #[test]
+    fn test_switch_master() {
+        let old = ("10.0.0.7".to_string(), 6379);
+        let promoted = ("10.0.0.8".to_string(), 6380);
+        let mut master = old.clone();
+
+        switch_master(&mut master, promoted.clone(), &|_: &str, _: u32| {
+            Err(BookServiceError::SentinelError("mymaster".to_string()))
+        });
+        assert_eq!(old, master);
+
+        switch_master(&mut master, promoted.clone(), &|_: &str, _: u32| Ok(()));
+        assert_eq!(promoted, master);
+    }
+
+    #[test]
+    fn test_from_settings() {
+        assert!(Sentinel::from_settings(&Settings::default()).is_none());
+        let sentinel = Sentinel::from_settings(
+            &Settings::default()
+                .with_redis_sentinels("localhost:26379")
+                .with_redis_master_name("books"),
+        )
+        .unwrap();
+        assert_eq!(vec!["localhost:26379"], sentinel.addresses);
+        assert_eq!("books", sentinel.master_name);
+    }
+}
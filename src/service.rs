@@ -1,92 +1,521 @@
 use futures::{future, Future, Stream};
-use hyper::header::CONTENT_TYPE;
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE, ETAG};
 use hyper::{Body, Request, Response};
+use serde::Serialize;
 use std::convert::From;
 use std::error::Error;
+use std::sync::Arc;
 use std::{io, str};
+use uuid::Uuid;
 
-use crate::dao::BookDao;
 use crate::errors::BookServiceError;
-use crate::model::Book;
-use crate::request::BookRequest;
+use crate::export;
+use crate::import;
+use crate::model::{Book, BookPage, IdempotentResponse, RevisionDiff, VersionedBook};
+use crate::request::{self, BookRequest, DiffParams};
 use crate::settings::Settings;
+use crate::store::BookStore;
+use crate::tenant::Catalogs;
 
 type BookSvcFuture = Box<dyn Future<Item = Response<Body>, Error = io::Error> + Send>;
 
+/// Marks a response replayed for a repeated Idempotency-Key
+const IDEMPOTENT_REPLAYED: &'static str = "idempotent-replayed";
+/// How long a key stays reserved for a request still being answered, so that one lost
+/// along with its process does not hold the key for the whole idempotency ttl
+const PENDING_TTL_SECS: u64 = 30;
+
 #[derive(Debug, Clone)]
 pub struct BookService {
-    dao: BookDao,
+    catalogs: Arc<Catalogs>,
+    idempotency_ttl_secs: u64,
 }
 
 impl BookService {
+    /// Creates the service over the storage backend selected in the settings
     pub fn new(settings: &Settings) -> Result<Self, BookServiceError> {
-        BookDao::new(settings).map(|dao| BookService { dao })
+        Catalogs::new(settings).map(|catalogs| BookService {
+            catalogs: Arc::new(catalogs),
+            idempotency_ttl_secs: settings.idempotency_ttl_secs(),
+        })
+    }
+
+    pub fn with_store(store: Arc<dyn BookStore>) -> Self {
+        BookService {
+            catalogs: Arc::new(Catalogs::Single(store)),
+            idempotency_ttl_secs: Settings::default().idempotency_ttl_secs(),
+        }
+    }
+
+    /// The store of every catalog served
+    pub fn stores(&self) -> Vec<Arc<dyn BookStore>> {
+        self.catalogs.stores()
     }
 
     pub fn service(&self, req: Request<Body>) -> BookSvcFuture {
         println!("Received: {} {}", req.method(), &req.uri().path());
+        let source = request::change_source(&req);
 
         match BookRequest::from_request(&req) {
-            Ok(BookRequest::GetBook(uuid)) => {
+            Ok(BookRequest::Health) => Box::new(future::ok(health_response())),
+            // every other request is for the catalog of the tenant it names
+            Ok(book_request) => {
+                let tenant = request::tenant(&req);
+                match self.catalogs.resolve(tenant.as_ref().map(String::as_str)) {
+                    Ok(store) => self.catalog_service(store, book_request, req, source),
+                    Err(e) => {
+                        debug!("Path {} : unknown tenant {:?}", req.uri().path(), &tenant);
+                        Box::new(future::ok(error_response(e)))
+                    }
+                }
+            }
+            Err(BookServiceError::NotFoundError) => {
+                debug!("Path {} : NotFoundError", req.uri().path());
+                Box::new(future::ok(
+                    Response::builder().status(404).body(Body::empty()).unwrap(),
+                ))
+            }
+            Err(_) => {
+                debug!("Path {} : NotFoundError", req.uri().path());
+                Box::new(future::ok(bad_request()))
+            }
+        }
+    }
+
+    /// Serves a request against the store of its catalog
+    fn catalog_service(
+        &self,
+        store: Arc<dyn BookStore>,
+        book_request: BookRequest,
+        req: Request<Body>,
+        source: String,
+    ) -> BookSvcFuture {
+        match book_request {
+            BookRequest::GetBook(uuid) => {
                 println!("Retrieving GET {}", &uuid);
-                let result = self
-                    .dao
-                    .get(&uuid)
-                    .and_then(|book| book.to_vec())
-                    .map(Body::from)
-                    .map(|v| {
+                // trashed books are not found, missing ones keep failing on their fields
+                let response = store
+                    .get_versioned(&uuid)
+                    .and_then(|ref versioned| versioned_response(versioned))
+                    .unwrap_or_else(error_response);
+
+                Box::new(future::ok(response))
+            }
+            BookRequest::ListBooks(params) => {
+                println!("Listing books {:?}", &params);
+                let response = store
+                    .list(params.cursor.as_ref().map(String::as_ref), params.limit)
+                    .and_then(|ref page| json_response(page))
+                    .unwrap_or_else(error_response);
+
+                Box::new(future::ok(response))
+            }
+            BookRequest::BooksByAuthor(author) => {
+                println!("Finding books by author {}", &author);
+                let response = store
+                    .find_by_author(&author)
+                    .map(|books| BookPage { books, next: None })
+                    .and_then(|ref page| json_response(page))
+                    .unwrap_or_else(error_response);
+
+                Box::new(future::ok(response))
+            }
+            BookRequest::ExportBooks(format) => {
+                println!("Exporting books as {:?}", &format);
+                let body = Body::wrap_stream(export::export_stream(store, format));
+                let response = Response::builder()
+                    .header(CONTENT_TYPE, format.content_type())
+                    .body(body)
+                    .unwrap();
+
+                Box::new(future::ok(response))
+            }
+            BookRequest::BookEvents => {
+                println!("Subscribing to book events");
+                // without a backend publishing events there is nothing to subscribe to
+                let response = store
+                    .events()
+                    .unwrap_or(Err(BookServiceError::NotFoundError))
+                    .map(|events| {
                         Response::builder()
-                            .header(CONTENT_TYPE, "application/json")
-                            .body(v)
+                            .header(CONTENT_TYPE, "text/event-stream")
+                            .header(CACHE_CONTROL, "no-cache")
+                            .body(Body::wrap_stream(events))
                             .unwrap()
                     })
-                    .or_else(|err: BookServiceError| server_error(err.description()))
-                    .map_err(From::from);
+                    .unwrap_or_else(error_response);
 
-                Box::new(future::result(result))
+                Box::new(future::ok(response))
             }
-            Ok(BookRequest::PostBook) => {
+            BookRequest::SearchBooks(params) => {
+                println!("Searching books {:?}", &params);
+                let response = store
+                    .search(&params.query, params.limit)
+                    .map(|books| BookPage { books, next: None })
+                    .and_then(|ref page| json_response(page))
+                    .unwrap_or_else(error_response);
+
+                Box::new(future::ok(response))
+            }
+            BookRequest::PostBook(idempotency_key) => {
                 println!("Processing POST - creating book");
-                let dao = self.dao.to_owned();
+                let ttl_secs = self.idempotency_ttl_secs;
                 let f = req
                     .into_body()
                     .concat2()
                     .map(move |body| {
                         println!("POST body {:?}", str::from_utf8(body.as_ref()));
 
+                        match idempotency_key {
+                            Some(ref key) => idempotent_post(
+                                store.as_ref(),
+                                key,
+                                body.as_ref(),
+                                &source,
+                                ttl_secs,
+                            ),
+                            None => post_book(store.as_ref(), body.as_ref(), &source)
+                                .map(|ref posted| posted_response(posted))
+                                .unwrap_or_else(|_| bad_request()),
+                        }
+                    })
+                    .or_else(|err| server_error(err.description()))
+                    .map_err(From::from);
+                Box::new(f)
+            }
+            BookRequest::BulkImport => {
+                println!("Processing bulk import");
+                let f = req
+                    .into_body()
+                    .concat2()
+                    .map(move |body| {
+                        import::import(store.as_ref(), body.as_ref(), &source)
+                            .and_then(|ref report| json_response(report))
+                            .unwrap_or_else(error_response)
+                    })
+                    .or_else(|err| server_error(err.description()))
+                    .map_err(From::from);
+                Box::new(f)
+            }
+            BookRequest::PutBook(uuid, expected_version) => {
+                println!("Processing PUT {} - replacing book", &uuid);
+                let f = req
+                    .into_body()
+                    .concat2()
+                    .map(move |body| {
+                        println!("PUT body {:?}", str::from_utf8(body.as_ref()));
+
                         Book::from_slice(body.as_ref())
-                            .and_then(|ref book| dao.put(book).map(|_| 202))
-                            .unwrap_or(400)
+                            .and_then(|book| check_book_id(&uuid, book))
+                            .and_then(|book| {
+                                store
+                                    .update(&book, expected_version, &source)?
+                                    .map(|version| VersionedBook { book, version })
+                                    .ok_or(BookServiceError::NotFoundError)
+                            })
+                            .and_then(|ref versioned| versioned_response(versioned))
+                            .unwrap_or_else(error_response)
+                    })
+                    .or_else(|err| server_error(err.description()))
+                    .map_err(From::from);
+                Box::new(f)
+            }
+            BookRequest::PatchBook(uuid, expected_version) => {
+                println!("Processing PATCH {} - merging book", &uuid);
+                let f = req
+                    .into_body()
+                    .concat2()
+                    .map(move |body| {
+                        println!("PATCH body {:?}", str::from_utf8(body.as_ref()));
+
+                        serde_json::from_slice(body.as_ref())
+                            .map_err(BookServiceError::BookParseError)
+                            .and_then(|ref patch| {
+                                store.patch(&uuid, patch, expected_version, &source)
+                            })
+                            .and_then(|ref versioned| versioned_response(versioned))
+                            .unwrap_or_else(error_response)
                     })
+                    .or_else(|err| server_error(err.description()))
+                    .map_err(From::from);
+                Box::new(f)
+            }
+            BookRequest::DeleteBook(uuid, expected_version) => {
+                println!("Processing DELETE {}", &uuid);
+                let response = store
+                    .delete(&uuid, expected_version, &source)
+                    .map(|removed| if removed { 204 } else { 404 })
                     .map(|status_code| {
                         Response::builder()
                             .status(status_code)
                             .body(Body::empty())
                             .unwrap()
                     })
-                    .or_else(|err| server_error(err.description()))
-                    .map_err(From::from);
-                Box::new(f)
+                    .unwrap_or_else(error_response);
+
+                Box::new(future::ok(response))
             }
-            Ok(BookRequest::Health) => {
-                println!("Processing health request");
-                let response = Response::builder().status(200).body(Body::empty()).unwrap();
+            BookRequest::ListTrash => {
+                println!("Listing trash");
+                let response = store
+                    .list_trash()
+                    .and_then(|ref trash| json_response(trash))
+                    .unwrap_or_else(error_response);
+
                 Box::new(future::ok(response))
             }
-            Err(BookServiceError::NotFoundError) => {
-                debug!("Path {} : NotFoundError", req.uri().path());
-                Box::new(future::ok(
-                    Response::builder().status(404).body(Body::empty()).unwrap(),
-                ))
+            BookRequest::CacheStats => {
+                println!("Retrieving cache stats");
+                // without a cache there is nothing to report
+                let response = store
+                    .cache_stats()
+                    .ok_or(BookServiceError::NotFoundError)
+                    .and_then(|ref stats| json_response(stats))
+                    .unwrap_or_else(error_response);
+
+                Box::new(future::ok(response))
             }
-            Err(_) => {
-                debug!("Path {} : NotFoundError", req.uri().path());
-                Box::new(future::ok(bad_request()))
+            BookRequest::RestoreBook(uuid) => {
+                println!("Restoring book {}", &uuid);
+                let response = store
+                    .restore(&uuid, &source)
+                    .and_then(|restored| restored.ok_or(BookServiceError::NotFoundError))
+                    .and_then(|ref versioned| versioned_response(versioned))
+                    .unwrap_or_else(error_response);
+
+                Box::new(future::ok(response))
+            }
+            BookRequest::BookHistory(uuid) => {
+                println!("Retrieving history of {}", &uuid);
+                let response = store
+                    .history(&uuid)
+                    .and_then(|revisions| {
+                        if revisions.is_empty() {
+                            Err(BookServiceError::NotFoundError)
+                        } else {
+                            json_response(&revisions)
+                        }
+                    })
+                    .unwrap_or_else(error_response);
+
+                Box::new(future::ok(response))
+            }
+            BookRequest::BookRevision(uuid, version) => {
+                println!("Retrieving revision {} of {}", version, &uuid);
+                let response = store
+                    .revision(&uuid, version)
+                    .and_then(|ref revision| json_response(revision))
+                    .unwrap_or_else(error_response);
+
+                Box::new(future::ok(response))
+            }
+            BookRequest::DiffRevisions(uuid, params) => {
+                println!("Comparing revisions of {} {:?}", &uuid, &params);
+                let response = diff_revisions(store.as_ref(), &uuid, &params)
+                    .and_then(|ref diff| json_response(diff))
+                    .unwrap_or_else(error_response);
+
+                Box::new(future::ok(response))
+            }
+            BookRequest::Health => Box::new(future::ok(health_response())),
+        }
+    }
+}
+
+/// Compares two revisions of the book, the latest one standing in for a missing `to`
+fn diff_revisions(
+    store: &dyn BookStore,
+    uuid: &Uuid,
+    params: &DiffParams,
+) -> Result<RevisionDiff, BookServiceError> {
+    let history = store.history(uuid)?;
+    let find = |version: u64| {
+        history
+            .iter()
+            .find(|revision| revision.version == version)
+            .ok_or(BookServiceError::NotFoundError)
+    };
+    let from = find(params.from)?;
+    let to = match params.to {
+        Some(version) => find(version)?,
+        None => history.last().ok_or(BookServiceError::NotFoundError)?,
+    };
+    Ok(from.diff(to))
+}
+
+/// Creates the book in a POST body. A body that is not a book is answered with a 400,
+/// failing to store the book is left as an error.
+fn post_book(
+    store: &dyn BookStore,
+    body: &[u8],
+    source: &str,
+) -> Result<IdempotentResponse, BookServiceError> {
+    match Book::from_slice(body) {
+        Ok(ref book) => store
+            .put(book, source)
+            .map(|version| IdempotentResponse::new(body, 202, Some(version))),
+        Err(_) => Ok(IdempotentResponse::new(body, 400, None)),
+    }
+}
+
+/// Replays the saved response when the key has been seen with the same body, and
+/// otherwise reserves the key, creates the book and saves the response. A retry sent
+/// while the first request is still being answered gets a 409, for PENDING_TTL_SECS at
+/// most. Failed writes drop the reservation so that a retry can succeed.
+fn idempotent_post(
+    store: &dyn BookStore,
+    key: &str,
+    body: &[u8],
+    source: &str,
+    ttl_secs: u64,
+) -> Response<Body> {
+    let reserved = store
+        .idempotent_response(key)
+        .and_then(|saved| match saved {
+            Some(saved) => Ok(Some(saved)),
+            None => store
+                .save_idempotent_response(
+                    key,
+                    &IdempotentResponse::pending(body),
+                    PENDING_TTL_SECS.min(ttl_secs),
+                )
+                .and_then(|reserved| {
+                    if reserved {
+                        Ok(None)
+                    } else {
+                        // another request reserved the key in between
+                        store
+                            .idempotent_response(key)
+                            .map(|saved| saved.or_else(|| Some(IdempotentResponse::pending(body))))
+                    }
+                }),
+        });
+    match reserved {
+        Ok(Some(ref saved)) => saved_response(key, saved, body),
+        Ok(None) => match post_book(store, body, source) {
+            Ok(posted) => {
+                // the book is written, so a failure to save is only logged
+                let _ = store.replace_idempotent_response(key, &posted, ttl_secs);
+                posted_response(&posted)
+            }
+            Err(e) => {
+                let _ = store.remove_idempotent_response(key);
+                error_response(e)
             }
+        },
+        Err(e) => error_response(e),
+    }
+}
+
+/// Answers a request with a key that has been seen before
+fn saved_response(key: &str, saved: &IdempotentResponse, body: &[u8]) -> Response<Body> {
+    if !saved.matches(body) {
+        error_response(BookServiceError::IdempotencyKeyReusedError(key.to_string()))
+    } else if saved.is_pending() {
+        error_response(BookServiceError::IdempotencyKeyInFlightError(
+            key.to_string(),
+        ))
+    } else {
+        println!("Replaying response for Idempotency-Key {}", key);
+        let mut response = posted_response(saved);
+        response
+            .headers_mut()
+            .insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+        response
+    }
+}
+
+fn posted_response(posted: &IdempotentResponse) -> Response<Body> {
+    let mut response = Response::builder()
+        .status(posted.status)
+        .body(Body::from(posted.body.clone()))
+        .unwrap();
+    if let Some(version) = posted.version {
+        response.headers_mut().insert(ETAG, etag(version));
+    }
+    response
+}
+
+/// Guards against a body that would write to a different book than the path names
+fn check_book_id(uuid: &Uuid, book: Book) -> Result<Book, BookServiceError> {
+    if &book.book_id == uuid {
+        Ok(book)
+    } else {
+        Err(BookServiceError::BookIdMismatchError(uuid.to_owned()))
+    }
+}
+
+/// The ETag of a book is its quoted version
+fn etag(version: u64) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{}\"", version)).unwrap()
+}
+
+fn versioned_response(versioned: &VersionedBook) -> Result<Response<Body>, BookServiceError> {
+    json_response(&versioned.book).map(|mut response| {
+        response.headers_mut().insert(ETAG, etag(versioned.version));
+        response
+    })
+}
+
+fn json_response<T: Serialize>(value: &T) -> Result<Response<Body>, BookServiceError> {
+    serde_json::to_vec(value)
+        .map_err(BookServiceError::BookSerializationError)
+        .map(|v| {
+            Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(v))
+                .unwrap()
+        })
+}
+
+/// Maps a failed request to the response the client should see
+fn error_response(err: BookServiceError) -> Response<Body> {
+    match err {
+        BookServiceError::NotFoundError => {
+            Response::builder().status(404).body(Body::empty()).unwrap()
+        }
+        BookServiceError::PreconditionFailedError(version) => Response::builder()
+            .status(412)
+            .header(ETAG, etag(version))
+            .body(Body::empty())
+            .unwrap(),
+        BookServiceError::InvalidUuidError(_)
+        | BookServiceError::BookParseError(_)
+        | BookServiceError::InvalidPatchError(_)
+        | BookServiceError::InvalidQueryError(_)
+        | BookServiceError::BookIdMismatchError(_) => {
+            debug!("Rejecting request: {}", &err);
+            bad_request()
+        }
+        BookServiceError::IdempotencyKeyReusedError(_) => {
+            debug!("Rejecting request: {}", &err);
+            Response::builder()
+                .status(422)
+                .body(Body::from(err.to_string()))
+                .unwrap()
+        }
+        BookServiceError::IdempotencyKeyInFlightError(_) => {
+            debug!("Rejecting request: {}", &err);
+            Response::builder()
+                .status(409)
+                .body(Body::from(err.to_string()))
+                .unwrap()
+        }
+        _ => {
+            eprintln!("Request failed: {}", &err);
+            Response::builder()
+                .status(500)
+                .body(Body::from(err.description().to_string()))
+                .unwrap()
         }
     }
 }
 
+fn health_response() -> Response<Body> {
+    println!("Processing health request");
+    Response::builder().status(200).body(Body::empty()).unwrap()
+}
+
 fn bad_request() -> Response<Body> {
     Response::builder().status(400).body(Body::empty()).unwrap()
 }
@@ -97,3 +526,395 @@ fn server_error(msg: &str) -> Result<Response<Body>, BookServiceError> {
         .body(Body::from(msg.to_string()))
         .unwrap())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use serde_json::Value;
+
+    const BOOK_ID: &'static str = "0bcd291d-b7c5-4390-965f-8a70707d22a5";
+    const BOOK_JSON: &'static str = "{\"book_id\":\"0bcd291d-b7c5-4390-965f-8a70707d22a5\",\"author\":\"Robert Jordan\",\"title\":\"Eye of the Wrold\"}";
+
+    fn memory_service() -> BookService {
+        BookService::with_store(Arc::new(MemoryStore::new()))
+    }
+
+    fn call(service: &BookService, method: &str, uri: &str, body: &str) -> (u16, String) {
+        let (status, _, body) = call_if_match(service, method, uri, body, None);
+        (status, body)
+    }
+
+    /// Sends the request with an optional If-Match, returning the ETag of the response too
+    fn call_if_match(
+        service: &BookService,
+        method: &str,
+        uri: &str,
+        body: &str,
+        if_match: Option<&str>,
+    ) -> (u16, Option<String>, String) {
+        let mut builder = Request::builder();
+        builder.method(method).uri(uri);
+        if let Some(tag) = if_match {
+            builder.header("If-Match", tag);
+        }
+        let request = builder.body(Body::from(body.to_string())).unwrap();
+        let response = service.service(request).wait().unwrap();
+        let status = response.status().as_u16();
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .map(|tag| tag.to_str().unwrap().to_string());
+        let body = response.into_body().concat2().wait().unwrap();
+        (status, etag, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn test_post_get() {
+        let service = memory_service();
+        let uri = format!("/book/{}", BOOK_ID);
+
+        assert_eq!(202, call(&service, "POST", "/book/", BOOK_JSON).0);
+
+        let (status, body) = call(&service, "GET", &uri, "");
+        assert_eq!(200, status);
+        assert_eq!(
+            Book::from_slice(BOOK_JSON.as_bytes()).unwrap(),
+            Book::from_slice(body.as_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_put() {
+        let service = memory_service();
+        let uri = format!("/book/{}", BOOK_ID);
+
+        assert_eq!(404, call(&service, "PUT", &uri, BOOK_JSON).0);
+
+        assert_eq!(202, call(&service, "POST", "/book/", BOOK_JSON).0);
+        let other_uri = format!("/book/{}", Uuid::new_v4());
+        assert_eq!(400, call(&service, "PUT", &other_uri, BOOK_JSON).0);
+
+        let replacement = BOOK_JSON.replace("Wrold", "World");
+        let (status, body) = call(&service, "PUT", &uri, &replacement);
+        assert_eq!(200, status);
+        assert_eq!(
+            "Eye of the World",
+            Book::from_slice(body.as_bytes()).unwrap().title
+        );
+    }
+
+    #[test]
+    fn test_patch_delete() {
+        let service = memory_service();
+        let uri = format!("/book/{}", BOOK_ID);
+        let patch = "{\"title\": \"Eye of the World\"}";
+
+        assert_eq!(404, call(&service, "PATCH", &uri, patch).0);
+        assert_eq!(202, call(&service, "POST", "/book/", BOOK_JSON).0);
+
+        let (status, body) = call(&service, "PATCH", &uri, patch);
+        assert_eq!(200, status);
+        assert_eq!(
+            "Eye of the World",
+            Book::from_slice(body.as_bytes()).unwrap().title
+        );
+        assert_eq!(400, call(&service, "PATCH", &uri, "{\"author\": null}").0);
+
+        assert_eq!(204, call(&service, "DELETE", &uri, "").0);
+        assert_eq!(404, call(&service, "DELETE", &uri, "").0);
+    }
+
+    #[test]
+    fn test_if_match() {
+        let service = memory_service();
+        let uri = format!("/book/{}", BOOK_ID);
+        let patch = "{\"title\": \"Eye of the World\"}";
+
+        assert_eq!(202, call(&service, "POST", "/book/", BOOK_JSON).0);
+        let (status, etag, _) = call_if_match(&service, "GET", &uri, "", None);
+        assert_eq!(200, status);
+        assert_eq!(Some("\"1\"".to_string()), etag);
+
+        let (status, etag, _) = call_if_match(&service, "PATCH", &uri, patch, Some("\"1\""));
+        assert_eq!(200, status);
+        assert_eq!(Some("\"2\"".to_string()), etag);
+
+        // a second editor still holding the first version loses
+        let (status, etag, _) = call_if_match(&service, "PUT", &uri, BOOK_JSON, Some("\"1\""));
+        assert_eq!(412, status);
+        assert_eq!(Some("\"2\"".to_string()), etag);
+        assert_eq!(
+            412,
+            call_if_match(&service, "DELETE", &uri, "", Some("\"1\"")).0
+        );
+
+        assert_eq!(
+            200,
+            call_if_match(&service, "PUT", &uri, BOOK_JSON, Some("*")).0
+        );
+        assert_eq!(
+            400,
+            call_if_match(&service, "DELETE", &uri, "", Some("abc")).0
+        );
+        assert_eq!(
+            204,
+            call_if_match(&service, "DELETE", &uri, "", Some("\"3\"")).0
+        );
+    }
+
+    #[test]
+    fn test_trash_restore() {
+        let service = memory_service();
+        let uri = format!("/book/{}", BOOK_ID);
+        let restore_uri = format!("/admin/trash/{}/restore", BOOK_ID);
+
+        assert_eq!(202, call(&service, "POST", "/book/", BOOK_JSON).0);
+        assert_eq!(204, call(&service, "DELETE", &uri, "").0);
+        assert_eq!(404, call(&service, "GET", &uri, "").0);
+
+        let (status, body) = call(&service, "GET", "/admin/trash", "");
+        assert_eq!(200, status);
+        assert!(body.contains(BOOK_ID));
+        assert!(body.contains("deleted_at"));
+
+        assert_eq!(200, call(&service, "POST", &restore_uri, "").0);
+        assert_eq!(404, call(&service, "POST", &restore_uri, "").0);
+        assert_eq!(200, call(&service, "GET", &uri, "").0);
+    }
+
+    #[test]
+    fn test_history_diff() {
+        let service = memory_service();
+        let uri = format!("/book/{}", BOOK_ID);
+        let patch = "{\"title\": \"Eye of the World\"}";
+
+        assert_eq!(
+            404,
+            call(&service, "GET", &format!("{}/history", uri), "").0
+        );
+        assert_eq!(202, call(&service, "POST", "/book/", BOOK_JSON).0);
+
+        let request = Request::builder()
+            .method("PATCH")
+            .uri(uri.as_str())
+            .header("X-Change-Source", "copy-editor")
+            .body(Body::from(patch))
+            .unwrap();
+        assert_eq!(200, service.service(request).wait().unwrap().status());
+
+        let (status, body) = call(&service, "GET", &format!("{}/history", uri), "");
+        assert_eq!(200, status);
+        assert!(body.contains("copy-editor"));
+
+        let (status, body) = call(&service, "GET", &format!("{}/history/1", uri), "");
+        assert_eq!(200, status);
+        assert!(body.contains("Wrold"));
+        assert_eq!(
+            404,
+            call(&service, "GET", &format!("{}/history/9", uri), "").0
+        );
+
+        let (status, body) = call(&service, "GET", &format!("{}/diff?from=1", uri), "");
+        assert_eq!(200, status);
+        let diff: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!("title", diff["changes"][0]["field"]);
+        assert_eq!("Eye of the Wrold", diff["changes"][0]["from"]);
+        assert_eq!("Eye of the World", diff["changes"][0]["to"]);
+    }
+
+    #[test]
+    fn test_bulk_import() {
+        let service = memory_service();
+        let body = format!("[{}, {{\"title\": \"Untitled\"}}]", BOOK_JSON);
+
+        let (status, body) = call(&service, "POST", "/book/_bulk", &body);
+        assert_eq!(200, status);
+        let report: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(1, report["created"]);
+        assert_eq!(1, report["invalid"]);
+        assert_eq!("created", report["items"][0]["status"]);
+        assert_eq!("invalid", report["items"][1]["status"]);
+
+        assert_eq!(
+            200,
+            call(&service, "GET", &format!("/book/{}", BOOK_ID), "").0
+        );
+        assert_eq!(400, call(&service, "POST", "/book/_bulk", "[{").0);
+    }
+
+    #[test]
+    fn test_idempotency_key() {
+        let service = memory_service();
+        let post = |body: &str| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/book/")
+                .header("Idempotency-Key", "import-42")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            service.service(request).wait().unwrap()
+        };
+
+        let first = post(BOOK_JSON);
+        assert_eq!(202, first.status().as_u16());
+        assert_eq!("\"1\"", first.headers().get(ETAG).unwrap());
+        assert!(first.headers().get(IDEMPOTENT_REPLAYED).is_none());
+
+        let retry = post(BOOK_JSON);
+        assert_eq!(202, retry.status().as_u16());
+        assert_eq!("\"1\"", retry.headers().get(ETAG).unwrap());
+        assert_eq!("true", retry.headers().get(IDEMPOTENT_REPLAYED).unwrap());
+        let uuid = Uuid::parse_str(BOOK_ID).unwrap();
+        assert_eq!(1, service.stores()[0].history(&uuid).unwrap().len());
+
+        let reused = post(&BOOK_JSON.replace("Wrold", "World"));
+        assert_eq!(422, reused.status().as_u16());
+
+        // a key first sent with an invalid book keeps answering 400
+        let request = Request::builder()
+            .method("POST")
+            .uri("/book/")
+            .header("Idempotency-Key", "import-43")
+            .body(Body::from("{"))
+            .unwrap();
+        assert_eq!(
+            400,
+            service.service(request).wait().unwrap().status().as_u16()
+        );
+    }
+
+    #[test]
+    fn test_idempotency_key_in_flight() {
+        let service = memory_service();
+        let post = |body: &str| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/book/")
+                .header("Idempotency-Key", "import-44")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            service.service(request).wait().unwrap().status().as_u16()
+        };
+
+        // the key reserved by a request that has not been answered yet
+        let pending = IdempotentResponse::pending(BOOK_JSON.as_bytes());
+        assert!(service.stores()[0]
+            .save_idempotent_response("import-44", &pending, 60)
+            .unwrap());
+        assert_eq!(409, post(BOOK_JSON));
+        assert_eq!(422, post(&BOOK_JSON.replace("Wrold", "World")));
+        let uuid = Uuid::parse_str(BOOK_ID).unwrap();
+        assert!(service.stores()[0].history(&uuid).unwrap().is_empty());
+
+        service.stores()[0]
+            .remove_idempotent_response("import-44")
+            .unwrap();
+        assert_eq!(202, post(BOOK_JSON));
+        assert_eq!(202, post(BOOK_JSON));
+        assert_eq!(1, service.stores()[0].history(&uuid).unwrap().len());
+    }
+
+    #[test]
+    fn test_tenants() {
+        let settings = Settings::default()
+            .with_storage("memory")
+            .with_tenants("shop-a,shop-b");
+        let service = BookService::new(&settings).unwrap();
+        let call_tenant = |method: &str, uri: &str, body: &str, tenant: Option<&str>| {
+            let mut builder = Request::builder();
+            builder.method(method).uri(uri);
+            if let Some(tenant) = tenant {
+                builder.header("X-Tenant", tenant);
+            }
+            let request = builder.body(Body::from(body.to_string())).unwrap();
+            let response = service.service(request).wait().unwrap();
+            let status = response.status().as_u16();
+            let body = response.into_body().concat2().wait().unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        };
+        let uri = format!("/book/{}", BOOK_ID);
+
+        assert_eq!(
+            202,
+            call_tenant("POST", "/book/", BOOK_JSON, Some("shop-a")).0
+        );
+        assert_eq!(200, call_tenant("GET", &uri, "", Some("shop-a")).0);
+        let (status, body) = call_tenant("GET", "/book/search?q=wrold", "", Some("shop-b"));
+        assert_eq!(200, status);
+        assert_eq!("{\"books\":[],\"next\":null}", body);
+        let (_, body) = call_tenant("GET", "/book/_export", "", Some("shop-b"));
+        assert!(body.is_empty());
+
+        assert_eq!(404, call_tenant("GET", &uri, "", Some("shop-c")).0);
+        assert_eq!(404, call_tenant("GET", &uri, "", None).0);
+        assert_eq!(200, call_tenant("GET", "/book/health", "", None).0);
+    }
+
+    #[test]
+    fn test_cache_stats() {
+        let settings = Settings::default()
+            .with_storage("memory")
+            .with_cache_size(10);
+        let service = BookService::new(&settings).unwrap();
+        let uri = format!("/book/{}", BOOK_ID);
+        assert_eq!(202, call(&service, "POST", "/book/", BOOK_JSON).0);
+        assert_eq!(200, call(&service, "GET", &uri, "").0);
+        assert_eq!(200, call(&service, "GET", &uri, "").0);
+
+        let (status, body) = call(&service, "GET", "/admin/cache", "");
+        assert_eq!(200, status);
+        let stats: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(1, stats["hits"]);
+        assert_eq!(1, stats["misses"]);
+        assert_eq!(10, stats["capacity"]);
+
+        assert_eq!(404, call(&memory_service(), "GET", "/admin/cache", "").0);
+    }
+
+    #[test]
+    fn test_events_need_redis() {
+        let service = memory_service();
+        assert_eq!(404, call(&service, "GET", "/book/_events", "").0);
+    }
+
+    #[test]
+    fn test_export() {
+        let service = memory_service();
+        assert_eq!(202, call(&service, "POST", "/book/", BOOK_JSON).0);
+
+        let (status, body) = call(&service, "GET", "/book/_export?format=csv", "");
+        assert_eq!(200, status);
+        assert_eq!(
+            format!(
+                "book_id,author,title\r\n{},Robert Jordan,Eye of the Wrold\r\n",
+                BOOK_ID
+            ),
+            body
+        );
+
+        let (status, body) = call(&service, "GET", "/book/_export", "");
+        assert_eq!(200, status);
+        assert!(body.ends_with("}\n"));
+    }
+
+    #[test]
+    fn test_list_and_search() {
+        let service = memory_service();
+        assert_eq!(202, call(&service, "POST", "/book/", BOOK_JSON).0);
+
+        let (status, body) = call(&service, "GET", "/book/?limit=10", "");
+        assert_eq!(200, status);
+        assert!(body.contains(BOOK_ID));
+
+        let (status, body) = call(&service, "GET", "/book/?author=robert+jordan", "");
+        assert_eq!(200, status);
+        assert!(body.contains(BOOK_ID));
+
+        let (status, body) = call(&service, "GET", "/book/search?q=wrold", "");
+        assert_eq!(200, status);
+        assert!(body.contains(BOOK_ID));
+
+        assert_eq!(400, call(&service, "GET", "/book/?limit=none", "").0);
+    }
+}
@@ -1,6 +1,25 @@
 use crate::errors::BookServiceError;
+use crate::namespace::Namespace;
+use crate::tenant;
 use config::{Config, Environment};
 use std::convert::From;
+use std::time::Duration;
+
+const DEFAULT_TRASH_RETENTION_SECS: u64 = 30 * 24 * 60 * 60;
+const DEFAULT_PURGE_INTERVAL_SECS: u64 = 60 * 60;
+const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_CACHE_TTL_SECS: u64 = 60;
+const DEFAULT_REDIS_MASTER_NAME: &'static str = "mymaster";
+const DEFAULT_EVENTS_CHANNEL: &'static str = "book-events";
+const DEFAULT_OUTBOX_MAX_LEN: usize = 100_000;
+
+/// The storage backends BOOKSTORE_STORAGE can select
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageBackend {
+    Redis,
+    Memory,
+    Sqlite,
+}
 
 #[derive(Debug, Clone, Default)]
 pub struct Settings {
@@ -9,7 +28,35 @@ pub struct Settings {
     pub redis_host: Option<String>,
     pub redis_password: Option<String>,
     pub redis_port: Option<u32>,
+    /// Prefixes every Redis key, so environments can share an instance
+    pub redis_namespace: Option<String>,
+    /// Comma separated host:port addresses of the Sentinels watching the Redis master
+    pub redis_sentinels: Option<String>,
+    pub redis_master_name: Option<String>,
+    /// Comma separated host:port addresses of Redis Cluster nodes to discover the cluster from.
+    /// In a cluster the outbox is appended after each write commits, not in its transaction.
+    pub redis_cluster_nodes: Option<String>,
+    /// Comma separated host:port addresses of the replicas reads are spread over
+    pub redis_replicas: Option<String>,
+    /// The Redis Pub/Sub channel book changes are published on, under the namespace
+    pub events_channel: Option<String>,
+    /// How many change records the outbox stream keeps, roughly. Every write appends one
+    /// in its transaction, except in a cluster where it is appended once the write commits.
+    pub outbox_max_len: Option<usize>,
+    /// How long change records are kept in the outbox stream, unset keeps them until
+    /// the max length is reached
+    pub outbox_max_age_secs: Option<u64>,
     pub hostname: Option<String>,
+    pub storage: Option<String>,
+    pub sqlite_path: Option<String>,
+    pub trash_retention_secs: Option<u64>,
+    pub purge_interval_secs: Option<u64>,
+    pub idempotency_ttl_secs: Option<u64>,
+    /// Comma separated allowlist of the tenants served, each with its own catalog
+    pub tenants: Option<String>,
+    /// How many books the read cache holds, 0 or unset disables it
+    pub cache_size: Option<usize>,
+    pub cache_ttl_secs: Option<u64>,
 }
 
 impl Settings {
@@ -23,7 +70,23 @@ impl Settings {
                 redis_host: config.get("redishost").ok(),
                 redis_password: config.get("redispassword").ok(),
                 redis_port: config.get("redisport").ok(),
+                redis_namespace: config.get("redisnamespace").ok(),
+                redis_sentinels: config.get("redissentinels").ok(),
+                redis_master_name: config.get("redismastername").ok(),
+                redis_cluster_nodes: config.get("redisclusternodes").ok(),
+                redis_replicas: config.get("redisreplicas").ok(),
+                events_channel: config.get("eventschannel").ok(),
+                outbox_max_len: config.get("outboxmaxlen").ok(),
+                outbox_max_age_secs: config.get("outboxmaxagesecs").ok(),
                 hostname: config.get("hostname").ok(),
+                storage: config.get("storage").ok(),
+                sqlite_path: config.get("sqlitepath").ok(),
+                trash_retention_secs: config.get("trashretentionsecs").ok(),
+                purge_interval_secs: config.get("purgeintervalsecs").ok(),
+                idempotency_ttl_secs: config.get("idempotencyttlsecs").ok(),
+                tenants: config.get("tenants").ok(),
+                cache_size: config.get("cachesize").ok(),
+                cache_ttl_secs: config.get("cachettlsecs").ok(),
             })
     }
 
@@ -67,16 +130,288 @@ impl Settings {
         }
     }
 
+    #[allow(dead_code)]
+    pub fn with_redis_namespace(self, redis_namespace: &str) -> Self {
+        Settings {
+            redis_namespace: Some(redis_namespace.to_string()),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_redis_sentinels(self, redis_sentinels: &str) -> Self {
+        Settings {
+            redis_sentinels: Some(redis_sentinels.to_string()),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_redis_master_name(self, redis_master_name: &str) -> Self {
+        Settings {
+            redis_master_name: Some(redis_master_name.to_string()),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_redis_cluster_nodes(self, redis_cluster_nodes: &str) -> Self {
+        Settings {
+            redis_cluster_nodes: Some(redis_cluster_nodes.to_string()),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_redis_replicas(self, redis_replicas: &str) -> Self {
+        Settings {
+            redis_replicas: Some(redis_replicas.to_string()),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_events_channel(self, events_channel: &str) -> Self {
+        Settings {
+            events_channel: Some(events_channel.to_string()),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_outbox_max_len(self, outbox_max_len: usize) -> Self {
+        Settings {
+            outbox_max_len: Some(outbox_max_len),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_outbox_max_age_secs(self, outbox_max_age_secs: u64) -> Self {
+        Settings {
+            outbox_max_age_secs: Some(outbox_max_age_secs),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_storage(self, storage: &str) -> Self {
+        Settings {
+            storage: Some(storage.to_string()),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_sqlite_path(self, sqlite_path: &str) -> Self {
+        Settings {
+            sqlite_path: Some(sqlite_path.to_string()),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_trash_retention_secs(self, trash_retention_secs: u64) -> Self {
+        Settings {
+            trash_retention_secs: Some(trash_retention_secs),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_purge_interval_secs(self, purge_interval_secs: u64) -> Self {
+        Settings {
+            purge_interval_secs: Some(purge_interval_secs),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_idempotency_ttl_secs(self, idempotency_ttl_secs: u64) -> Self {
+        Settings {
+            idempotency_ttl_secs: Some(idempotency_ttl_secs),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_tenants(self, tenants: &str) -> Self {
+        Settings {
+            tenants: Some(tenants.to_string()),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_cache_size(self, cache_size: usize) -> Self {
+        Settings {
+            cache_size: Some(cache_size),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_cache_ttl_secs(self, cache_ttl_secs: u64) -> Self {
+        Settings {
+            cache_ttl_secs: Some(cache_ttl_secs),
+            ..self
+        }
+    }
+
+    /// How long deleted books stay in the trash before they are purged, 30 days by default
+    pub fn trash_retention_secs(&self) -> u64 {
+        self.trash_retention_secs
+            .unwrap_or(DEFAULT_TRASH_RETENTION_SECS)
+    }
+
+    /// How often the trash is purged, hourly by default
+    pub fn purge_interval(&self) -> Duration {
+        Duration::from_secs(
+            self.purge_interval_secs
+                .unwrap_or(DEFAULT_PURGE_INTERVAL_SECS),
+        )
+    }
+
+    /// How long the response to an Idempotency-Key is replayed for, a day by default
+    pub fn idempotency_ttl_secs(&self) -> u64 {
+        self.idempotency_ttl_secs
+            .unwrap_or(DEFAULT_IDEMPOTENCY_TTL_SECS)
+    }
+
+    /// How many books the read cache holds, the cache is off by default
+    pub fn cache_size(&self) -> usize {
+        self.cache_size.unwrap_or(0)
+    }
+
+    /// How long a cached book is served before it is read again, a minute by default
+    pub fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_ttl_secs.unwrap_or(DEFAULT_CACHE_TTL_SECS))
+    }
+
+    /// How many change records the outbox stream keeps, 100000 by default
+    pub fn outbox_max_len(&self) -> usize {
+        self.outbox_max_len.unwrap_or(DEFAULT_OUTBOX_MAX_LEN)
+    }
+
+    /// How long change records are kept in the outbox stream, there is no limit by default
+    pub fn outbox_max_age(&self) -> Option<Duration> {
+        self.outbox_max_age_secs.map(Duration::from_secs)
+    }
+
+    /// The tenants in the allowlist, empty when the service holds a single catalog
+    pub fn tenants(&self) -> Result<Vec<String>, BookServiceError> {
+        self.tenants
+            .as_ref()
+            .map(|tenants| {
+                tenants
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|tenant| !tenant.is_empty())
+                    .map(|tenant| {
+                        if tenant::is_valid_tenant(tenant) {
+                            Ok(tenant.to_string())
+                        } else {
+                            Err(BookServiceError::InvalidTenantError(tenant.to_string()))
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or(Ok(Vec::new()))
+    }
+
+    /// The storage backend to use, redis when none is configured
+    pub fn storage_backend(&self) -> Result<StorageBackend, BookServiceError> {
+        match self.storage.as_ref().map(|s| s.to_lowercase()) {
+            None => Ok(StorageBackend::Redis),
+            Some(ref s) if s == "redis" => Ok(StorageBackend::Redis),
+            Some(ref s) if s == "memory" => Ok(StorageBackend::Memory),
+            Some(ref s) if s == "sqlite" => Ok(StorageBackend::Sqlite),
+            Some(s) => Err(BookServiceError::UnknownStorageError(s)),
+        }
+    }
+
+    /// The namespace Redis keys are written under, unprefixed when none is configured
+    pub fn redis_namespace(&self) -> Namespace {
+        Namespace::new(
+            self.redis_namespace
+                .as_ref()
+                .map(String::as_str)
+                .unwrap_or(""),
+        )
+    }
+
     pub fn redis_url(&self) -> Result<String, BookServiceError> {
-        match (&self.redis_host, &self.redis_port, &self.redis_password) {
-            (Some(host), Some(port), Some(password)) => {
-                Ok(format!("redis://:{}@{}:{}", password, host, port))
-            }
-            (Some(host), Some(port), None) => Ok(format!("redis://{}:{}", host, port)),
-            (None, _, _) => Err(BookServiceError::RedisHostError),
-            (_, None, _) => Err(BookServiceError::RedisPortError),
+        match (&self.redis_host, &self.redis_port) {
+            (Some(host), Some(port)) => Ok(self.redis_master_url(host, *port)),
+            (None, _) => Err(BookServiceError::RedisHostError),
+            (_, None) => Err(BookServiceError::RedisPortError),
         }
     }
+
+    /// The url of a Redis master at the address, authenticating with the configured password
+    pub fn redis_master_url(&self, host: &str, port: u32) -> String {
+        match &self.redis_password {
+            Some(password) => format!("redis://:{}@{}:{}", password, host, port),
+            None => format!("redis://{}:{}", host, port),
+        }
+    }
+
+    /// The url of the Redis node at a host:port address, None when it is not one
+    pub fn redis_address_url(&self, address: &str) -> Option<String> {
+        let mut parts = address.rsplitn(2, ':');
+        match (
+            parts.next().and_then(|port| port.parse().ok()),
+            parts.next(),
+        ) {
+            (Some(port), Some(host)) => Some(self.redis_master_url(host, port)),
+            _ => None,
+        }
+    }
+
+    /// The addresses of the Sentinels to discover the master through, empty when Redis
+    /// is reached directly at BOOKSTORE_REDISHOST
+    pub fn redis_sentinels(&self) -> Vec<String> {
+        addresses(&self.redis_sentinels)
+    }
+
+    /// The nodes to discover a Redis Cluster from, empty when Redis is not clustered
+    pub fn redis_cluster_nodes(&self) -> Vec<String> {
+        addresses(&self.redis_cluster_nodes)
+    }
+
+    /// The replicas of the master to read from, empty when every read goes to the master
+    pub fn redis_replicas(&self) -> Vec<String> {
+        addresses(&self.redis_replicas)
+    }
+
+    /// The name the Sentinels monitor the master under, mymaster by default
+    pub fn redis_master_name(&self) -> &str {
+        self.redis_master_name
+            .as_ref()
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_REDIS_MASTER_NAME)
+    }
+
+    /// The channel book changes are published on, book-events by default
+    pub fn events_channel(&self) -> &str {
+        self.events_channel
+            .as_ref()
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_EVENTS_CHANNEL)
+    }
+}
+
+/// Splits a comma separated list of host:port addresses
+fn addresses(list: &Option<String>) -> Vec<String> {
+    list.as_ref()
+        .map(|list| {
+            list.split(',')
+                .map(str::trim)
+                .filter(|address| !address.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
 #[cfg(test)]
@@ -96,6 +431,95 @@ mod tests {
         assert_eq!(url.to_string(), result);
     }
 
+    #[test]
+    fn test_redis_sentinels() {
+        let settings = Settings::default()
+            .with_redis_password("myredispass")
+            .with_redis_sentinels("sentinel-1:26379, sentinel-2:26379,");
+        assert_eq!(
+            vec!["sentinel-1:26379", "sentinel-2:26379"],
+            settings.redis_sentinels()
+        );
+        assert_eq!("mymaster", settings.redis_master_name());
+        assert_eq!("book-events", settings.events_channel());
+        assert_eq!(
+            "changes",
+            Settings::default()
+                .with_events_channel("changes")
+                .events_channel()
+        );
+        assert_eq!(
+            "redis://:myredispass@10.0.0.7:6380",
+            settings.redis_master_url("10.0.0.7", 6380)
+        );
+        assert!(Settings::default().redis_sentinels().is_empty());
+        assert_eq!(
+            vec!["node-1:7000", "node-2:7000"],
+            Settings::default()
+                .with_redis_cluster_nodes("node-1:7000,node-2:7000")
+                .redis_cluster_nodes()
+        );
+        assert_eq!(
+            Some("redis://:myredispass@replica-1:6379".to_string()),
+            settings.redis_address_url("replica-1:6379")
+        );
+        assert_eq!(None, settings.redis_address_url("replica-1"));
+    }
+
+    #[test]
+    fn test_storage_backend() {
+        assert_eq!(
+            StorageBackend::Redis,
+            Settings::default().storage_backend().unwrap()
+        );
+        assert_eq!(
+            StorageBackend::Redis,
+            Settings::default()
+                .with_storage("Redis")
+                .storage_backend()
+                .unwrap()
+        );
+        assert_eq!(
+            StorageBackend::Memory,
+            Settings::default()
+                .with_storage("memory")
+                .storage_backend()
+                .unwrap()
+        );
+        assert!(Settings::default()
+            .with_storage("carrier-pigeon")
+            .storage_backend()
+            .is_err());
+    }
+
+    #[test]
+    fn test_tenants() {
+        assert!(Settings::default().tenants().unwrap().is_empty());
+        assert_eq!(
+            vec!["shop-a".to_string(), "shop_b".to_string()],
+            Settings::default()
+                .with_tenants(" shop-a,,shop_b ")
+                .tenants()
+                .unwrap()
+        );
+        assert!(Settings::default()
+            .with_tenants("shop-a,shop:b")
+            .tenants()
+            .is_err());
+    }
+
+    #[test]
+    fn test_outbox() {
+        let settings = Settings::default();
+        assert_eq!(100_000, settings.outbox_max_len());
+        assert_eq!(None, settings.outbox_max_age());
+        let settings = settings
+            .with_outbox_max_len(500)
+            .with_outbox_max_age_secs(3600);
+        assert_eq!(500, settings.outbox_max_len());
+        assert_eq!(Some(Duration::from_secs(3600)), settings.outbox_max_age());
+    }
+
     #[test]
     fn test_settings() {
         env::remove_var("BOOKSTORE_SERVERADDRESS");
@@ -118,5 +542,4 @@ mod tests {
         let settings = Settings::new().unwrap();
         assert_eq!("0.0.0.0:80", settings.server_address.unwrap());
     }
-
 }
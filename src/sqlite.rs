@@ -0,0 +1,928 @@
+use rusqlite::types::ToSql;
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use serde_json::Value;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use uuid::Uuid;
+
+use crate::dao;
+use crate::errors::{BookServiceError, DaoCause};
+use crate::model::{
+    Book, BookPage, IdempotentResponse, Operation, Revision, TrashedBook, VersionedBook,
+};
+use crate::search;
+use crate::settings::Settings;
+use crate::store::{check_version, unix_time, BookStore};
+
+const DEFAULT_PATH: &'static str = "bookstore.db";
+
+/// Schema migrations in the order they are applied, PRAGMA user_version records how
+/// many of them a database has seen. Never edit a released migration, append a new one.
+const MIGRATIONS: &'static [&'static str] = &[
+    // 1 - books, indexed for listing, author lookups and title ordering, plus the title
+    // term index used by search
+    "CREATE TABLE books (
+        book_id TEXT PRIMARY KEY NOT NULL,
+        author TEXT NOT NULL,
+        author_key TEXT NOT NULL,
+        title TEXT NOT NULL
+    );
+    CREATE INDEX books_author_key ON books (author_key);
+    CREATE INDEX books_title ON books (title);
+    CREATE TABLE book_terms (
+        term TEXT NOT NULL,
+        book_id TEXT NOT NULL,
+        PRIMARY KEY (term, book_id)
+    );
+    CREATE INDEX book_terms_book_id ON book_terms (book_id);",
+    // 2 - the version handed out as the ETag of each book
+    "ALTER TABLE books ADD COLUMN version INTEGER NOT NULL DEFAULT 0;",
+    // 3 - soft deletes, a trashed book has the unix time it was deleted at
+    "ALTER TABLE books ADD COLUMN deleted_at INTEGER;
+    CREATE INDEX books_deleted_at ON books (deleted_at);",
+    // 4 - the revision history of each book
+    "CREATE TABLE book_revisions (
+        book_id TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        author TEXT NOT NULL,
+        title TEXT NOT NULL,
+        operation TEXT NOT NULL,
+        source TEXT NOT NULL,
+        changed_at INTEGER NOT NULL,
+        PRIMARY KEY (book_id, version)
+    );",
+    // 5 - responses replayed to requests repeating an Idempotency-Key
+    "CREATE TABLE idempotency_keys (
+        key TEXT PRIMARY KEY NOT NULL,
+        response BLOB NOT NULL,
+        expires_at INTEGER NOT NULL
+    );
+    CREATE INDEX idempotency_keys_expires_at ON idempotency_keys (expires_at);",
+];
+
+/// A BookStore kept in an embedded SQLite database, for single node deployments
+/// and running without Redis
+#[derive(Debug)]
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens (or creates) the database at BOOKSTORE_SQLITEPATH and migrates it
+    pub fn new(settings: &Settings) -> Result<Self, BookServiceError> {
+        SqliteStore::open(database_path(settings))
+    }
+
+    /// Opens the database of the tenant, kept next to the configured one with the tenant
+    /// added to its name, bookstore.db becoming bookstore-{tenant}.db
+    pub fn for_tenant(settings: &Settings, tenant: &str) -> Result<Self, BookServiceError> {
+        SqliteStore::open(tenant_path(&database_path(settings), tenant))
+    }
+
+    fn open(path: String) -> Result<Self, BookServiceError> {
+        println!("Opening sqlite database {}", &path);
+        Connection::open(&path)
+            .map_err(|e| {
+                eprintln!("Could not open sqlite database {}! {}", &path, &e);
+                BookServiceError::from(e)
+            })
+            .and_then(SqliteStore::with_connection)
+    }
+
+    /// A private database that disappears along with the store
+    #[allow(dead_code)]
+    pub fn in_memory() -> Result<Self, BookServiceError> {
+        Connection::open_in_memory()
+            .map_err(BookServiceError::from)
+            .and_then(SqliteStore::with_connection)
+    }
+
+    fn with_connection(mut conn: Connection) -> Result<Self, BookServiceError> {
+        migrate(&mut conn).map_err(|e| {
+            eprintln!("Could not migrate sqlite database! {}", &e);
+            BookServiceError::from(e)
+        })?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    // Statements either commit or roll back as a whole, so a panic while holding the
+    // lock does not leave the connection unusable
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl BookStore for SqliteStore {
+    fn put(&self, entry: &Book, source: &str) -> Result<u64, BookServiceError> {
+        println!("put for book {:?}", &entry);
+        let mut conn = self.connection();
+        conn.transaction()
+            .and_then(|tx| {
+                // writing over a trashed book takes it out of the trash
+                let version = stored_version(&tx, &entry.book_id)?.unwrap_or(0) + 1;
+                write_book(&tx, entry, version)?;
+                append_revision(&tx, entry, version, Operation::Put, source)?;
+                tx.commit().map(|_| version)
+            })
+            .map_err(|e| {
+                eprintln!("Failed to put book {:?}", &e);
+                BookServiceError::BookCreateError(DaoCause::from(e))
+            })
+    }
+
+    /// Writes every book in a single transaction
+    fn put_many(&self, entries: &[Book], source: &str) -> Result<Vec<u64>, BookServiceError> {
+        println!("put for {} books", entries.len());
+        let mut conn = self.connection();
+        conn.transaction()
+            .and_then(|tx| {
+                let mut versions = Vec::with_capacity(entries.len());
+                for entry in entries {
+                    let version = stored_version(&tx, &entry.book_id)?.unwrap_or(0) + 1;
+                    write_book(&tx, entry, version)?;
+                    append_revision(&tx, entry, version, Operation::Put, source)?;
+                    versions.push(version);
+                }
+                tx.commit().map(|_| versions)
+            })
+            .map_err(|e| {
+                eprintln!("Failed to put books {:?}", &e);
+                BookServiceError::BookCreateError(DaoCause::from(e))
+            })
+    }
+
+    fn get_versioned(&self, uuid: &Uuid) -> Result<VersionedBook, BookServiceError> {
+        read_book(&self.connection(), uuid)
+            .map_err(|e| {
+                eprintln!("Error Getting book {}", &e);
+                BookServiceError::BookGetError(DaoCause::from(e))
+            })
+            .and_then(|book| book.ok_or(BookServiceError::NotFoundError))
+    }
+
+    fn update(
+        &self,
+        entry: &Book,
+        expected_version: Option<u64>,
+        source: &str,
+    ) -> Result<Option<u64>, BookServiceError> {
+        println!("update for book {:?}", &entry);
+        let mut conn = self.connection();
+        let tx = conn.transaction().map_err(update_error)?;
+        let version = match read_book(&tx, &entry.book_id).map_err(update_error)? {
+            Some(stored) => {
+                check_version(expected_version, stored.version)?;
+                stored.version + 1
+            }
+            None => return Ok(None),
+        };
+        write_book(&tx, entry, version)
+            .and_then(|_| append_revision(&tx, entry, version, Operation::Update, source))
+            .and_then(|_| tx.commit())
+            .map_err(update_error)
+            .map(|_| Some(version))
+    }
+
+    fn patch(
+        &self,
+        uuid: &Uuid,
+        patch: &Value,
+        expected_version: Option<u64>,
+        source: &str,
+    ) -> Result<VersionedBook, BookServiceError> {
+        println!("patch for book {} {}", uuid, patch);
+        let mut conn = self.connection();
+        let tx = conn.transaction().map_err(update_error)?;
+        let stored = read_book(&tx, uuid)
+            .map_err(update_error)?
+            .ok_or(BookServiceError::NotFoundError)?;
+        check_version(expected_version, stored.version)?;
+        let merged = VersionedBook {
+            book: stored.book.merge_patch(patch)?,
+            version: stored.version + 1,
+        };
+        write_book(&tx, &merged.book, merged.version)
+            .and_then(|_| {
+                append_revision(&tx, &merged.book, merged.version, Operation::Patch, source)
+            })
+            .and_then(|_| tx.commit())
+            .map_err(update_error)
+            .map(|_| merged)
+    }
+
+    fn delete(
+        &self,
+        uuid: &Uuid,
+        expected_version: Option<u64>,
+        source: &str,
+    ) -> Result<bool, BookServiceError> {
+        println!("delete for book {}", uuid);
+        let id = uuid.hyphenated().to_string();
+        let mut conn = self.connection();
+        let tx = conn.transaction().map_err(delete_error)?;
+        let stored = match read_book(&tx, uuid).map_err(delete_error)? {
+            Some(stored) => stored,
+            None => return Ok(false),
+        };
+        check_version(expected_version, stored.version)?;
+        tx.execute("DELETE FROM book_terms WHERE book_id = ?1", params![id])
+            .and_then(|_| {
+                tx.execute(
+                    "UPDATE books SET deleted_at = ?2, version = version + 1 WHERE book_id = ?1",
+                    params![id, unix_time() as i64],
+                )
+            })
+            .and_then(|_| {
+                append_revision(
+                    &tx,
+                    &stored.book,
+                    stored.version + 1,
+                    Operation::Delete,
+                    source,
+                )
+            })
+            .and_then(|_| tx.commit())
+            .map_err(delete_error)
+            .map(|_| true)
+    }
+
+    fn list_trash(&self) -> Result<Vec<TrashedBook>, BookServiceError> {
+        let conn = self.connection();
+        conn.prepare_cached(
+            "SELECT book_id, author, title, deleted_at FROM books \
+             WHERE deleted_at IS NOT NULL ORDER BY deleted_at",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map(NO_PARAMS, |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, i64>(3)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<(String, String, String, i64)>>>()
+        })
+        .map_err(|e| {
+            eprintln!("Error listing trash {}", &e);
+            BookServiceError::BookListError(DaoCause::from(e))
+        })?
+        .into_iter()
+        .map(|(id, author, title, deleted_at)| {
+            Uuid::parse_str(&id)
+                .map_err(BookServiceError::from)
+                .map(|book_id| TrashedBook {
+                    book: Book {
+                        book_id,
+                        author,
+                        title,
+                    },
+                    deleted_at: deleted_at as u64,
+                })
+        })
+        .collect()
+    }
+
+    /// Rewriting the book clears deleted_at and puts back its title terms
+    fn restore(
+        &self,
+        uuid: &Uuid,
+        source: &str,
+    ) -> Result<Option<VersionedBook>, BookServiceError> {
+        println!("restore for book {}", uuid);
+        let mut conn = self.connection();
+        let tx = conn.transaction().map_err(update_error)?;
+        let restored = match read_trashed(&tx, uuid).map_err(update_error)? {
+            Some(stored) => VersionedBook {
+                book: stored.book,
+                version: stored.version + 1,
+            },
+            None => return Ok(None),
+        };
+        write_book(&tx, &restored.book, restored.version)
+            .and_then(|_| {
+                append_revision(
+                    &tx,
+                    &restored.book,
+                    restored.version,
+                    Operation::Restore,
+                    source,
+                )
+            })
+            .and_then(|_| tx.commit())
+            .map_err(update_error)
+            .map(|_| Some(restored))
+    }
+
+    fn purge(&self, deleted_before: u64) -> Result<usize, BookServiceError> {
+        let mut conn = self.connection();
+        conn.transaction()
+            .and_then(|tx| {
+                tx.execute(
+                    "DELETE FROM book_revisions WHERE book_id IN \
+                     (SELECT book_id FROM books WHERE deleted_at <= ?1)",
+                    params![deleted_before as i64],
+                )?;
+                let purged = tx.execute(
+                    "DELETE FROM books WHERE deleted_at <= ?1",
+                    params![deleted_before as i64],
+                )?;
+                tx.commit().map(|_| purged)
+            })
+            .map_err(|e| {
+                eprintln!("Failed to purge trash {:?}", &e);
+                BookServiceError::BookDeleteError(DaoCause::from(e))
+            })
+    }
+
+    fn history(&self, uuid: &Uuid) -> Result<Vec<Revision>, BookServiceError> {
+        let conn = self.connection();
+        conn.prepare_cached(
+            "SELECT version, author, title, operation, source, changed_at \
+             FROM book_revisions WHERE book_id = ?1 ORDER BY version",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map(params![uuid.hyphenated().to_string()], |row| {
+                let operation: String = row.get(3)?;
+                Ok(Revision {
+                    version: row.get::<_, i64>(0)? as u64,
+                    book: Book {
+                        book_id: uuid.to_owned(),
+                        author: row.get(1)?,
+                        title: row.get(2)?,
+                    },
+                    // only append_revision writes the column
+                    operation: Operation::parse(&operation).unwrap_or(Operation::Put),
+                    source: row.get(4)?,
+                    changed_at: row.get::<_, i64>(5)? as u64,
+                })
+            })?
+            .collect()
+        })
+        .map_err(|e| {
+            eprintln!("Error reading history {}", &e);
+            BookServiceError::BookGetError(DaoCause::from(e))
+        })
+    }
+
+    /// Books are listed in book_id order and the cursor is the last book_id of the
+    /// previous page
+    fn list(&self, cursor: Option<&str>, limit: usize) -> Result<BookPage, BookServiceError> {
+        let after = match cursor {
+            Some(cursor) => decode_cursor(cursor)?,
+            None => String::new(),
+        };
+        let mut books = query_books(
+            &self.connection(),
+            "SELECT book_id, author, title FROM books \
+             WHERE book_id > ?1 AND deleted_at IS NULL ORDER BY book_id LIMIT ?2",
+            params![after, (limit + 1) as i64],
+        )?;
+        let next = if books.len() > limit {
+            books.truncate(limit);
+            books
+                .last()
+                .map(|book| encode_cursor(&book.book_id.hyphenated().to_string()))
+        } else {
+            None
+        };
+        Ok(BookPage { books, next })
+    }
+
+    fn find_by_author(&self, author: &str) -> Result<Vec<Book>, BookServiceError> {
+        println!("find books by author {}", author);
+        query_books(
+            &self.connection(),
+            "SELECT book_id, author, title FROM books \
+             WHERE author_key = ?1 AND deleted_at IS NULL ORDER BY title",
+            params![dao::normalise_author(author)],
+        )
+    }
+
+    fn search(&self, query: &str, limit: usize) -> Result<Vec<Book>, BookServiceError> {
+        println!("search books for {}", query);
+        let conn = self.connection();
+        let term_matches = search::tokenise(query)
+            .iter()
+            .map(|term| {
+                conn.prepare_cached("SELECT book_id FROM book_terms WHERE term = ?1")
+                    .and_then(|mut stmt| {
+                        stmt.query_map(params![term], |row| row.get(0))?
+                            .collect::<rusqlite::Result<Vec<String>>>()
+                    })
+            })
+            .collect::<rusqlite::Result<Vec<Vec<String>>>>()
+            .map_err(|e| {
+                eprintln!("Error searching books {}", &e);
+                BookServiceError::BookListError(DaoCause::from(e))
+            })?;
+        let mut books = Vec::new();
+        for id in search::rank(&term_matches).iter().take(limit) {
+            let uuid = Uuid::parse_str(id).map_err(BookServiceError::from)?;
+            let book = read_book(&conn, &uuid).map_err(|e| {
+                eprintln!("Error searching books {}", &e);
+                BookServiceError::BookListError(DaoCause::from(e))
+            })?;
+            books.extend(book.map(|stored| stored.book));
+        }
+        Ok(books)
+    }
+
+    fn idempotent_response(
+        &self,
+        key: &str,
+    ) -> Result<Option<IdempotentResponse>, BookServiceError> {
+        let stored: Option<Vec<u8>> = self
+            .connection()
+            .query_row(
+                "SELECT response FROM idempotency_keys WHERE key = ?1 AND expires_at > ?2",
+                params![key, unix_time() as i64],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| {
+                eprintln!("Error reading idempotency key {}", &e);
+                BookServiceError::BookGetError(DaoCause::from(e))
+            })?;
+        match stored {
+            Some(ref stored) => IdempotentResponse::from_slice(stored).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Expired responses are deleted as new ones are saved
+    fn save_idempotent_response(
+        &self,
+        key: &str,
+        response: &IdempotentResponse,
+        ttl_secs: u64,
+    ) -> Result<bool, BookServiceError> {
+        let value = response.to_vec()?;
+        let now = unix_time() as i64;
+        let mut conn = self.connection();
+        conn.transaction()
+            .and_then(|tx| {
+                tx.execute(
+                    "DELETE FROM idempotency_keys WHERE expires_at <= ?1",
+                    params![now],
+                )?;
+                let inserted = tx.execute(
+                    "INSERT OR IGNORE INTO idempotency_keys (key, response, expires_at) \
+                     VALUES (?1, ?2, ?3)",
+                    params![key, value, now + ttl_secs as i64],
+                )?;
+                tx.commit().map(|_| inserted == 1)
+            })
+            .map_err(|e| {
+                eprintln!("Error saving idempotency key {}", &e);
+                BookServiceError::BookCreateError(DaoCause::from(e))
+            })
+    }
+
+    fn replace_idempotent_response(
+        &self,
+        key: &str,
+        response: &IdempotentResponse,
+        ttl_secs: u64,
+    ) -> Result<(), BookServiceError> {
+        let value = response.to_vec()?;
+        self.connection()
+            .execute(
+                "INSERT OR REPLACE INTO idempotency_keys (key, response, expires_at) \
+                 VALUES (?1, ?2, ?3)",
+                params![key, value, (unix_time() + ttl_secs) as i64],
+            )
+            .map(|_| ())
+            .map_err(|e| {
+                eprintln!("Error saving idempotency key {}", &e);
+                BookServiceError::BookCreateError(DaoCause::from(e))
+            })
+    }
+
+    fn remove_idempotent_response(&self, key: &str) -> Result<(), BookServiceError> {
+        self.connection()
+            .execute("DELETE FROM idempotency_keys WHERE key = ?1", params![key])
+            .map(|_| ())
+            .map_err(|e| {
+                eprintln!("Error removing idempotency key {}", &e);
+                BookServiceError::BookDeleteError(DaoCause::from(e))
+            })
+    }
+}
+
+fn database_path(settings: &Settings) -> String {
+    settings
+        .sqlite_path
+        .to_owned()
+        .unwrap_or(DEFAULT_PATH.to_string())
+}
+
+fn tenant_path(path: &str, tenant: &str) -> String {
+    let path = Path::new(path);
+    let name = match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => format!(
+            "{}-{}.{}",
+            stem.to_string_lossy(),
+            tenant,
+            extension.to_string_lossy()
+        ),
+        (Some(stem), None) => format!("{}-{}", stem.to_string_lossy(), tenant),
+        _ => format!("bookstore-{}.db", tenant),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Brings the schema up to date, each pending migration is applied in its own
+/// transaction along with the bump of user_version
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let current: i64 = conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        let version = index + 1;
+        println!("Applying sqlite schema migration {}", version);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute_batch(&format!("PRAGMA user_version = {}", version))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Reads the book unless it is in the trash
+fn read_book(conn: &Connection, uuid: &Uuid) -> rusqlite::Result<Option<VersionedBook>> {
+    read_row(conn, uuid, "deleted_at IS NULL")
+}
+
+/// Reads the book only if it is in the trash
+fn read_trashed(conn: &Connection, uuid: &Uuid) -> rusqlite::Result<Option<VersionedBook>> {
+    read_row(conn, uuid, "deleted_at IS NOT NULL")
+}
+
+/// The version of the stored row, trashed or not
+fn stored_version(conn: &Connection, uuid: &Uuid) -> rusqlite::Result<Option<u64>> {
+    conn.query_row(
+        "SELECT version FROM books WHERE book_id = ?1",
+        params![uuid.hyphenated().to_string()],
+        |row| row.get::<_, i64>(0).map(|version| version as u64),
+    )
+    .optional()
+}
+
+fn read_row(
+    conn: &Connection,
+    uuid: &Uuid,
+    condition: &str,
+) -> rusqlite::Result<Option<VersionedBook>> {
+    conn.query_row(
+        &format!(
+            "SELECT author, title, version FROM books WHERE book_id = ?1 AND {}",
+            condition
+        ),
+        params![uuid.hyphenated().to_string()],
+        |row| {
+            Ok(VersionedBook {
+                book: Book {
+                    book_id: uuid.to_owned(),
+                    author: row.get(0)?,
+                    title: row.get(1)?,
+                },
+                version: row.get::<_, i64>(2)? as u64,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Inserts or replaces the book and its title terms
+fn write_book(conn: &Connection, book: &Book, version: u64) -> rusqlite::Result<()> {
+    let id = book.book_id.hyphenated().to_string();
+    conn.execute(
+        "INSERT OR REPLACE INTO books (book_id, author, author_key, title, version) \
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            id,
+            book.author,
+            dao::normalise_author(&book.author),
+            book.title,
+            version as i64
+        ],
+    )?;
+    conn.execute("DELETE FROM book_terms WHERE book_id = ?1", params![id])?;
+    for term in search::tokenise(&book.title) {
+        conn.execute(
+            "INSERT INTO book_terms (term, book_id) VALUES (?1, ?2)",
+            params![term, id],
+        )?;
+    }
+    Ok(())
+}
+
+/// Records the state a write left the book in
+fn append_revision(
+    conn: &Connection,
+    book: &Book,
+    version: u64,
+    operation: Operation,
+    source: &str,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO book_revisions \
+         (book_id, version, author, title, operation, source, changed_at) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            book.book_id.hyphenated().to_string(),
+            version as i64,
+            book.author,
+            book.title,
+            operation.as_str(),
+            source,
+            unix_time() as i64
+        ],
+    )
+    .map(|_| ())
+}
+
+fn update_error(e: rusqlite::Error) -> BookServiceError {
+    eprintln!("Failed to update book {:?}", &e);
+    BookServiceError::BookUpdateError(DaoCause::from(e))
+}
+
+fn delete_error(e: rusqlite::Error) -> BookServiceError {
+    eprintln!("Failed to delete book {:?}", &e);
+    BookServiceError::BookDeleteError(DaoCause::from(e))
+}
+
+/// Runs a query selecting book_id, author and title
+fn query_books(
+    conn: &Connection,
+    sql: &str,
+    params: &[&dyn ToSql],
+) -> Result<Vec<Book>, BookServiceError> {
+    conn.prepare_cached(sql)
+        .and_then(|mut stmt| {
+            stmt.query_map(params, |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<(String, String, String)>>>()
+        })
+        .map_err(|e| {
+            eprintln!("Error listing books {}", &e);
+            BookServiceError::BookListError(DaoCause::from(e))
+        })?
+        .into_iter()
+        .map(|(id, author, title)| {
+            Uuid::parse_str(&id)
+                .map_err(BookServiceError::from)
+                .map(|book_id| Book {
+                    book_id,
+                    author,
+                    title,
+                })
+        })
+        .collect()
+}
+
+fn encode_cursor(book_id: &str) -> String {
+    base64::encode_config(book_id, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_cursor(cursor: &str) -> Result<String, BookServiceError> {
+    base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| BookServiceError::InvalidQueryError(format!("invalid cursor {}", cursor)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        // a second run has nothing left to apply
+        migrate(&mut conn).unwrap();
+        let version: i64 = conn
+            .query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(MIGRATIONS.len() as i64, version);
+    }
+
+    #[test]
+    fn test_tenant_path() {
+        assert_eq!("bookstore-shop-a.db", tenant_path("bookstore.db", "shop-a"));
+        assert_eq!(
+            "/var/lib/books/catalog-shop-a",
+            tenant_path("/var/lib/books/catalog", "shop-a")
+        );
+    }
+
+    #[test]
+    fn test_put_get() {
+        let book_id = Uuid::parse_str("0bcd291d-b7c5-4390-965f-8a70707d22a5").unwrap();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the World");
+
+        let store = SqliteStore::in_memory().unwrap();
+        let _ = store.put(&book, "test").unwrap();
+
+        assert_eq!(book, store.get(&book_id).unwrap());
+        assert!(store.get(&Uuid::new_v4()).is_err());
+    }
+
+    #[test]
+    fn test_list() {
+        let store = SqliteStore::in_memory().unwrap();
+        let mut expected: Vec<Book> = (0..5)
+            .map(|i| Book::new(Uuid::new_v4(), "Robert Jordan", &format!("Volume {}", i)))
+            .collect();
+        for book in &expected {
+            let _ = store.put(book, "test").unwrap();
+        }
+
+        let mut listed = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = store.list(cursor.as_ref().map(String::as_ref), 2).unwrap();
+            assert!(page.books.len() <= 2);
+            listed.extend(page.books);
+            cursor = page.next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        expected.sort_by_key(|book| book.book_id);
+        assert_eq!(expected, listed);
+    }
+
+    #[test]
+    fn test_update_patch_delete() {
+        let store = SqliteStore::in_memory().unwrap();
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the Wrold");
+        let patch: Value = serde_json::from_str("{\"title\": \"Eye of the World\"}").unwrap();
+
+        assert!(store.update(&book, None, "test").unwrap().is_none());
+        assert!(store.patch(&book_id, &patch, None, "test").is_err());
+
+        let _ = store.put(&book, "test").unwrap();
+        let merged = store.patch(&book_id, &patch, None, "test").unwrap();
+        assert_eq!("Eye of the World", merged.book.title);
+        assert_eq!(merged.book, store.get(&book_id).unwrap());
+
+        let updated = Book::new(book_id, "Robert Jordan", "The Great Hunt");
+        assert!(store.update(&updated, None, "test").unwrap().is_some());
+        assert_eq!(updated, store.get(&book_id).unwrap());
+
+        assert!(store.delete(&book_id, None, "test").unwrap());
+        assert!(!store.delete(&book_id, None, "test").unwrap());
+        assert!(store.search("hunt", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_versions() {
+        let store = SqliteStore::in_memory().unwrap();
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the World");
+
+        assert_eq!(1, store.put(&book, "test").unwrap());
+        assert_eq!(2, store.put(&book, "test").unwrap());
+        assert_eq!(2, store.get_versioned(&book_id).unwrap().version);
+        assert_eq!(Some(3), store.update(&book, Some(2), "test").unwrap());
+        match store.update(&book, Some(2), "test") {
+            Err(BookServiceError::PreconditionFailedError(3)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(store.delete(&book_id, Some(2), "test").is_err());
+        assert!(store.delete(&book_id, Some(3), "test").unwrap());
+    }
+
+    #[test]
+    fn test_trash() {
+        let store = SqliteStore::in_memory().unwrap();
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the World");
+
+        let _ = store.put(&book, "test").unwrap();
+        assert!(store.delete(&book_id, None, "test").unwrap());
+        assert!(store.get(&book_id).is_err());
+        assert!(store.list(None, 10).unwrap().books.is_empty());
+        assert!(store.find_by_author("Robert Jordan").unwrap().is_empty());
+        assert!(store.search("world", 10).unwrap().is_empty());
+
+        let trash = store.list_trash().unwrap();
+        assert_eq!(1, trash.len());
+        assert_eq!(book, trash[0].book);
+
+        let restored = store.restore(&book_id, "test").unwrap().unwrap();
+        assert_eq!(3, restored.version);
+        assert_eq!(1, store.search("world", 10).unwrap().len());
+        assert!(store.restore(&book_id, "test").unwrap().is_none());
+
+        assert!(store.delete(&book_id, None, "test").unwrap());
+        assert_eq!(0, store.purge(trash[0].deleted_at - 1).unwrap());
+        assert_eq!(1, store.purge(unix_time()).unwrap());
+        assert!(store.list_trash().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_put_many() {
+        let store = SqliteStore::in_memory().unwrap();
+        let book_id = Uuid::new_v4();
+        let books = vec![
+            Book::new(book_id, "Robert Jordan", "Eye of the Wrold"),
+            Book::new(Uuid::new_v4(), "Robert Jordan", "The Great Hunt"),
+            Book::new(book_id, "Robert Jordan", "Eye of the World"),
+        ];
+
+        assert_eq!(vec![1, 1, 2], store.put_many(&books, "test").unwrap());
+        assert_eq!(books[2], store.get(&book_id).unwrap());
+        assert_eq!(2, store.find_by_author("robert jordan").unwrap().len());
+    }
+
+    #[test]
+    fn test_idempotent_response() {
+        let store = SqliteStore::in_memory().unwrap();
+        let response = IdempotentResponse::new(b"{}", 202, Some(1));
+        assert_eq!(None, store.idempotent_response("import-42").unwrap());
+
+        assert!(store
+            .save_idempotent_response("import-42", &response, 60)
+            .unwrap());
+        let replaced = IdempotentResponse::new(b"{}", 400, None);
+        assert!(!store
+            .save_idempotent_response("import-42", &replaced, 60)
+            .unwrap());
+        assert_eq!(
+            Some(response),
+            store.idempotent_response("import-42").unwrap()
+        );
+
+        store
+            .replace_idempotent_response("import-42", &replaced, 60)
+            .unwrap();
+        assert_eq!(
+            Some(replaced.clone()),
+            store.idempotent_response("import-42").unwrap()
+        );
+        store.remove_idempotent_response("import-42").unwrap();
+        assert_eq!(None, store.idempotent_response("import-42").unwrap());
+
+        store
+            .save_idempotent_response("expired", &replaced, 0)
+            .unwrap();
+        assert_eq!(None, store.idempotent_response("expired").unwrap());
+    }
+
+    #[test]
+    fn test_history() {
+        let store = SqliteStore::in_memory().unwrap();
+        let book_id = Uuid::new_v4();
+        let book = Book::new(book_id, "Robert Jordan", "Eye of the Wrold");
+        let fixed = Book::new(book_id, "Robert Jordan", "Eye of the World");
+
+        let _ = store.put(&book, "import").unwrap();
+        let _ = store.update(&fixed, None, "editor").unwrap();
+        assert!(store.delete(&book_id, None, "editor").unwrap());
+
+        let history = store.history(&book_id).unwrap();
+        assert_eq!(3, history.len());
+        assert_eq!(Operation::Update, history[1].operation);
+        assert_eq!("editor", history[1].source);
+        let diff = history[0].diff(&history[1]);
+        assert_eq!(1, diff.changes.len());
+        assert_eq!("Eye of the Wrold", diff.changes[0].from);
+
+        assert_eq!(1, store.purge(unix_time()).unwrap());
+        assert!(store.history(&book_id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_find_by_author_and_search() {
+        let store = SqliteStore::in_memory().unwrap();
+        let eye = Book::new(Uuid::new_v4(), "Robert Jordan", "The Eye of the World");
+        let hunt = Book::new(Uuid::new_v4(), "Robert Jordan", "The Great Hunt");
+        let bell = Book::new(
+            Uuid::new_v4(),
+            "Ernest Hemmingway",
+            "For Whom the Bell Tolls",
+        );
+        for book in &[&eye, &hunt, &bell] {
+            let _ = store.put(book, "test").unwrap();
+        }
+
+        let found = store.find_by_author("  robert JORDAN").unwrap();
+        assert_eq!(vec![&eye, &hunt], found.iter().collect::<Vec<&Book>>());
+
+        let found = store.search("great world", 10).unwrap();
+        assert_eq!(2, found.len());
+        assert!(store.search("the", 10).unwrap().is_empty());
+        assert_eq!(vec![bell], store.search("bell", 10).unwrap());
+    }
+}
@@ -0,0 +1,228 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use crate::cache::CachedStore;
+use crate::dao::BookDao;
+use crate::errors::BookServiceError;
+use crate::events::EventStream;
+use crate::memory::MemoryStore;
+use crate::model::{
+    Book, BookPage, CacheStats, IdempotentResponse, Revision, TrashedBook, VersionedBook,
+};
+use crate::settings::{Settings, StorageBackend};
+use crate::sqlite::SqliteStore;
+
+/// The storage operations behind BookService, implemented once per backend.
+/// Every write bumps the version of the book; writes given an expected version fail with
+/// PreconditionFailedError unless it is still the stored one.
+/// Deleted books are moved to the trash, where they are hidden from every read and
+/// write until restored, and only purge removes them for good.
+/// Each write also appends a revision recording the new state of the book, the time and
+/// the source of the change.
+pub trait BookStore: Debug + Send + Sync {
+    /// Creates the book or overwrites an existing one, returning its new version
+    fn put(&self, entry: &Book, source: &str) -> Result<u64, BookServiceError>;
+
+    /// Creates or overwrites each of the books, returning their new versions in order
+    fn put_many(&self, entries: &[Book], source: &str) -> Result<Vec<u64>, BookServiceError> {
+        entries
+            .iter()
+            .map(|entry| self.put(entry, source))
+            .collect()
+    }
+
+    fn get_versioned(&self, uuid: &Uuid) -> Result<VersionedBook, BookServiceError>;
+
+    fn get(&self, uuid: &Uuid) -> Result<Book, BookServiceError> {
+        self.get_versioned(uuid).map(|versioned| versioned.book)
+    }
+
+    /// Replaces an existing book, returning its new version or None if there was no entry
+    /// for its uuid
+    fn update(
+        &self,
+        entry: &Book,
+        expected_version: Option<u64>,
+        source: &str,
+    ) -> Result<Option<u64>, BookServiceError>;
+
+    /// Applies a JSON Merge Patch to the stored book and returns the merged result
+    fn patch(
+        &self,
+        uuid: &Uuid,
+        patch: &Value,
+        expected_version: Option<u64>,
+        source: &str,
+    ) -> Result<VersionedBook, BookServiceError>;
+
+    /// Moves the book to the trash, returning false if there was no live entry for the uuid
+    fn delete(
+        &self,
+        uuid: &Uuid,
+        expected_version: Option<u64>,
+        source: &str,
+    ) -> Result<bool, BookServiceError>;
+
+    /// The books in the trash, the longest deleted first
+    fn list_trash(&self) -> Result<Vec<TrashedBook>, BookServiceError>;
+
+    /// Takes the book back out of the trash, returning None if it was not in the trash
+    fn restore(&self, uuid: &Uuid, source: &str)
+        -> Result<Option<VersionedBook>, BookServiceError>;
+
+    /// Permanently removes the books deleted at or before the unix time along with their
+    /// history, returning how many were purged
+    fn purge(&self, deleted_before: u64) -> Result<usize, BookServiceError>;
+
+    /// Every revision of the book, oldest first. Empty for a book never written.
+    fn history(&self, uuid: &Uuid) -> Result<Vec<Revision>, BookServiceError>;
+
+    fn revision(&self, uuid: &Uuid, version: u64) -> Result<Revision, BookServiceError> {
+        self.history(uuid).and_then(|revisions| {
+            revisions
+                .into_iter()
+                .find(|revision| revision.version == version)
+                .ok_or(BookServiceError::NotFoundError)
+        })
+    }
+
+    /// Returns a page of books starting at the opaque cursor handed out as `next`
+    /// by the previous page
+    fn list(&self, cursor: Option<&str>, limit: usize) -> Result<BookPage, BookServiceError>;
+
+    fn find_by_author(&self, author: &str) -> Result<Vec<Book>, BookServiceError>;
+
+    /// Searches titles, books matching the most query terms first
+    fn search(&self, query: &str, limit: usize) -> Result<Vec<Book>, BookServiceError>;
+
+    /// The response saved for an Idempotency-Key, None for a new or expired key
+    fn idempotent_response(
+        &self,
+        key: &str,
+    ) -> Result<Option<IdempotentResponse>, BookServiceError>;
+
+    /// Saves the response to an Idempotency-Key for ttl_secs unless the key already has
+    /// one, returning whether it was saved. Saving a pending response reserves the key.
+    fn save_idempotent_response(
+        &self,
+        key: &str,
+        response: &IdempotentResponse,
+        ttl_secs: u64,
+    ) -> Result<bool, BookServiceError>;
+
+    /// Replaces the pending response of a reserved key with the final one
+    fn replace_idempotent_response(
+        &self,
+        key: &str,
+        response: &IdempotentResponse,
+        ttl_secs: u64,
+    ) -> Result<(), BookServiceError>;
+
+    /// Drops the reservation of a key whose request failed, so that a retry can succeed
+    fn remove_idempotent_response(&self, key: &str) -> Result<(), BookServiceError>;
+
+    /// Hit and miss counts when reads go through a cache
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+
+    /// The writes made to the catalog from now on as Server-Sent Events, None when the
+    /// backend does not publish them
+    fn events(&self) -> Option<Result<EventStream, BookServiceError>> {
+        None
+    }
+}
+
+/// Fails a conditional write whose expected version is no longer the stored one
+pub fn check_version(expected_version: Option<u64>, version: u64) -> Result<(), BookServiceError> {
+    match expected_version {
+        Some(expected) if expected != version => {
+            Err(BookServiceError::PreconditionFailedError(version))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Seconds since the unix epoch, the clock trash deletion times are kept in
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Builds the backend selected by BOOKSTORE_STORAGE, behind a cache when one is configured
+pub fn build_store(settings: &Settings) -> Result<Arc<dyn BookStore>, BookServiceError> {
+    build_backend(settings).map(|store| with_cache(store, settings))
+}
+
+fn build_backend(settings: &Settings) -> Result<Arc<dyn BookStore>, BookServiceError> {
+    match settings.storage_backend()? {
+        StorageBackend::Redis => {
+            BookDao::new(settings).map(|dao| Arc::new(dao) as Arc<dyn BookStore>)
+        }
+        StorageBackend::Memory => Ok(Arc::new(MemoryStore::new())),
+        StorageBackend::Sqlite => {
+            SqliteStore::new(settings).map(|store| Arc::new(store) as Arc<dyn BookStore>)
+        }
+    }
+}
+
+/// Builds an isolated store for each tenant with the backend selected by BOOKSTORE_STORAGE.
+/// Redis tenants share the connection pool and each get a namespace below the configured
+/// one, SQLite tenants each get a database file of their own. Every tenant has a cache of
+/// its own when one is configured.
+pub fn build_tenant_stores(
+    settings: &Settings,
+    tenants: &[String],
+) -> Result<HashMap<String, Arc<dyn BookStore>>, BookServiceError> {
+    build_tenant_backends(settings, tenants).map(|stores| {
+        stores
+            .into_iter()
+            .map(|(tenant, store)| (tenant, with_cache(store, settings)))
+            .collect()
+    })
+}
+
+fn build_tenant_backends(
+    settings: &Settings,
+    tenants: &[String],
+) -> Result<HashMap<String, Arc<dyn BookStore>>, BookServiceError> {
+    match settings.storage_backend()? {
+        StorageBackend::Redis => BookDao::new(settings).map(|dao| {
+            tenants
+                .iter()
+                .map(|tenant| {
+                    let store = Arc::new(dao.for_tenant(tenant)) as Arc<dyn BookStore>;
+                    (tenant.to_owned(), store)
+                })
+                .collect()
+        }),
+        StorageBackend::Memory => Ok(tenants
+            .iter()
+            .map(|tenant| {
+                let store = Arc::new(MemoryStore::new()) as Arc<dyn BookStore>;
+                (tenant.to_owned(), store)
+            })
+            .collect()),
+        StorageBackend::Sqlite => tenants
+            .iter()
+            .map(|tenant| {
+                SqliteStore::for_tenant(settings, tenant)
+                    .map(|store| (tenant.to_owned(), Arc::new(store) as Arc<dyn BookStore>))
+            })
+            .collect(),
+    }
+}
+
+/// Puts a read-through cache in front of the store unless BOOKSTORE_CACHESIZE is 0
+fn with_cache(store: Arc<dyn BookStore>, settings: &Settings) -> Arc<dyn BookStore> {
+    match settings.cache_size() {
+        0 => store,
+        capacity => Arc::new(CachedStore::new(store, capacity, settings.cache_ttl())),
+    }
+}
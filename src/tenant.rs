@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::errors::BookServiceError;
+use crate::settings::Settings;
+use crate::store::{self, BookStore};
+
+/// The catalogs a BookService serves. Without an allowlist of tenants there is a single
+/// catalog; with one every tenant has its own, isolated in the storage backend, and
+/// requests must name the tenant they are for.
+#[derive(Debug)]
+pub enum Catalogs {
+    Single(Arc<dyn BookStore>),
+    PerTenant(HashMap<String, Arc<dyn BookStore>>),
+}
+
+impl Catalogs {
+    pub fn new(settings: &Settings) -> Result<Self, BookServiceError> {
+        let tenants = settings.tenants()?;
+        if tenants.is_empty() {
+            store::build_store(settings).map(Catalogs::Single)
+        } else {
+            println!("Serving catalogs for tenants {:?}", &tenants);
+            store::build_tenant_stores(settings, &tenants).map(Catalogs::PerTenant)
+        }
+    }
+
+    /// The store of the catalog the request is for. A tenant that is not in the
+    /// allowlist is not found, as is a request without one when there is an allowlist.
+    pub fn resolve(&self, tenant: Option<&str>) -> Result<Arc<dyn BookStore>, BookServiceError> {
+        match (self, tenant) {
+            (Catalogs::Single(store), None) => Ok(store.clone()),
+            (Catalogs::PerTenant(stores), Some(tenant)) => stores
+                .get(tenant)
+                .cloned()
+                .ok_or(BookServiceError::NotFoundError),
+            _ => Err(BookServiceError::NotFoundError),
+        }
+    }
+
+    /// Every catalog, for the work done across all of them such as purging the trash
+    pub fn stores(&self) -> Vec<Arc<dyn BookStore>> {
+        match self {
+            Catalogs::Single(store) => vec![store.clone()],
+            Catalogs::PerTenant(stores) => stores.values().cloned().collect(),
+        }
+    }
+}
+
+/// Tenant ids are used in key names and file names, so only letters, digits, '-' and
+/// '_' are allowed
+pub fn is_valid_tenant(tenant: &str) -> bool {
+    !tenant.is_empty()
+        && tenant
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Book;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_resolve() {
+        let settings = Settings::default()
+            .with_storage("memory")
+            .with_tenants("shop-a, shop-b");
+        let catalogs = Catalogs::new(&settings).unwrap();
+        assert_eq!(2, catalogs.stores().len());
+
+        let book_id = Uuid::new_v4();
+        let shop_a = catalogs.resolve(Some("shop-a")).unwrap();
+        let _ = shop_a
+            .put(
+                &Book::new(book_id, "Robert Jordan", "Eye of the World"),
+                "test",
+            )
+            .unwrap();
+        assert!(shop_a.get(&book_id).is_ok());
+        let shop_b = catalogs.resolve(Some("shop-b")).unwrap();
+        assert!(shop_b.list(None, 10).unwrap().books.is_empty());
+
+        assert!(catalogs.resolve(Some("shop-c")).is_err());
+        assert!(catalogs.resolve(None).is_err());
+
+        let single = Catalogs::new(&Settings::default().with_storage("memory")).unwrap();
+        assert!(single.resolve(None).is_ok());
+        assert!(single.resolve(Some("shop-a")).is_err());
+    }
+
+    #[test]
+    fn test_is_valid_tenant() {
+        assert!(is_valid_tenant("shop_A-1"));
+        assert!(!is_valid_tenant(""));
+        assert!(!is_valid_tenant("shop:a"));
+        assert!(!is_valid_tenant("../shop"));
+    }
+}
@@ -0,0 +1,71 @@
+use std::io;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::errors::BookServiceError;
+use crate::settings::Settings;
+use crate::store::{unix_time, BookStore};
+
+/// Starts the background thread that empties the trash of every catalog. Every purge
+/// interval the books deleted longer ago than the trash retention are removed for good.
+pub fn spawn_purger(
+    stores: Vec<Arc<dyn BookStore>>,
+    settings: &Settings,
+) -> io::Result<JoinHandle<()>> {
+    let retention_secs = settings.trash_retention_secs();
+    let interval = settings.purge_interval();
+    println!(
+        "Purging trash older than {}s every {:?}",
+        retention_secs, interval
+    );
+    thread::Builder::new()
+        .name("trash-purger".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            // a failed purge is retried on the next interval
+            for store in &stores {
+                let _ = purge_expired(store.as_ref(), retention_secs);
+            }
+        })
+}
+
+/// Purges the books that have been in the trash longer than the retention
+pub fn purge_expired(
+    store: &dyn BookStore,
+    retention_secs: u64,
+) -> Result<usize, BookServiceError> {
+    let deleted_before = unix_time().saturating_sub(retention_secs);
+    store
+        .purge(deleted_before)
+        .map(|purged| {
+            println!("Purged {} books deleted before {}", purged, deleted_before);
+            purged
+        })
+        .map_err(|e| {
+            eprintln!("Could not purge trash {}", &e);
+            e
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use crate::model::Book;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_purge_expired() {
+        let store = MemoryStore::new();
+        let book_id = Uuid::new_v4();
+        let _ = store
+            .put(&Book::new(book_id, "Robert Jordan", "Eye of the World"), "test")
+            .unwrap();
+        assert!(store.delete(&book_id, None, "test").unwrap());
+
+        assert_eq!(0, purge_expired(&store, 60 * 60).unwrap());
+        assert_eq!(1, store.list_trash().unwrap().len());
+        assert_eq!(1, purge_expired(&store, 0).unwrap());
+        assert!(store.list_trash().unwrap().is_empty());
+    }
+}